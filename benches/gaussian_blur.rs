@@ -0,0 +1,63 @@
+//! Time the vectorized Gaussian blur against the scalar passes it replaced on a full hd
+//! plane, run with `cargo bench --bench gaussian_blur`
+
+use anuvis::pipeline_steps::gaussian_blur::GaussianBlur;
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+
+const WIDTH: usize = 1920;
+const HEIGHT: usize = 1080;
+const ITERATIONS: u32 = 20;
+
+fn main() {
+    // gradient with pseudo random noise so every kernel tap matters
+    let mut state = 0x2545_f491u32;
+    let input: Vec<u8> = (0..WIDTH * HEIGHT)
+        .map(|i| {
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            let gradient = (i % WIDTH * 255 / WIDTH) as u32;
+            ((gradient + (state & 0x3f)) & 0xff) as u8
+        })
+        .collect();
+
+    for sigma in [1.4, 3.0] {
+        let blur = GaussianBlur::new("", sigma).unwrap();
+
+        let scalar = time(|| {
+            blur.blur_plane_scalar(black_box(&input), WIDTH, HEIGHT)
+                .unwrap()
+        });
+        let simd = time(|| blur.blur_plane(black_box(&input), WIDTH, HEIGHT).unwrap());
+
+        for (name, elapsed) in [("scalar", scalar), ("simd", simd)] {
+            println!(
+                "gaussian_blur {} {}x{} sigma {}: {:.2?} per blur, {:.1} megapixels/s",
+                name,
+                WIDTH,
+                HEIGHT,
+                sigma,
+                elapsed,
+                (WIDTH * HEIGHT) as f64 / elapsed.as_secs_f64() / 1e6
+            );
+        }
+        println!(
+            "gaussian_blur sigma {}: {:.2}x speedup",
+            sigma,
+            scalar.as_secs_f64() / simd.as_secs_f64()
+        );
+    }
+}
+
+// average time of a blur over the iterations
+fn time(mut blur: impl FnMut() -> Vec<u8>) -> Duration {
+    // warm up caches and the allocator before timing
+    black_box(blur());
+
+    let start = Instant::now();
+    for _ in 0..ITERATIONS {
+        black_box(blur());
+    }
+    start.elapsed() / ITERATIONS
+}
@@ -0,0 +1,1017 @@
+use crate::pipeline_steps::border::BorderMode;
+use image::{ImageBuffer, Luma, Rgb, Rgba};
+use std::fmt;
+use std::io;
+use std::ops::{Index, IndexMut};
+use std::path::PathBuf;
+
+/// Memory layout of the pixels in a `Frame`.
+///
+/// Packed formats store the channels of a pixel next to each other, row after row
+/// without padding. 16 bit samples are little endian, float samples are native
+/// endian `f32` in the 0.0..=1.0 range. The YUV formats use BT.601 limited range
+/// with chroma subsampled by two in both directions.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PixelFormat {
+    /// 8 bit luma
+    Gray8,
+    /// 16 bit luma
+    Gray16,
+    /// 32 bit float luma
+    GrayF32,
+    /// 8 bit red, green, blue
+    Rgb,
+    /// 8 bit blue, green, red
+    Bgr,
+    /// 8 bit red, green, blue, alpha
+    Rgba,
+    /// 32 bit float red, green, blue
+    RgbF32,
+    /// 32 bit float red, green, blue, alpha
+    RgbaF32,
+    /// Planar Y, then U, then V
+    I420,
+    /// Planar Y, then interleaved U and V
+    Nv12,
+}
+
+impl PixelFormat {
+    /// Number of color components of a pixel, including alpha
+    pub fn channels(self) -> usize {
+        match self {
+            PixelFormat::Gray8 | PixelFormat::Gray16 | PixelFormat::GrayF32 => 1,
+            PixelFormat::Rgb | PixelFormat::Bgr | PixelFormat::RgbF32 => 3,
+            PixelFormat::I420 | PixelFormat::Nv12 => 3,
+            PixelFormat::Rgba | PixelFormat::RgbaF32 => 4,
+        }
+    }
+
+    /// Whether the format only carries luma
+    pub fn is_gray(self) -> bool {
+        matches!(
+            self,
+            PixelFormat::Gray8 | PixelFormat::Gray16 | PixelFormat::GrayF32
+        )
+    }
+
+    /// Whether the format carries an alpha channel
+    pub fn has_alpha(self) -> bool {
+        matches!(self, PixelFormat::Rgba | PixelFormat::RgbaF32)
+    }
+
+    /// Whether every channel is a byte stored next to the others, the layout the
+    /// 8 bit processing steps work on
+    pub fn is_packed_u8(self) -> bool {
+        matches!(
+            self,
+            PixelFormat::Gray8 | PixelFormat::Rgb | PixelFormat::Bgr | PixelFormat::Rgba
+        )
+    }
+
+    /// Bytes per row and number of rows of every plane of a `width` x `height` image
+    pub fn planes(self, width: usize, height: usize) -> Vec<(usize, usize)> {
+        let chroma_width = (width + 1) / 2;
+        let chroma_height = (height + 1) / 2;
+
+        match self {
+            PixelFormat::I420 => vec![
+                (width, height),
+                (chroma_width, chroma_height),
+                (chroma_width, chroma_height),
+            ],
+            PixelFormat::Nv12 => vec![(width, height), (chroma_width * 2, chroma_height)],
+            packed => vec![(packed.bytes_per_pixel().unwrap_or(1) * width, height)],
+        }
+    }
+
+    /// Bytes of a single pixel of a packed format, `None` for the planar YUV formats
+    pub fn bytes_per_pixel(self) -> Option<usize> {
+        match self {
+            PixelFormat::Gray8 => Some(1),
+            PixelFormat::Gray16 => Some(2),
+            PixelFormat::Rgb | PixelFormat::Bgr => Some(3),
+            PixelFormat::GrayF32 | PixelFormat::Rgba => Some(4),
+            PixelFormat::RgbF32 => Some(12),
+            PixelFormat::RgbaF32 => Some(16),
+            PixelFormat::I420 | PixelFormat::Nv12 => None,
+        }
+    }
+
+    /// Number of bytes of a `width` x `height` image in this format
+    pub fn buffer_size(self, width: usize, height: usize) -> usize {
+        match self.bytes_per_pixel() {
+            Some(bytes) => bytes * width * height,
+            None => width * height + 2 * ((width + 1) / 2) * ((height + 1) / 2),
+        }
+    }
+
+    /// The GStreamer raw video format name, `None` for formats GStreamer has no
+    /// equivalent for
+    pub fn gst_name(self) -> Option<&'static str> {
+        match self {
+            PixelFormat::Gray8 => Some("GRAY8"),
+            PixelFormat::Gray16 => Some("GRAY16_LE"),
+            PixelFormat::Rgb => Some("RGB"),
+            PixelFormat::Bgr => Some("BGR"),
+            PixelFormat::Rgba => Some("RGBA"),
+            PixelFormat::I420 => Some("I420"),
+            PixelFormat::Nv12 => Some("NV12"),
+            PixelFormat::GrayF32 | PixelFormat::RgbF32 | PixelFormat::RgbaF32 => None,
+        }
+    }
+
+    /// Look up a format by its GStreamer raw video format name
+    pub fn from_gst_name(name: &str) -> Option<Self> {
+        match name {
+            "GRAY8" => Some(PixelFormat::Gray8),
+            "GRAY16_LE" => Some(PixelFormat::Gray16),
+            "RGB" => Some(PixelFormat::Rgb),
+            "BGR" => Some(PixelFormat::Bgr),
+            "RGBA" => Some(PixelFormat::Rgba),
+            "I420" => Some(PixelFormat::I420),
+            "NV12" => Some(PixelFormat::Nv12),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for PixelFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            PixelFormat::GrayF32 => "GRAY32F",
+            PixelFormat::RgbF32 => "RGB32F",
+            PixelFormat::RgbaF32 => "RGBA32F",
+            other => other.gst_name().unwrap_or("UNKNOWN"),
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// A video frame: the bytes of its pixels held in an `Image<u8>`, plus the pixel
+/// format that says how to read them.
+///
+/// Packed formats keep one frame row per image row, so a `Gray8` frame is its luma
+/// image and moves to and from `Image<u8>` without copying. The planar YUV formats
+/// keep their planes back to back in a single image row.
+#[derive(Debug, Clone)]
+pub struct Frame {
+    image: Image<u8>,
+    width: i32,
+    height: i32,
+    format: PixelFormat,
+}
+
+impl Frame {
+    /// Create a frame, checking the data matches the size of the format
+    ///
+    /// # Arguments
+    /// * `data` - Pixel data laid out as described by `format`
+    /// * `width` - Width of the frame in pixels
+    /// * `height` - Height of the frame in pixels
+    /// * `format` - Layout of the pixel data
+    pub fn new(data: Vec<u8>, width: i32, height: i32, format: PixelFormat) -> io::Result<Self> {
+        if width <= 0 || height <= 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Invalid frame dimensions: {}x{}", width, height),
+            ));
+        }
+
+        let expected = format.buffer_size(width as usize, height as usize);
+        if data.len() != expected {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "{} frame of {}x{} needs {} bytes, got {}",
+                    format,
+                    width,
+                    height,
+                    expected,
+                    data.len()
+                ),
+            ));
+        }
+
+        Ok(Self::from_parts(data, width, height, format))
+    }
+
+    // wrap bytes already checked to match the format, see `new`
+    fn from_parts(data: Vec<u8>, width: i32, height: i32, format: PixelFormat) -> Self {
+        let (row_bytes, rows) = match format.bytes_per_pixel() {
+            Some(bytes_per_pixel) => (width as usize * bytes_per_pixel, height as usize),
+            None => (data.len(), 1),
+        };
+
+        Self {
+            image: Image {
+                data,
+                width: row_bytes,
+                height: rows,
+                stride: row_bytes,
+            },
+            width,
+            height,
+            format,
+        }
+    }
+
+    /// Width of the frame in pixels
+    pub fn width(&self) -> i32 {
+        self.width
+    }
+
+    /// Height of the frame in pixels
+    pub fn height(&self) -> i32 {
+        self.height
+    }
+
+    pub fn format(&self) -> PixelFormat {
+        self.format
+    }
+
+    /// The pixel bytes, laid out as described by the format
+    pub fn data(&self) -> &[u8] {
+        &self.image.data
+    }
+
+    /// The pixel bytes for editing in place, the size and format stay the same
+    pub fn data_mut(&mut self) -> &mut [u8] {
+        &mut self.image.data
+    }
+
+    /// Take the pixel bytes out of the frame
+    pub fn into_data(self) -> Vec<u8> {
+        self.image.data
+    }
+
+    /// Number of color components of a pixel, including alpha
+    pub fn channels(&self) -> i32 {
+        self.format.channels() as i32
+    }
+
+    pub fn get_pixel(&self, x: i32, y: i32) -> Option<(i32, i32, i32)> {
+        if x < 0 || x >= self.width || y < 0 || y >= self.height {
+            return None;
+        }
+
+        let index = (y * self.width + x) as usize;
+        match self.format {
+            PixelFormat::Gray8 => {
+                // For grayscale, return the same value for R, G, and B
+                let value = self.data()[index] as i32;
+                Some((value, value, value))
+            }
+            PixelFormat::Rgb => {
+                let index = index * 3;
+                let data = self.data();
+                Some((
+                    data[index] as i32,
+                    data[index + 1] as i32,
+                    data[index + 2] as i32,
+                ))
+            }
+            _ => {
+                let [r, g, b, _] = self.pixel_rgba(x as usize, y as usize);
+                Some((to_u8(r) as i32, to_u8(g) as i32, to_u8(b) as i32))
+            }
+        }
+    }
+
+    /// Convert to 8 bit luma, the input of the single channel processing steps
+    pub fn to_grayscale(&mut self) -> &mut Self {
+        // Early return if already grayscale
+        if self.format != PixelFormat::Gray8 {
+            *self = self.convert(PixelFormat::Gray8);
+        }
+
+        self
+    }
+
+    /// Take the frame as an 8 bit luma image, converting it to `Gray8` first if needed
+    pub fn into_gray_image(mut self) -> Image<u8> {
+        self.to_grayscale();
+        self.image
+    }
+
+    /// Borrow the frame as an 8 bit luma image, converting it to `Gray8` first if needed
+    pub fn gray_image(&mut self) -> &Image<u8> {
+        self.to_grayscale();
+        &self.image
+    }
+
+    /// Create an image of the frame's size by applying `f` to the 8 bit luma of every
+    /// pixel. `Gray8` frames are read in place, other formats are converted first.
+    pub fn map_gray<U: Copy>(&self, f: impl FnMut(u8) -> U) -> Image<U> {
+        if self.format != PixelFormat::Gray8 {
+            return self.convert(PixelFormat::Gray8).map_gray(f);
+        }
+
+        self.image.map(f)
+    }
+
+    /// Read the frame as a float luma image. `GrayF32` frames are read as they are,
+    /// other formats are converted to luma in the 0.0..=1.0 range.
+    pub fn to_float_image(&self) -> Image<f32> {
+        if self.format != PixelFormat::GrayF32 {
+            return self.convert(PixelFormat::GrayF32).to_float_image();
+        }
+
+        let (width, height) = (self.width as usize, self.height as usize);
+        Image {
+            data: (0..width * height)
+                .map(|i| read_f32(self.data(), i))
+                .collect(),
+            width,
+            height,
+            stride: width,
+        }
+    }
+
+    /// Convert to the closest packed 8 bit format, keeping gray frames single channel
+    /// and alpha when the frame has it
+    pub fn to_packed_u8(&mut self) -> &mut Self {
+        let format = if self.format.is_gray() {
+            PixelFormat::Gray8
+        } else if self.format.has_alpha() {
+            PixelFormat::Rgba
+        } else if self.format == PixelFormat::Bgr {
+            PixelFormat::Bgr
+        } else {
+            PixelFormat::Rgb
+        };
+
+        if self.format != format {
+            *self = self.convert(format);
+        }
+
+        self
+    }
+
+    pub fn print_pixel(&self, x: i32, y: i32) {
+        match self.get_pixel(x, y) {
+            Some((r, g, b)) => {
+                if self.format.is_gray() {
+                    println!(
+                        "Pixel at ({}, {}): Grayscale({}) - Hex: #{:02X}{:02X}{:02X}",
+                        x, y, r, r as u8, r as u8, r as u8
+                    );
+                } else {
+                    println!(
+                        "Pixel at ({}, {}): RGB({}, {}, {}) - Hex: #{:02X}{:02X}{:02X}",
+                        x, y, r, g, b, r as u8, g as u8, b as u8
+                    );
+                }
+            }
+            None => println!("Pixel position ({}, {}) is out of bounds", x, y),
+        }
+    }
+
+    /// Save the frame as an image, the format is picked from the file extension.
+    /// Formats an image file cannot hold are converted first: float frames to
+    /// 16 bit gray or 8 bit color, BGR and YUV frames to RGB.
+    pub fn save(&self, path: &PathBuf) -> io::Result<()> {
+        let width = self.width as u32;
+        let height = self.height as u32;
+        let buffer_error =
+            || io::Error::new(io::ErrorKind::InvalidData, "Failed to create image buffer");
+        let save_error = |e: image::ImageError| io::Error::new(io::ErrorKind::Other, e.to_string());
+
+        match self.format {
+            PixelFormat::Gray8 => ImageBuffer::<Luma<u8>, _>::from_raw(width, height, self.data())
+                .ok_or_else(buffer_error)?
+                .save(path)
+                .map_err(save_error),
+            PixelFormat::Gray16 => {
+                let samples: Vec<u16> = self
+                    .data()
+                    .chunks_exact(2)
+                    .map(|bytes| u16::from_le_bytes([bytes[0], bytes[1]]))
+                    .collect();
+
+                ImageBuffer::<Luma<u16>, _>::from_raw(width, height, samples)
+                    .ok_or_else(buffer_error)?
+                    .save(path)
+                    .map_err(save_error)
+            }
+            PixelFormat::Rgb => ImageBuffer::<Rgb<u8>, _>::from_raw(width, height, self.data())
+                .ok_or_else(buffer_error)?
+                .save(path)
+                .map_err(save_error),
+            PixelFormat::Rgba => ImageBuffer::<Rgba<u8>, _>::from_raw(width, height, self.data())
+                .ok_or_else(buffer_error)?
+                .save(path)
+                .map_err(save_error),
+            PixelFormat::GrayF32 => self.convert(PixelFormat::Gray16).save(path),
+            PixelFormat::RgbaF32 => self.convert(PixelFormat::Rgba).save(path),
+            PixelFormat::Bgr | PixelFormat::RgbF32 | PixelFormat::I420 | PixelFormat::Nv12 => {
+                self.convert(PixelFormat::Rgb).save(path)
+            }
+        }
+    }
+
+    /// Convert to 8 bit RGB
+    pub fn to_rgb(self) -> Frame {
+        if self.format == PixelFormat::Rgb {
+            return self;
+        }
+
+        self.convert(PixelFormat::Rgb)
+    }
+
+    /// Convert the frame to another pixel format.
+    ///
+    /// Color is reduced to luma with the BT.601 weights, luma is copied to every
+    /// color channel, a missing alpha channel is opaque and chroma is averaged over
+    /// each 2x2 block when subsampling to YUV.
+    pub fn convert(&self, format: PixelFormat) -> Frame {
+        if self.format == format {
+            return self.clone();
+        }
+
+        let width = self.width as usize;
+        let height = self.height as usize;
+        let mut data = vec![0u8; format.buffer_size(width, height)];
+
+        match format {
+            PixelFormat::I420 | PixelFormat::Nv12 => {
+                self.encode_yuv(&mut data, format);
+            }
+            _ => {
+                let bytes_per_pixel = format.bytes_per_pixel().unwrap_or(1);
+                for (index, pixel) in data.chunks_exact_mut(bytes_per_pixel).enumerate() {
+                    let rgba = self.pixel_rgba(index % width, index / width);
+                    write_packed(pixel, format, rgba);
+                }
+            }
+        }
+
+        Frame::from_parts(data, self.width, self.height, format)
+    }
+
+    // read a pixel as red, green, blue, alpha in the 0.0..=255.0 range
+    fn pixel_rgba(&self, x: usize, y: usize) -> [f32; 4] {
+        let width = self.width as usize;
+        let height = self.height as usize;
+        let index = y * width + x;
+        let data = self.data();
+
+        match self.format {
+            PixelFormat::Gray8 => {
+                let v = data[index] as f32;
+                [v, v, v, 255.0]
+            }
+            PixelFormat::Gray16 => {
+                let v = u16::from_le_bytes([data[index * 2], data[index * 2 + 1]]) as f32 / 257.0;
+                [v, v, v, 255.0]
+            }
+            PixelFormat::GrayF32 => {
+                let v = read_f32(data, index) * 255.0;
+                [v, v, v, 255.0]
+            }
+            PixelFormat::Rgb => {
+                let p = &data[index * 3..index * 3 + 3];
+                [p[0] as f32, p[1] as f32, p[2] as f32, 255.0]
+            }
+            PixelFormat::Bgr => {
+                let p = &data[index * 3..index * 3 + 3];
+                [p[2] as f32, p[1] as f32, p[0] as f32, 255.0]
+            }
+            PixelFormat::Rgba => {
+                let p = &data[index * 4..index * 4 + 4];
+                [p[0] as f32, p[1] as f32, p[2] as f32, p[3] as f32]
+            }
+            PixelFormat::RgbF32 => [
+                read_f32(data, index * 3) * 255.0,
+                read_f32(data, index * 3 + 1) * 255.0,
+                read_f32(data, index * 3 + 2) * 255.0,
+                255.0,
+            ],
+            PixelFormat::RgbaF32 => [
+                read_f32(data, index * 4) * 255.0,
+                read_f32(data, index * 4 + 1) * 255.0,
+                read_f32(data, index * 4 + 2) * 255.0,
+                read_f32(data, index * 4 + 3) * 255.0,
+            ],
+            PixelFormat::I420 | PixelFormat::Nv12 => {
+                let chroma_width = (width + 1) / 2;
+                let chroma_height = (height + 1) / 2;
+                let chroma_index = (y / 2) * chroma_width + x / 2;
+                let luma_size = width * height;
+
+                let (u, v) = if self.format == PixelFormat::I420 {
+                    let v_offset = luma_size + chroma_width * chroma_height;
+                    (
+                        data[luma_size + chroma_index],
+                        data[v_offset + chroma_index],
+                    )
+                } else {
+                    (
+                        data[luma_size + chroma_index * 2],
+                        data[luma_size + chroma_index * 2 + 1],
+                    )
+                };
+
+                let [r, g, b] = yuv_to_rgb(data[index] as f32, u as f32, v as f32);
+                [r, g, b, 255.0]
+            }
+        }
+    }
+
+    // write the frame as planar YUV with 2x2 subsampled chroma
+    fn encode_yuv(&self, data: &mut [u8], format: PixelFormat) {
+        let width = self.width as usize;
+        let height = self.height as usize;
+        let chroma_width = (width + 1) / 2;
+        let chroma_height = (height + 1) / 2;
+        let luma_size = width * height;
+
+        for y in 0..height {
+            for x in 0..width {
+                let [r, g, b, _] = self.pixel_rgba(x, y);
+                data[y * width + x] = to_u8(rgb_to_yuv(r, g, b)[0]);
+            }
+        }
+
+        for cy in 0..chroma_height {
+            for cx in 0..chroma_width {
+                // average the color of the pixels sharing this chroma sample
+                let mut sum = [0.0f32; 3];
+                let mut count = 0.0;
+                for y in (cy * 2)..(cy * 2 + 2).min(height) {
+                    for x in (cx * 2)..(cx * 2 + 2).min(width) {
+                        let [r, g, b, _] = self.pixel_rgba(x, y);
+                        sum[0] += r;
+                        sum[1] += g;
+                        sum[2] += b;
+                        count += 1.0;
+                    }
+                }
+
+                let [_, u, v] = rgb_to_yuv(sum[0] / count, sum[1] / count, sum[2] / count);
+                let chroma_index = cy * chroma_width + cx;
+
+                if format == PixelFormat::I420 {
+                    data[luma_size + chroma_index] = to_u8(u);
+                    data[luma_size + chroma_width * chroma_height + chroma_index] = to_u8(v);
+                } else {
+                    data[luma_size + chroma_index * 2] = to_u8(u);
+                    data[luma_size + chroma_index * 2 + 1] = to_u8(v);
+                }
+            }
+        }
+    }
+}
+
+impl From<Image<u8>> for Frame {
+    /// Wrap an 8 bit image as a `Gray8` frame, only padded images are copied
+    fn from(image: Image<u8>) -> Self {
+        let (width, height) = (image.width as i32, image.height as i32);
+        Self::from_parts(image.into_vec(), width, height, PixelFormat::Gray8)
+    }
+}
+
+impl From<Image<f32>> for Frame {
+    /// Store a float image as a `GrayF32` frame, values outside 0.0..=1.0 are kept
+    /// as they are so float maps pass through a pipeline without loss
+    fn from(image: Image<f32>) -> Self {
+        let (width, height) = (image.width as i32, image.height as i32);
+        let data = image
+            .pixels()
+            .flat_map(|value| value.to_ne_bytes())
+            .collect();
+        Self::from_parts(data, width, height, PixelFormat::GrayF32)
+    }
+}
+
+/// A single channel image of any sample type, used for the intermediate results
+/// of the processing steps: gradients, masks, labels and float maps.
+///
+/// Samples are stored contiguously, rows are `stride` samples apart so an image can
+/// also describe a padded buffer. Gray frames convert to `Image<u8>` and `Image<f32>`
+/// and back without loss.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Image<T> {
+    data: Vec<T>,
+    width: usize,
+    height: usize,
+    // distance between the start of two rows, in samples
+    stride: usize,
+}
+
+impl<T: Copy> Image<T> {
+    /// Create an image with every sample set to `value`
+    ///
+    /// # Arguments
+    /// * `width` - Width of the image in samples
+    /// * `height` - Height of the image in samples
+    /// * `value` - Initial value of every sample
+    pub fn new(width: usize, height: usize, value: T) -> Self {
+        Self {
+            data: vec![value; width * height],
+            width,
+            height,
+            stride: width,
+        }
+    }
+
+    /// Create an image from samples stored row after row without padding
+    pub fn from_vec(data: Vec<T>, width: usize, height: usize) -> io::Result<Self> {
+        Self::from_vec_with_stride(data, width, height, width)
+    }
+
+    /// Create an image from samples with rows `stride` samples apart
+    ///
+    /// # Arguments
+    /// * `data` - The samples, the padding at the end of each row is ignored
+    /// * `width` - Width of the image in samples
+    /// * `height` - Height of the image in samples
+    /// * `stride` - Distance between the start of two rows, at least `width`
+    pub fn from_vec_with_stride(
+        data: Vec<T>,
+        width: usize,
+        height: usize,
+        stride: usize,
+    ) -> io::Result<Self> {
+        if stride < width {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "Image stride {} is smaller than its width {}",
+                    stride, width
+                ),
+            ));
+        }
+
+        let expected = if height == 0 {
+            0
+        } else {
+            stride * (height - 1) + width
+        };
+        if data.len() < expected {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "Image of {}x{} with stride {} needs {} samples, got {}",
+                    width,
+                    height,
+                    stride,
+                    expected,
+                    data.len()
+                ),
+            ));
+        }
+
+        Ok(Self {
+            data,
+            width,
+            height,
+            stride,
+        })
+    }
+
+    /// Create an image by calling `f` with the position of every sample, row by row
+    pub fn from_fn(width: usize, height: usize, mut f: impl FnMut(usize, usize) -> T) -> Self {
+        let mut data = Vec::with_capacity(width * height);
+        for y in 0..height {
+            for x in 0..width {
+                data.push(f(x, y));
+            }
+        }
+
+        Self {
+            data,
+            width,
+            height,
+            stride: width,
+        }
+    }
+
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    pub fn stride(&self) -> usize {
+        self.stride
+    }
+
+    /// The sample at a position, `None` outside the image
+    pub fn get(&self, x: usize, y: usize) -> Option<T> {
+        if x < self.width && y < self.height {
+            Some(self.data[y * self.stride + x])
+        } else {
+            None
+        }
+    }
+
+    /// The sample at a position that may lie outside the image, resolved with `border`
+    ///
+    /// # Arguments
+    /// * `x` - Column of the sample, may be negative or past the width
+    /// * `y` - Row of the sample, may be negative or past the height
+    /// * `border` - How positions outside the image are resolved
+    /// * `constant` - The sample used when the border mode is constant
+    pub fn get_with_border(&self, x: isize, y: isize, border: BorderMode, constant: T) -> T {
+        match (
+            border.resolve(x, self.width),
+            border.resolve(y, self.height),
+        ) {
+            (Some(x), Some(y)) => self.data[y * self.stride + x],
+            _ => constant,
+        }
+    }
+
+    /// The samples of one row, without padding
+    pub fn row(&self, y: usize) -> &[T] {
+        let start = y * self.stride;
+        &self.data[start..start + self.width]
+    }
+
+    pub fn row_mut(&mut self, y: usize) -> &mut [T] {
+        let start = y * self.stride;
+        &mut self.data[start..start + self.width]
+    }
+
+    /// Iterate over the rows of the image, top to bottom
+    pub fn rows(&self) -> impl Iterator<Item = &[T]> + '_ {
+        (0..self.height).map(move |y| self.row(y))
+    }
+
+    /// Iterate over every sample, row by row
+    pub fn pixels(&self) -> impl Iterator<Item = T> + '_ {
+        self.rows().flat_map(|row| row.iter().copied())
+    }
+
+    /// Create an image of the same size by applying `f` to every sample
+    pub fn map<U: Copy>(&self, f: impl FnMut(T) -> U) -> Image<U> {
+        Image {
+            data: self.pixels().map(f).collect(),
+            width: self.width,
+            height: self.height,
+            stride: self.width,
+        }
+    }
+
+    /// The samples row after row, with the row padding removed
+    pub fn into_vec(self) -> Vec<T> {
+        if self.stride == self.width {
+            let mut data = self.data;
+            data.truncate(self.width * self.height);
+            data
+        } else {
+            self.pixels().collect()
+        }
+    }
+}
+
+impl<T> Index<(usize, usize)> for Image<T> {
+    type Output = T;
+
+    /// The sample at `(x, y)`
+    fn index(&self, (x, y): (usize, usize)) -> &T {
+        assert!(
+            x < self.width && y < self.height,
+            "pixel ({}, {}) is outside the {}x{} image",
+            x,
+            y,
+            self.width,
+            self.height
+        );
+        &self.data[y * self.stride + x]
+    }
+}
+
+impl<T> IndexMut<(usize, usize)> for Image<T> {
+    fn index_mut(&mut self, (x, y): (usize, usize)) -> &mut T {
+        assert!(
+            x < self.width && y < self.height,
+            "pixel ({}, {}) is outside the {}x{} image",
+            x,
+            y,
+            self.width,
+            self.height
+        );
+        &mut self.data[y * self.stride + x]
+    }
+}
+
+// quantize a 0.0..=255.0 value to a byte
+#[inline(always)]
+fn to_u8(value: f32) -> u8 {
+    value.round().clamp(0.0, 255.0) as u8
+}
+
+#[inline(always)]
+fn read_f32(data: &[u8], sample: usize) -> f32 {
+    let bytes = &data[sample * 4..sample * 4 + 4];
+    f32::from_ne_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])
+}
+
+// BT.601 luma of a 0.0..=255.0 color
+#[inline(always)]
+fn luma(r: f32, g: f32, b: f32) -> f32 {
+    0.299 * r + 0.587 * g + 0.114 * b
+}
+
+// store a 0.0..=255.0 color as one pixel of a packed format
+fn write_packed(pixel: &mut [u8], format: PixelFormat, [r, g, b, a]: [f32; 4]) {
+    match format {
+        PixelFormat::Gray8 => pixel[0] = to_u8(luma(r, g, b)),
+        PixelFormat::Gray16 => {
+            let value = (luma(r, g, b) * 257.0).round().clamp(0.0, 65535.0) as u16;
+            pixel.copy_from_slice(&value.to_le_bytes());
+        }
+        PixelFormat::GrayF32 => pixel.copy_from_slice(&(luma(r, g, b) / 255.0).to_ne_bytes()),
+        PixelFormat::Rgb => pixel.copy_from_slice(&[to_u8(r), to_u8(g), to_u8(b)]),
+        PixelFormat::Bgr => pixel.copy_from_slice(&[to_u8(b), to_u8(g), to_u8(r)]),
+        PixelFormat::Rgba => pixel.copy_from_slice(&[to_u8(r), to_u8(g), to_u8(b), to_u8(a)]),
+        PixelFormat::RgbF32 | PixelFormat::RgbaF32 => {
+            for (bytes, value) in pixel.chunks_exact_mut(4).zip([r, g, b, a]) {
+                bytes.copy_from_slice(&(value / 255.0).to_ne_bytes());
+            }
+        }
+        PixelFormat::I420 | PixelFormat::Nv12 => unreachable!("planar formats are not packed"),
+    }
+}
+
+// BT.601 limited range YUV to a 0.0..=255.0 color
+#[inline(always)]
+fn yuv_to_rgb(y: f32, u: f32, v: f32) -> [f32; 3] {
+    let c = 1.164_383 * (y - 16.0);
+    let d = u - 128.0;
+    let e = v - 128.0;
+
+    [
+        c + 1.596_027 * e,
+        c - 0.391_762 * d - 0.812_968 * e,
+        c + 2.017_232 * d,
+    ]
+}
+
+// 0.0..=255.0 color to BT.601 limited range YUV
+#[inline(always)]
+fn rgb_to_yuv(r: f32, g: f32, b: f32) -> [f32; 3] {
+    [
+        16.0 + 0.256_788 * r + 0.504_129 * g + 0.097_906 * b,
+        128.0 - 0.148_223 * r - 0.290_993 * g + 0.439_216 * b,
+        128.0 + 0.439_216 * r - 0.367_788 * g - 0.071_427 * b,
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn map_gray_matches_the_converted_frame() {
+        let data: Vec<u8> = (0..4 * 3 * 3).map(|i| (i * 7) as u8).collect();
+        let frame = Frame::new(data, 4, 3, PixelFormat::Rgb).unwrap();
+
+        let mapped = frame.map_gray(|value| value);
+        let converted = frame.clone().into_gray_image();
+        assert_eq!(mapped.into_vec(), converted.into_vec());
+    }
+
+    #[test]
+    fn gray_images_move_in_and_out_of_frames() {
+        let image = Image::from_fn(5, 3, |x, y| (y * 5 + x) as u8);
+        let expected = image.clone();
+        let buffer = image.row(0).as_ptr();
+
+        let frame = Frame::from(image);
+        assert_eq!((frame.width(), frame.height()), (5, 3));
+        assert_eq!(frame.data().as_ptr(), buffer);
+
+        let image = frame.into_gray_image();
+        assert_eq!(image, expected);
+        assert_eq!(image.row(0).as_ptr(), buffer);
+    }
+
+    #[test]
+    fn yuv_white_and_black_decode_to_rgb() {
+        // 4x2 frame, white on the left half and black on the right, neutral chroma
+        let luma = [235, 235, 16, 16, 235, 235, 16, 16];
+        let mut i420 = luma.to_vec();
+        i420.extend_from_slice(&[128, 128, 128, 128]);
+        let mut nv12 = luma.to_vec();
+        nv12.extend_from_slice(&[128, 128, 128, 128]);
+
+        for (data, format) in [(i420, PixelFormat::I420), (nv12, PixelFormat::Nv12)] {
+            let rgb = Frame::new(data, 4, 2, format)
+                .unwrap()
+                .convert(PixelFormat::Rgb);
+            for row in rgb.data().chunks_exact(4 * 3) {
+                assert_eq!(
+                    row,
+                    [255, 255, 255, 255, 255, 255, 0, 0, 0, 0, 0, 0],
+                    "{}",
+                    format
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn red_encodes_to_its_bt601_yuv() {
+        let red = Frame::new([255, 0, 0].repeat(4), 2, 2, PixelFormat::Rgb).unwrap();
+
+        let i420 = red.convert(PixelFormat::I420);
+        assert_eq!(i420.data(), [81, 81, 81, 81, 90, 240]);
+
+        // NV12 interleaves the same chroma after the luma plane
+        let nv12 = red.convert(PixelFormat::Nv12);
+        assert_eq!(nv12.data(), [81, 81, 81, 81, 90, 240]);
+
+        // and decoding gets back close to the original color
+        for pixel in i420.convert(PixelFormat::Rgb).data().chunks_exact(3) {
+            assert!(
+                pixel[0] >= 253 && pixel[1] <= 2 && pixel[2] <= 2,
+                "{:?}",
+                pixel
+            );
+        }
+    }
+
+    #[test]
+    fn yuv_chroma_is_shared_by_2x2_blocks() {
+        // 3x3 frame: the chroma planes are 2x2, the last row and column keep their own
+        let data: Vec<u8> = (0..9)
+            .flat_map(|i| if i % 3 == 2 { [0, 0, 255] } else { [255, 0, 0] })
+            .collect();
+        let frame = Frame::new(data, 3, 3, PixelFormat::Rgb).unwrap();
+
+        let i420 = frame.convert(PixelFormat::I420);
+        assert_eq!(i420.data().len(), 9 + 2 * 4);
+        let (u, v) = (&i420.data()[9..13], &i420.data()[13..17]);
+        // red on the left, blue in the last column
+        assert_eq!((u[0], v[0]), (90, 240));
+        assert_eq!((u[1], v[1]), (240, 110));
+        assert_eq!((u[2], v[2]), (90, 240));
+        assert_eq!((u[3], v[3]), (240, 110));
+    }
+
+    #[test]
+    fn gray16_samples_are_little_endian() {
+        let frame = Frame::new(vec![0x00, 0xFF, 0xFF, 0x00], 2, 1, PixelFormat::Gray16).unwrap();
+
+        // 0xFF00 is almost white, 0x00FF almost black
+        let gray = frame.convert(PixelFormat::Gray8);
+        assert_eq!(gray.data(), [254, 1]);
+
+        let back = gray.convert(PixelFormat::Gray16);
+        assert_eq!(back.data(), [0xFE, 0xFE, 0x01, 0x01]);
+    }
+
+    #[test]
+    fn color_reduces_to_bt601_luma() {
+        let data = vec![255, 0, 0, 0, 255, 0, 0, 0, 255, 255, 255, 255];
+        let frame = Frame::new(data, 4, 1, PixelFormat::Rgb).unwrap();
+
+        assert_eq!(frame.convert(PixelFormat::Gray8).data(), [76, 150, 29, 255]);
+    }
+
+    #[test]
+    fn float_frames_hold_the_0_to_1_range() {
+        let frame = Frame::new(vec![0, 51, 255], 3, 1, PixelFormat::Gray8).unwrap();
+
+        let float = frame.convert(PixelFormat::GrayF32);
+        let values: Vec<f32> = float.to_float_image().pixels().collect();
+        assert_eq!(values, [0.0, 0.2, 1.0]);
+        assert_eq!(float.convert(PixelFormat::Gray8).data(), frame.data());
+    }
+
+    #[test]
+    fn packed_conversions_round_trip() {
+        let rgba: Vec<u8> = (0..3 * 2 * 4).map(|i| (i * 11) as u8).collect();
+        let frame = Frame::new(rgba, 3, 2, PixelFormat::Rgba).unwrap();
+
+        let float = frame.convert(PixelFormat::RgbaF32);
+        assert_eq!(float.data().len(), 3 * 2 * 16);
+        assert_eq!(float.convert(PixelFormat::Rgba).data(), frame.data());
+
+        // BGR swaps the color bytes, dropping alpha and coming back makes it opaque
+        let rgb = frame.convert(PixelFormat::Rgb);
+        let bgr = rgb.convert(PixelFormat::Bgr);
+        assert_eq!(
+            bgr.data()[..3],
+            [rgb.data()[2], rgb.data()[1], rgb.data()[0]]
+        );
+        assert_eq!(bgr.convert(PixelFormat::Rgb).data(), rgb.data());
+        assert!(rgb
+            .convert(PixelFormat::Rgba)
+            .data()
+            .chunks_exact(4)
+            .all(|pixel| pixel[3] == 255));
+    }
+
+    #[test]
+    #[should_panic(expected = "outside")]
+    fn index_into_row_padding_panics() {
+        // (3, 0) is inside the buffer but past the end of the row
+        let image = Image::from_vec_with_stride(vec![0u8; 8], 3, 2, 4).unwrap();
+        let _ = image[(3, 0)];
+    }
+}
@@ -1,10 +1,21 @@
-use crate::video_pipeline::Frame;
-use std::{io, path::PathBuf};
+use crate::frame::Frame;
+use crate::profiling::Profiler;
+use crate::video_pipeline::FrameInfo;
+use crate::video_writer::{VideoLayout, VideoWriter};
+use serde::Serialize;
+use std::any::{Any, TypeId};
+use std::collections::{BTreeMap, HashMap};
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{mpsc, Arc, Mutex};
+use std::{io, path::PathBuf, thread};
 
 // trait for a step within the frame pipeline
 /// A trait representing a single step in a machine vision processing pipeline.
 /// Each step takes a frame as input, processes it, and returns a modified frame.
-pub trait PipelineStep {
+/// Steps are shared between worker threads, so they must be `Send + Sync`.
+pub trait PipelineStep: Send + Sync {
     /// Process a single frame, applying this step's machine vision algorithm
     ///
     /// # Arguments
@@ -12,21 +23,330 @@ pub trait PipelineStep {
     ///
     /// # Returns
     /// * `io::Result<Frame>` - The processed frame or an error
-    fn process(&self, frame: &mut Frame, frame_count: u32) -> io::Result<()>;
+    fn process(&self, frame: &mut Frame, frame_count: u64) -> io::Result<()>;
+
+    /// Process a single frame and attach structured results, such as counts or
+    /// detections, to its context. Steps that only transform the image keep the
+    /// default, which forwards to `process`.
+    ///
+    /// # Arguments
+    /// * `frame` - The input frame to process
+    /// * `context` - The results of the frame so far, numbered like the frame
+    fn process_with_context(
+        &self,
+        frame: &mut Frame,
+        context: &mut FrameContext,
+    ) -> io::Result<()> {
+        self.process(frame, context.frame_count())
+    }
+
+    /// Whether the step carries state from one frame to the next, such as smoothed
+    /// thresholds. Those steps need the frames in source order, so a pipeline with
+    /// several jobs refuses to run them.
+    fn is_stateful(&self) -> bool {
+        false
+    }
 
     /// Get the name of this pipeline step for debugging and logging
     fn name(&self) -> &str;
 }
 
-/// A pipeline that runs a sequence of machine vision processing steps on video frames.
-/// Supports debugging output of intermediate results between steps.
-pub struct FramePipeline {
+/// Structured results the steps attach to a frame as it moves through the pipeline,
+/// keyed by the position and name of the step, such as `3:ConnectedComponents`.
+/// Written to `frame_<n>_results.json` next to the images and appended to
+/// `results.jsonl` in the output directory, in source order.
+///
+/// Steps can also hand typed data, such as the gradients of the frame, to later steps
+/// of the same frame. That data is never written out.
+#[derive(Default)]
+pub struct FrameContext {
+    frame_count: u64,
+    // key of the step running, results are attached under it
+    step: String,
+    // results by step, in pipeline order
+    results: serde_json::Map<String, serde_json::Value>,
+    // in memory data by type, at most one value of every type
+    data: HashMap<TypeId, Box<dyn Any + Send>>,
+}
+
+impl FrameContext {
+    pub fn new(frame_count: u64) -> Self {
+        Self {
+            frame_count,
+            step: String::new(),
+            results: serde_json::Map::new(),
+            data: HashMap::new(),
+        }
+    }
+
+    /// Number of the frame the results belong to
+    pub fn frame_count(&self) -> u64 {
+        self.frame_count
+    }
+
+    /// Select the step whose results `insert` attaches, the pipeline calls this before
+    /// running every step
+    ///
+    /// # Arguments
+    /// * `index` - Position of the step in the pipeline, starting at 1
+    /// * `name` - Name of the step
+    pub fn set_step(&mut self, index: usize, name: &str) {
+        self.step = step_key(index, name);
+    }
+
+    /// Attach the result of the running step to the frame, replacing an earlier result
+    /// of the same step
+    ///
+    /// # Arguments
+    /// * `value` - Any serializable value
+    pub fn insert<T: Serialize + ?Sized>(&mut self, value: &T) -> io::Result<()> {
+        let value = serde_json::to_value(value)
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
+        self.results.insert(self.step.clone(), value);
+
+        Ok(())
+    }
+
+    /// The result an earlier step attached, by its position and name
+    pub fn get(&self, index: usize, name: &str) -> Option<&serde_json::Value> {
+        self.results.get(&step_key(index, name))
+    }
+
+    pub fn results(&self) -> &serde_json::Map<String, serde_json::Value> {
+        &self.results
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.results.is_empty()
+    }
+
+    /// Hand data to later steps of this frame, replacing earlier data of the same type
+    pub fn insert_data<T: Any + Send>(&mut self, value: T) {
+        self.data.insert(TypeId::of::<T>(), Box::new(value));
+    }
+
+    /// Data of a type attached by an earlier step
+    pub fn data<T: Any + Send>(&self) -> Option<&T> {
+        self.data
+            .get(&TypeId::of::<T>())
+            .and_then(|value| value.downcast_ref())
+    }
+
+    /// Remove data of a type attached by an earlier step
+    pub fn take_data<T: Any + Send>(&mut self) -> Option<T> {
+        self.data
+            .remove(&TypeId::of::<T>())
+            .and_then(|value| value.downcast().ok())
+            .map(|value| *value)
+    }
+}
+
+// key of the results of a step, unique even when a step type is used twice
+fn step_key(index: usize, name: &str) -> String {
+    format!("{}:{}", index, name)
+}
+
+/// Directory the outputs of a frame are written to, inside the output directory
+pub fn frame_dir(output_dir: &str, frame_count: u64) -> PathBuf {
+    PathBuf::from(output_dir).join(format!("frame_{:08}_output", frame_count))
+}
+
+/// Write a per frame file as `frame_<n>_<name>` into the frame directory, creating it
+/// when needed
+///
+/// # Arguments
+/// * `output_dir` - The output directory of the pipeline
+/// * `frame_count` - Number of the frame the file belongs to
+/// * `name` - File name after the frame number, including the extension
+/// * `contents` - The bytes to write
+pub fn write_frame_file(
+    output_dir: &str,
+    frame_count: u64,
+    name: &str,
+    contents: impl AsRef<[u8]>,
+) -> io::Result<()> {
+    let frame_dir = frame_dir(output_dir, frame_count);
+    std::fs::create_dir_all(&frame_dir)?;
+    std::fs::write(
+        frame_dir.join(format!("frame_{:08}_{}", frame_count, name)),
+        contents,
+    )
+}
+
+/// Write a value as a per frame JSON file, see `write_frame_file`
+pub fn write_frame_json<T: Serialize + ?Sized>(
+    output_dir: &str,
+    frame_count: u64,
+    name: &str,
+    value: &T,
+) -> io::Result<()> {
+    let json = serde_json::to_string(value)
+        .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
+    write_frame_file(output_dir, frame_count, name, json)
+}
+
+/// Appends the results of every frame as one JSON line to `results.jsonl`.
+/// The file is only created once a frame has results.
+struct ResultsLog {
+    path: PathBuf,
+    writer: Option<BufWriter<File>>,
+}
+
+impl ResultsLog {
+    fn append(&mut self, info: &FrameInfo, context: &FrameContext) -> io::Result<()> {
+        if context.is_empty() {
+            return Ok(());
+        }
+
+        let writer = match self.writer.as_mut() {
+            Some(writer) => writer,
+            None => self
+                .writer
+                .insert(BufWriter::new(File::create(&self.path)?)),
+        };
+
+        let line = serde_json::json!({
+            "frame": info,
+            "results": context.results(),
+        });
+        serde_json::to_writer(&mut *writer, &line)
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
+        writer.write_all(b"\n")
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self.writer.as_mut() {
+            Some(writer) => writer.flush(),
+            None => Ok(()),
+        }
+    }
+}
+
+/// The steps of a pipeline and where their per frame results are written.
+/// Shared read-only between worker threads.
+struct StepRunner {
     /// The ordered sequence of processing steps to apply
     steps: Vec<Box<dyn PipelineStep>>,
     /// Directory to store debug output and intermediate results
     output_dir: String,
     /// Whether to save debug output after each step
     debug: bool,
+    /// Whether to save the input and processed frame of every step as PNG
+    save_images: bool,
+    /// Records the time and allocations of every step when profiling
+    profiler: Option<Profiler>,
+}
+
+impl StepRunner {
+    /// Run every step on a frame, returning the results the steps attached and a copy of
+    /// the input when `keep_original` is set
+    fn run(
+        &self,
+        frame: &mut Frame,
+        info: &FrameInfo,
+        keep_original: bool,
+    ) -> io::Result<(Option<Frame>, FrameContext)> {
+        let frame_count = info.frame_number;
+
+        // Create frame-specific output directory
+        let frame_dir = frame_dir(&self.output_dir, frame_count);
+
+        if self.save_images || self.debug {
+            std::fs::create_dir_all(&frame_dir)?;
+        }
+
+        if self.save_images {
+            let frame_path = frame_dir.join(format!("frame_pre_{:08}.png", frame_count));
+            frame.save(&frame_path)?;
+
+            // record where the frame sits in the source for downstream tooling
+            let info_path = frame_dir.join(format!("frame_{:08}_info.json", frame_count));
+            let info_json = serde_json::to_string_pretty(info)
+                .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
+            std::fs::write(info_path, info_json)?;
+        }
+
+        // keep the untouched frame around when it is encoded next to the result
+        let original = if keep_original {
+            Some(frame.clone())
+        } else {
+            None
+        };
+
+        let mut context = FrameContext::new(frame_count);
+
+        // Process through each step
+        for (index, step) in self.steps.iter().enumerate() {
+            if self.debug {
+                println!("Executing step {}: {}", index + 1, step.name());
+            }
+
+            context.set_step(index + 1, step.name());
+
+            // Process frame and immediately drop the old one
+            match self.profiler.as_ref() {
+                Some(profiler) => profiler.measure(index + 1, step.name(), frame_count, || {
+                    step.process_with_context(frame, &mut context)
+                })?,
+                None => step.process_with_context(frame, &mut context)?,
+            }
+
+            // If in debug mode, save intermediate results
+            if self.debug {
+                let debug_path = frame_dir.join(format!(
+                    "debug_step_{}_{}_{:08}.png",
+                    index + 1,
+                    step.name(),
+                    frame_count
+                ));
+
+                frame.save(&debug_path)?;
+            }
+        }
+
+        if let Some(profiler) = self.profiler.as_ref() {
+            profiler.frame_done();
+        }
+
+        // Save the final processed frame
+        if self.save_images {
+            let frame_path = frame_dir.join(format!("frame_{:08}.png", frame_count));
+            frame.save(&frame_path)?;
+        }
+
+        if !context.is_empty() {
+            std::fs::create_dir_all(&frame_dir)?;
+            let results_path = frame_dir.join(format!("frame_{:08}_results.json", frame_count));
+            let results_json = serde_json::to_string_pretty(context.results())
+                .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
+            std::fs::write(results_path, results_json)?;
+        }
+
+        Ok((original, context))
+    }
+}
+
+/// A frame that went through every step, waiting to be emitted in source order
+struct ProcessedFrame {
+    info: FrameInfo,
+    original: Option<Frame>,
+    frame: Frame,
+    context: FrameContext,
+}
+
+/// A pipeline that runs a sequence of machine vision processing steps on video frames.
+/// Supports debugging output of intermediate results between steps.
+pub struct FramePipeline {
+    /// The steps and their per frame outputs
+    runner: StepRunner,
+    /// Optional encoder receiving every processed frame
+    video_writer: Option<VideoWriter>,
+    /// Per frame results of every step, one line per frame
+    results_log: ResultsLog,
+    /// Number of frames processed concurrently by `run`
+    jobs: usize,
+    /// Whether `finish` writes the step timings as a Chrome trace
+    trace: bool,
 }
 
 impl FramePipeline {
@@ -49,56 +369,373 @@ impl FramePipeline {
         }
 
         Ok(Self {
-            steps: Vec::new(),
-            output_dir: output_dir.to_string(),
-            debug: false,
+            runner: StepRunner {
+                steps: Vec::new(),
+                output_dir: output_dir.to_string(),
+                debug: false,
+                save_images: true,
+                profiler: None,
+            },
+            video_writer: None,
+            results_log: ResultsLog {
+                path: PathBuf::from(output_dir).join("results.jsonl"),
+                writer: None,
+            },
+            jobs: 1,
+            trace: false,
         })
     }
 
     pub fn add_step<T: PipelineStep + 'static>(&mut self, step: T) {
-        self.steps.push(Box::new(step));
+        self.runner.steps.push(Box::new(step));
+    }
+
+    pub fn add_boxed_step(&mut self, step: Box<dyn PipelineStep>) {
+        self.runner.steps.push(step);
     }
 
     pub fn set_debug(&mut self, debug: bool) {
-        self.debug = debug;
+        self.runner.debug = debug;
     }
 
-    pub fn process_frame(&mut self, frame: &mut Frame, frame_count: u32) -> io::Result<()> {
-        // Create frame-specific output directory
-        let frame_dir =
-            PathBuf::from(&self.output_dir).join(format!("frame_{:08}_output", frame_count));
+    pub fn set_save_images(&mut self, save_images: bool) {
+        self.runner.save_images = save_images;
+    }
+
+    /// Set how many frames `run` processes concurrently, 1 processes frames on the calling thread
+    pub fn set_jobs(&mut self, jobs: usize) {
+        self.jobs = jobs.max(1);
+    }
 
-        std::fs::create_dir_all(&frame_dir)?;
+    /// Measure the wall time and allocations of every step on every frame, `finish`
+    /// prints a summary and saves it to `profile.json` in the output directory
+    pub fn set_profiling(&mut self, profiling: bool) {
+        if !profiling {
+            self.runner.profiler = None;
+        } else if self.runner.profiler.is_none() {
+            self.runner.profiler = Some(if self.trace {
+                Profiler::with_trace()
+            } else {
+                Profiler::new()
+            });
+        }
+    }
 
-        let frame_path = frame_dir.join(format!("frame_pre_{:08}.png", frame_count));
-        frame.save(&frame_path)?;
+    /// Also save every step run to `trace.json` in the Chrome trace event format,
+    /// enables profiling. Unlike the summary the trace grows with every frame.
+    pub fn set_trace(&mut self, trace: bool) {
+        self.trace = trace;
+        if trace || self.runner.profiler.is_some() {
+            // the runs are only kept by a profiler created for a trace
+            self.runner.profiler = None;
+            self.set_profiling(true);
+        }
+    }
 
-        // Process through each step
-        for (index, step) in self.steps.iter().enumerate() {
-            if self.debug {
-                println!("Executing step {}: {}", index + 1, step.name());
+    /// Encode every processed frame into a video file in addition to (or instead of) PNGs
+    pub fn set_video_writer(&mut self, writer: VideoWriter) {
+        self.video_writer = Some(writer);
+    }
+
+    /// Finalize any outputs that span multiple frames, such as the video file and the
+    /// profile
+    pub fn finish(&mut self) -> io::Result<()> {
+        if let Some(writer) = self.video_writer.as_mut() {
+            writer.finish()?;
+        }
+
+        if let Some(profiler) = self.runner.profiler.as_ref() {
+            let output_dir = PathBuf::from(&self.runner.output_dir);
+            let report = profiler.report();
+            println!("{}", report);
+            report.save(&output_dir.join("profile.json"))?;
+
+            if self.trace {
+                profiler.write_chrome_trace(&output_dir.join("trace.json"))?;
             }
+        }
 
-            // Process frame and immediately drop the old one
-            step.process(frame, frame_count)?;
+        self.results_log.flush()
+    }
 
-            // If in debug mode, save intermediate results
-            if self.debug {
-                let debug_path = frame_dir.join(format!(
-                    "debug_step_{}_{}_{:08}.png",
-                    index + 1,
-                    step.name(),
-                    frame_count
+    fn keep_original(&self) -> bool {
+        matches!(
+            self.video_writer.as_ref().map(|w| w.layout()),
+            Some(VideoLayout::SideBySide)
+        )
+    }
+
+    pub fn process_frame(&mut self, frame: &mut Frame, info: &FrameInfo) -> io::Result<()> {
+        let (original, context) = self.runner.run(frame, info, self.keep_original())?;
+        Self::emit(
+            &mut self.video_writer,
+            &mut self.results_log,
+            original.as_ref(),
+            frame,
+            &context,
+            info,
+        )
+    }
+
+    // hand a processed frame to the outputs that need frames in source order
+    fn emit(
+        video_writer: &mut Option<VideoWriter>,
+        results_log: &mut ResultsLog,
+        original: Option<&Frame>,
+        frame: &Frame,
+        context: &FrameContext,
+        info: &FrameInfo,
+    ) -> io::Result<()> {
+        if let Some(writer) = video_writer.as_mut() {
+            writer.write_frame(original, frame, info)?;
+        }
+
+        results_log.append(info, context)
+    }
+
+    /// Process every frame from a source, spreading frames over `jobs` worker threads.
+    ///
+    /// Frames are emitted to the video writer in the order they were produced. At most
+    /// `2 * jobs` frames are in flight, counting frames waiting for a worker, being
+    /// processed and waiting for an earlier slow frame to be emitted. A slow pipeline or
+    /// a single slow frame therefore stops pulling from the source instead of letting
+    /// frames pile up. A source error stops the run once the frames already queued are
+    /// finished. Stateful steps are refused with more than one job.
+    pub fn run<I, E>(&mut self, mut frames: I) -> io::Result<()>
+    where
+        I: Iterator<Item = Result<(FrameInfo, Frame), E>>,
+        E: Into<io::Error>,
+    {
+        if self.jobs > 1 {
+            if let Some(step) = self.runner.steps.iter().find(|step| step.is_stateful()) {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!(
+                        "Step {} carries state between frames and needs frames in order, \
+                         run it with a single job",
+                        step.name()
+                    ),
                 ));
+            }
+        }
 
-                frame.save(&debug_path)?;
+        if self.jobs <= 1 {
+            for item in frames {
+                let (info, mut frame) = item.map_err(Into::into)?;
+                self.process_frame(&mut frame, &info)?;
             }
+
+            return Ok(());
         }
 
-        // Save the final processed frame
-        let frame_path = frame_dir.join(format!("frame_{:08}.png", frame_count));
-        frame.save(&frame_path)?;
+        let jobs = self.jobs;
+        let keep_original = self.keep_original();
+        let runner = &self.runner;
+        let video_writer = &mut self.video_writer;
+        let results_log = &mut self.results_log;
 
-        Ok(())
+        thread::scope(|scope| {
+            let (job_tx, job_rx) = mpsc::sync_channel::<(u64, FrameInfo, Frame)>(jobs);
+            let (done_tx, done_rx) = mpsc::sync_channel::<(u64, io::Result<ProcessedFrame>)>(jobs);
+
+            // one slot per frame in flight, taken by the producer before pulling a frame
+            // and given back once the frame is emitted
+            let (slot_tx, slot_rx) = mpsc::sync_channel::<()>(2 * jobs);
+
+            // the receiver is dropped once every worker has exited, which unblocks the producer
+            let job_rx = Arc::new(Mutex::new(job_rx));
+
+            for _ in 0..jobs {
+                let job_rx = Arc::clone(&job_rx);
+                let done_tx = done_tx.clone();
+
+                scope.spawn(move || loop {
+                    let job = job_rx.lock().unwrap().recv();
+                    let (sequence, info, mut frame) = match job {
+                        Ok(job) => job,
+                        Err(_) => break,
+                    };
+
+                    // a panicking step fails the frame instead of leaving its slot taken
+                    let result = panic::catch_unwind(AssertUnwindSafe(|| {
+                        runner.run(&mut frame, &info, keep_original)
+                    }))
+                    .unwrap_or_else(|_| {
+                        Err(io::Error::new(
+                            io::ErrorKind::Other,
+                            format!("A step panicked on frame {}", info.frame_number),
+                        ))
+                    })
+                    .map(|(original, context)| ProcessedFrame {
+                        info,
+                        original,
+                        frame,
+                        context,
+                    });
+
+                    // the collector stopped after an error, nothing left to do
+                    if done_tx.send((sequence, result)).is_err() {
+                        break;
+                    }
+                });
+            }
+            drop(job_rx);
+            drop(done_tx);
+
+            // reorder results and emit them in source order
+            let collector = scope.spawn(move || -> io::Result<()> {
+                let mut pending = BTreeMap::new();
+                let mut next_sequence = 0u64;
+
+                for (sequence, result) in done_rx {
+                    pending.insert(sequence, result);
+
+                    while let Some(result) = pending.remove(&next_sequence) {
+                        let processed = result?;
+                        Self::emit(
+                            video_writer,
+                            results_log,
+                            processed.original.as_ref(),
+                            &processed.frame,
+                            &processed.context,
+                            &processed.info,
+                        )?;
+                        next_sequence += 1;
+
+                        // free the slot of the emitted frame
+                        let _ = slot_rx.recv();
+                    }
+                }
+
+                Ok(())
+            });
+
+            // blocks while all slots are taken, applying back-pressure to the source
+            let mut source_error = None;
+            let mut sequence = 0u64;
+            loop {
+                // the collector stopped after an error
+                if slot_tx.send(()).is_err() {
+                    break;
+                }
+
+                let item = match frames.next() {
+                    Some(item) => item,
+                    None => break,
+                };
+                let (info, frame) = match item {
+                    Ok(frame) => frame,
+                    Err(err) => {
+                        source_error = Some(err.into());
+                        break;
+                    }
+                };
+
+                if job_tx.send((sequence, info, frame)).is_err() {
+                    break;
+                }
+                sequence += 1;
+            }
+            drop(job_tx);
+
+            let result = collector.join().unwrap();
+            match source_error {
+                Some(err) => result.and(Err(err)),
+                None => result,
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Smoothed;
+
+    impl PipelineStep for Smoothed {
+        fn process(&self, _frame: &mut Frame, _frame_count: u64) -> io::Result<()> {
+            Ok(())
+        }
+
+        fn is_stateful(&self) -> bool {
+            true
+        }
+
+        fn name(&self) -> &str {
+            "Smoothed"
+        }
+    }
+
+    // panics on the second frame, like a step hitting an out of bounds pixel
+    struct Panicking;
+
+    impl PipelineStep for Panicking {
+        fn process(&self, _frame: &mut Frame, frame_count: u64) -> io::Result<()> {
+            if frame_count == 1 {
+                panic!("pixel outside the image");
+            }
+            Ok(())
+        }
+
+        fn name(&self) -> &str {
+            "Panicking"
+        }
+    }
+
+    #[test]
+    fn results_are_keyed_by_step_in_pipeline_order() {
+        let mut context = FrameContext::new(7);
+        for (index, name) in [(2, "HoughLines"), (10, "ConnectedComponents")] {
+            context.set_step(index, name);
+            context.insert(&index).unwrap();
+        }
+        // a second result of the same step replaces the first
+        context.insert(&"blobs").unwrap();
+
+        let keys: Vec<&String> = context.results().keys().collect();
+        assert_eq!(keys, ["2:HoughLines", "10:ConnectedComponents"]);
+        assert_eq!(context.get(2, "HoughLines"), Some(&serde_json::json!(2)));
+        assert_eq!(
+            context.get(10, "ConnectedComponents"),
+            Some(&serde_json::json!("blobs"))
+        );
+    }
+
+    #[test]
+    fn stateful_steps_need_a_single_job() {
+        let output_dir = std::env::temp_dir().join("anuvis_stateful_steps");
+        let mut pipeline = FramePipeline::new(output_dir.to_str().unwrap()).unwrap();
+        pipeline.add_step(Smoothed);
+        pipeline.set_jobs(2);
+
+        let frames = std::iter::empty::<Result<(FrameInfo, Frame), io::Error>>();
+        let error = pipeline.run(frames).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+
+        pipeline.set_jobs(1);
+        assert!(pipeline
+            .run(std::iter::empty::<Result<_, io::Error>>())
+            .is_ok());
+    }
+
+    #[test]
+    fn a_panicking_step_fails_the_run() {
+        let output_dir = std::env::temp_dir().join("anuvis_panicking_step");
+        let mut pipeline = FramePipeline::new(output_dir.to_str().unwrap()).unwrap();
+        pipeline.add_step(Panicking);
+        pipeline.set_save_images(false);
+        pipeline.set_jobs(2);
+
+        let frames = (0..8u64).map(|frame_number| {
+            let info = FrameInfo {
+                frame_number,
+                ..FrameInfo::default()
+            };
+            let frame: Frame = crate::frame::Image::from_fn(4, 4, |_, _| 0u8).into();
+            Ok::<_, io::Error>((info, frame))
+        });
+
+        assert!(pipeline.run(frames).is_err());
     }
 }
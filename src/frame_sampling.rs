@@ -0,0 +1,295 @@
+use std::time::Duration;
+
+/// Selects which decoded frames are handed to the frame pipeline
+#[derive(Debug, Clone, PartialEq)]
+pub enum FrameSampling {
+    /// Keep every decoded frame
+    All,
+    /// Keep every nth frame, starting with the first
+    EveryNth(u64),
+    /// Keep frames at a fixed rate in frames per second of video time
+    Fps(f64),
+    /// Keep the first frame at or after each timestamp
+    Timestamps(Vec<Duration>),
+    /// Keep frames with these zero based frame numbers
+    FrameNumbers(Vec<u64>),
+    /// Keep only frames that can be decoded independently
+    KeyframesOnly,
+}
+
+/// Outcome of offering a frame to a sampler
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SampleDecision {
+    /// The frame is selected
+    Keep,
+    /// The frame is not selected
+    Skip,
+    /// No further frames will be selected, decoding can stop
+    Done,
+}
+
+/// Stateful evaluation of a `FrameSampling` over a stream of frames
+pub struct FrameSampler {
+    sampling: FrameSampling,
+    /// Position in the timestamp or frame number list
+    cursor: usize,
+    /// Earliest presentation time of the next frame in fps mode
+    next_due: Option<Duration>,
+}
+
+impl FrameSampler {
+    pub fn new(sampling: FrameSampling) -> Self {
+        // lists are walked in order, so sort them once up front
+        let sampling = match sampling {
+            FrameSampling::Timestamps(mut timestamps) => {
+                timestamps.sort();
+                timestamps.dedup();
+                FrameSampling::Timestamps(timestamps)
+            }
+            FrameSampling::FrameNumbers(mut frames) => {
+                frames.sort_unstable();
+                frames.dedup();
+                FrameSampling::FrameNumbers(frames)
+            }
+            other => other,
+        };
+
+        Self {
+            sampling,
+            cursor: 0,
+            next_due: None,
+        }
+    }
+
+    /// Rewind the sampler to the start of the stream
+    pub fn reset(&mut self) {
+        self.cursor = 0;
+        self.next_due = None;
+    }
+
+    /// Decide whether a frame is selected
+    ///
+    /// # Arguments
+    /// * `index` - Zero based position of the frame in the source
+    /// * `pts` - Presentation timestamp of the frame, if known
+    /// * `keyframe` - Whether the frame can be decoded independently
+    pub fn decide(&mut self, index: u64, pts: Option<Duration>, keyframe: bool) -> SampleDecision {
+        match &self.sampling {
+            FrameSampling::All => SampleDecision::Keep,
+            FrameSampling::EveryNth(n) => {
+                if *n <= 1 || index % n == 0 {
+                    SampleDecision::Keep
+                } else {
+                    SampleDecision::Skip
+                }
+            }
+            FrameSampling::Fps(fps) => {
+                // without a timestamp there is nothing to pace against
+                let pts = match pts {
+                    Some(pts) => pts,
+                    None => return SampleDecision::Keep,
+                };
+
+                let interval = Duration::from_secs_f64(1.0 / fps);
+                let due = *self.next_due.get_or_insert(pts);
+                if pts < due {
+                    return SampleDecision::Skip;
+                }
+
+                let mut next_due = due + interval;
+                while next_due <= pts {
+                    next_due += interval;
+                }
+                self.next_due = Some(next_due);
+
+                SampleDecision::Keep
+            }
+            FrameSampling::Timestamps(timestamps) => {
+                if self.cursor >= timestamps.len() {
+                    return SampleDecision::Done;
+                }
+
+                let pts = match pts {
+                    Some(pts) => pts,
+                    None => return SampleDecision::Skip,
+                };
+
+                if pts < timestamps[self.cursor] {
+                    return SampleDecision::Skip;
+                }
+
+                // several requested times may land on the same frame
+                while self.cursor < timestamps.len() && timestamps[self.cursor] <= pts {
+                    self.cursor += 1;
+                }
+
+                SampleDecision::Keep
+            }
+            FrameSampling::FrameNumbers(frames) => {
+                while self.cursor < frames.len() && frames[self.cursor] < index {
+                    self.cursor += 1;
+                }
+
+                if self.cursor >= frames.len() {
+                    return SampleDecision::Done;
+                }
+
+                if frames[self.cursor] == index {
+                    self.cursor += 1;
+                    SampleDecision::Keep
+                } else {
+                    SampleDecision::Skip
+                }
+            }
+            FrameSampling::KeyframesOnly => {
+                if keyframe {
+                    SampleDecision::Keep
+                } else {
+                    SampleDecision::Skip
+                }
+            }
+        }
+    }
+}
+
+/// Parse a timestamp such as `83.5`, `01:23.5` or `00:01:23.5`
+pub fn parse_timestamp(input: &str) -> Result<Duration, String> {
+    let parts: Vec<&str> = input.trim().split(':').collect();
+    if parts.is_empty() || parts.len() > 3 {
+        return Err(format!("Invalid timestamp '{}', expected [[HH:]MM:]SS[.fff]", input));
+    }
+
+    let mut seconds = 0.0;
+    for (i, part) in parts.iter().enumerate() {
+        let is_last = i == parts.len() - 1;
+        let value: f64 = if is_last {
+            part.parse().ok()
+        } else {
+            part.parse::<u64>().ok().map(|v| v as f64)
+        }
+        .filter(|v: &f64| v.is_finite() && *v >= 0.0)
+        .ok_or_else(|| format!("Invalid timestamp '{}', expected [[HH:]MM:]SS[.fff]", input))?;
+
+        seconds = seconds * 60.0 + value;
+    }
+
+    Ok(Duration::from_secs_f64(seconds))
+}
+
+/// Parse a sampling rate in frames per second, it must be positive
+pub fn parse_fps(input: &str) -> Result<f64, String> {
+    match input.trim().parse::<f64>() {
+        Ok(fps) if fps.is_finite() && fps > 0.0 => Ok(fps),
+        _ => Err(format!("Invalid fps '{}', expected a positive number", input)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // frame numbers kept from a stream of `count` frames at `rate` frames per second
+    fn kept(sampling: FrameSampling, rate: f64, count: u64) -> Vec<u64> {
+        let mut sampler = FrameSampler::new(sampling);
+        let mut kept = Vec::new();
+        for index in 0..count {
+            let pts = Duration::from_secs_f64(index as f64 / rate);
+            match sampler.decide(index, Some(pts), false) {
+                SampleDecision::Keep => kept.push(index),
+                SampleDecision::Skip => {}
+                SampleDecision::Done => break,
+            }
+        }
+        kept
+    }
+
+    #[test]
+    fn timestamps_in_every_form() {
+        let seconds = |input| parse_timestamp(input).map(|d| d.as_secs_f64());
+
+        assert_eq!(seconds("83.5"), Ok(83.5));
+        assert_eq!(seconds("01:23.5"), Ok(83.5));
+        assert_eq!(seconds("00:01:23.5"), Ok(83.5));
+        assert_eq!(seconds("1:00:00"), Ok(3600.0));
+        assert_eq!(seconds(" 7 "), Ok(7.0));
+
+        for invalid in [
+            "", "abc", "-1", "1:2:3:4", "1.5:20", "01:-5", "nan", "inf", "1:",
+        ] {
+            assert!(parse_timestamp(invalid).is_err(), "{:?}", invalid);
+        }
+    }
+
+    #[test]
+    fn fps_must_be_positive() {
+        assert_eq!(parse_fps("2.5"), Ok(2.5));
+        assert_eq!(parse_fps(" 30 "), Ok(30.0));
+
+        for invalid in ["0", "-1", "nan", "inf", "fast", ""] {
+            assert!(parse_fps(invalid).is_err(), "{:?}", invalid);
+        }
+    }
+
+    #[test]
+    fn fps_keeps_one_frame_per_interval() {
+        // the due times land exactly on frame boundaries
+        assert_eq!(
+            kept(FrameSampling::Fps(10.0), 30.0, 30),
+            [0, 3, 6, 9, 12, 15, 18, 21, 24, 27]
+        );
+
+        // due times between frames keep the first frame after them
+        assert_eq!(
+            kept(FrameSampling::Fps(7.0), 30.0, 30),
+            [0, 5, 9, 13, 18, 22, 26]
+        );
+
+        // asking for more than the source has keeps every frame
+        assert_eq!(kept(FrameSampling::Fps(60.0), 30.0, 5), [0, 1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn timestamps_are_sorted_and_stop_after_the_last() {
+        let at = |seconds: f64| Duration::from_secs_f64(seconds);
+        let sampling = FrameSampling::Timestamps(vec![at(2.0), at(0.45), at(2.0), at(0.5)]);
+
+        // 0.45 and 0.5 both land on the frame at 0.5
+        assert_eq!(kept(sampling.clone(), 10.0, 100), [5, 20]);
+
+        let mut sampler = FrameSampler::new(sampling);
+        assert_eq!(sampler.decide(0, None, false), SampleDecision::Skip);
+        assert_eq!(
+            sampler.decide(1, Some(at(3.0)), false),
+            SampleDecision::Keep
+        );
+        assert_eq!(
+            sampler.decide(2, Some(at(3.1)), false),
+            SampleDecision::Done
+        );
+    }
+
+    #[test]
+    fn frame_numbers_are_sorted_and_deduplicated() {
+        let sampling = FrameSampling::FrameNumbers(vec![30, 10, 20, 10]);
+        assert_eq!(kept(sampling.clone(), 30.0, 100), [10, 20, 30]);
+
+        let mut sampler = FrameSampler::new(sampling);
+        for index in 0..=30 {
+            sampler.decide(index, None, false);
+        }
+        assert_eq!(sampler.decide(31, None, false), SampleDecision::Done);
+
+        sampler.reset();
+        assert_eq!(sampler.decide(10, None, false), SampleDecision::Keep);
+    }
+
+    #[test]
+    fn every_nth_and_keyframes() {
+        assert_eq!(kept(FrameSampling::EveryNth(4), 30.0, 10), [0, 4, 8]);
+        assert_eq!(kept(FrameSampling::EveryNth(1), 30.0, 3), [0, 1, 2]);
+
+        let mut sampler = FrameSampler::new(FrameSampling::KeyframesOnly);
+        assert_eq!(sampler.decide(0, None, true), SampleDecision::Keep);
+        assert_eq!(sampler.decide(1, None, false), SampleDecision::Skip);
+    }
+}
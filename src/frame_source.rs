@@ -0,0 +1,455 @@
+use crate::frame::{Frame, PixelFormat};
+use crate::frame_sampling::{FrameSampler, FrameSampling, SampleDecision};
+use crate::video_pipeline::{DeliveryMode, FrameInfo, VideoError, VideoPipeline};
+use image::ColorType;
+use std::cmp::Ordering;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// File extensions read as still images rather than video
+const IMAGE_EXTENSIONS: &[&str] = &["png", "jpg", "jpeg", "bmp", "tif", "tiff", "webp", "tga"];
+
+/// Nominal rate assigned to image sequences so time based sampling and video output work
+const IMAGE_SEQUENCE_FPS: (i32, i32) = (30, 1);
+
+/// A producer of frames for the `FramePipeline`, either decoded video or still images
+pub trait FrameSource {
+    /// Prepare the source to deliver frames from the beginning
+    fn start(&mut self) -> Result<(), VideoError>;
+
+    /// Release the resources held while delivering frames
+    fn stop(&mut self) -> Result<(), VideoError>;
+
+    /// The next selected frame, or `VideoError::Eos` once the source is exhausted
+    fn next_frame(&mut self) -> Result<(FrameInfo, Frame), VideoError>;
+
+    /// Select which frames are delivered, takes effect on the next `start`
+    fn set_sampling(&mut self, sampling: FrameSampling);
+
+    /// Frame rate as numerator and denominator, 0/1 when unknown
+    fn framerate(&self) -> (i32, i32);
+}
+
+impl FrameSource for VideoPipeline {
+    fn start(&mut self) -> Result<(), VideoError> {
+        VideoPipeline::start(self)
+    }
+
+    fn stop(&mut self) -> Result<(), VideoError> {
+        VideoPipeline::stop(self)
+    }
+
+    fn next_frame(&mut self) -> Result<(FrameInfo, Frame), VideoError> {
+        VideoPipeline::next_frame(self)
+    }
+
+    fn set_sampling(&mut self, sampling: FrameSampling) {
+        VideoPipeline::set_sampling(self, sampling)
+    }
+
+    fn framerate(&self) -> (i32, i32) {
+        let framerate = self.get_framerate();
+        (framerate.numer(), framerate.denom())
+    }
+}
+
+/// Iterate over the remaining frames of a source, pulling lazily so a slow consumer
+/// throttles the source. The iterator ends at the end of the stream and yields any
+/// other error once.
+pub fn frames<'a>(
+    source: &'a mut dyn FrameSource,
+) -> impl Iterator<Item = Result<(FrameInfo, Frame), VideoError>> + 'a {
+    let mut done = false;
+    std::iter::from_fn(move || {
+        if done {
+            return None;
+        }
+
+        match source.next_frame() {
+            Ok(frame) => Some(Ok(frame)),
+            Err(VideoError::Eos) => {
+                done = true;
+                None
+            }
+            Err(err) => {
+                done = true;
+                Some(Err(err))
+            }
+        }
+    })
+}
+
+/// Open the source matching an `--input` value.
+///
+/// Image files, directories and glob patterns (`frames/img_*.png`) are read as still
+/// images, everything else goes through a `VideoPipeline`.
+pub fn open(input: &str, live: bool) -> Result<Box<dyn FrameSource>, VideoError> {
+    if !input.contains("://") {
+        let path = Path::new(input);
+        let file_name = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .unwrap_or("");
+
+        if file_name.contains('*') || file_name.contains('?') {
+            return Ok(Box::new(ImageSource::glob(input)?));
+        }
+
+        if path.is_dir() {
+            return Ok(Box::new(ImageSource::directory(input)?));
+        }
+
+        if is_image_path(path) {
+            return Ok(Box::new(ImageSource::single(input)?));
+        }
+    }
+
+    let mut pipeline = VideoPipeline::new(input)?;
+
+    // live sources already deliver live, other inputs only when asked to
+    if live {
+        pipeline.set_delivery_mode(DeliveryMode::Live);
+    }
+
+    Ok(Box::new(pipeline))
+}
+
+fn is_image_path(path: &Path) -> bool {
+    path.extension()
+        .and_then(|ext| ext.to_str())
+        .map(|ext| IMAGE_EXTENSIONS.contains(&ext.to_ascii_lowercase().as_str()))
+        .unwrap_or(false)
+}
+
+/// A single still image or an ordered sequence of them, each delivered as one frame
+pub struct ImageSource {
+    /// Images in delivery order
+    paths: Vec<PathBuf>,
+    /// Index of the next image to offer to the sampler
+    position: usize,
+    /// Decides which images are delivered
+    sampler: FrameSampler,
+}
+
+impl ImageSource {
+    fn from_paths(paths: Vec<PathBuf>, input: &str) -> Result<Self, VideoError> {
+        if paths.is_empty() {
+            return Err(VideoError::Image(format!("No images found for {}", input)));
+        }
+
+        Ok(Self {
+            paths,
+            position: 0,
+            sampler: FrameSampler::new(FrameSampling::All),
+        })
+    }
+
+    /// A source delivering a single image
+    pub fn single(path: &str) -> Result<Self, VideoError> {
+        if !Path::new(path).is_file() {
+            return Err(VideoError::Image(format!("{} is not a file", path)));
+        }
+
+        Self::from_paths(vec![PathBuf::from(path)], path)
+    }
+
+    /// A source delivering every image in a directory, in natural file name order
+    pub fn directory(dir: &str) -> Result<Self, VideoError> {
+        let mut paths = Vec::new();
+        let entries =
+            std::fs::read_dir(dir).map_err(|e| VideoError::Image(format!("{}: {}", dir, e)))?;
+
+        for entry in entries {
+            let path = entry
+                .map_err(|e| VideoError::Image(format!("{}: {}", dir, e)))?
+                .path();
+            if path.is_file() && is_image_path(&path) {
+                paths.push(path);
+            }
+        }
+
+        paths.sort_by(|a, b| natural_cmp(&a.to_string_lossy(), &b.to_string_lossy()));
+        Self::from_paths(paths, dir)
+    }
+
+    /// A source delivering the images matching a `*`/`?` pattern in the file name,
+    /// e.g. `frames/img_*.png`, in natural file name order. Like a directory, only
+    /// files with an image extension are picked up.
+    pub fn glob(pattern: &str) -> Result<Self, VideoError> {
+        let path = Path::new(pattern);
+        let name_pattern = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .unwrap_or("");
+        let dir = match path.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => parent.to_path_buf(),
+            _ => PathBuf::from("."),
+        };
+
+        let mut paths = Vec::new();
+        let entries = std::fs::read_dir(&dir)
+            .map_err(|e| VideoError::Image(format!("{}: {}", dir.display(), e)))?;
+
+        for entry in entries {
+            let path = entry
+                .map_err(|e| VideoError::Image(format!("{}: {}", dir.display(), e)))?
+                .path();
+            let matches = path
+                .file_name()
+                .and_then(|name| name.to_str())
+                .map_or(false, |name| wildcard_match(name_pattern, name));
+            if matches && path.is_file() && is_image_path(&path) {
+                paths.push(path);
+            }
+        }
+
+        paths.sort_by(|a, b| natural_cmp(&a.to_string_lossy(), &b.to_string_lossy()));
+        Self::from_paths(paths, pattern)
+    }
+
+    // decode an image in the closest pixel format, keeping its bit depth and alpha
+    fn load(&self, index: usize) -> Result<(FrameInfo, Frame), VideoError> {
+        let path = &self.paths[index];
+        let image = image::open(path)
+            .map_err(|e| VideoError::Image(format!("{}: {}", path.display(), e)))?;
+
+        let source_format = format!("{:?}", image.color());
+        let (data, format) = match image.color() {
+            ColorType::L8 | ColorType::La8 => (image.to_luma8().into_raw(), PixelFormat::Gray8),
+            ColorType::L16 | ColorType::La16 => {
+                let data = image
+                    .to_luma16()
+                    .into_raw()
+                    .into_iter()
+                    .flat_map(u16::to_le_bytes)
+                    .collect();
+                (data, PixelFormat::Gray16)
+            }
+            ColorType::Rgba8 | ColorType::Rgba16 => {
+                (image.to_rgba8().into_raw(), PixelFormat::Rgba)
+            }
+            ColorType::Rgb32F | ColorType::Rgba32F => {
+                let (samples, format) = if image.color().has_alpha() {
+                    (image.to_rgba32f().into_raw(), PixelFormat::RgbaF32)
+                } else {
+                    (image.to_rgb32f().into_raw(), PixelFormat::RgbF32)
+                };
+                let data = samples.into_iter().flat_map(f32::to_ne_bytes).collect();
+                (data, format)
+            }
+            _ => (image.to_rgb8().into_raw(), PixelFormat::Rgb),
+        };
+
+        let (numer, denom) = IMAGE_SEQUENCE_FPS;
+        let frame_duration = Duration::from_secs_f64(denom as f64 / numer as f64);
+
+        let info = FrameInfo {
+            frame_number: index as u64,
+            pts: Some(frame_duration * index as u32),
+            duration: Some(frame_duration),
+            stream_id: Some(path.display().to_string()),
+            source_format: Some(source_format),
+            pixel_format: format.to_string(),
+            warnings: Vec::new(),
+        };
+
+        let frame = Frame::new(data, image.width() as i32, image.height() as i32, format)
+            .map_err(|e| VideoError::Image(format!("{}: {}", path.display(), e)))?;
+
+        Ok((info, frame))
+    }
+}
+
+impl FrameSource for ImageSource {
+    fn start(&mut self) -> Result<(), VideoError> {
+        self.position = 0;
+        self.sampler.reset();
+        Ok(())
+    }
+
+    fn stop(&mut self) -> Result<(), VideoError> {
+        self.position = self.paths.len();
+        Ok(())
+    }
+
+    fn next_frame(&mut self) -> Result<(FrameInfo, Frame), VideoError> {
+        let (numer, denom) = IMAGE_SEQUENCE_FPS;
+
+        // only decode the images the sampler selects
+        while self.position < self.paths.len() {
+            let index = self.position;
+            self.position += 1;
+
+            let pts = Duration::from_secs_f64(index as f64 * denom as f64 / numer as f64);
+            match self.sampler.decide(index as u64, Some(pts), true) {
+                SampleDecision::Keep => return self.load(index),
+                SampleDecision::Skip => continue,
+                SampleDecision::Done => break,
+            }
+        }
+
+        self.position = self.paths.len();
+        Err(VideoError::Eos)
+    }
+
+    fn set_sampling(&mut self, sampling: FrameSampling) {
+        self.sampler = FrameSampler::new(sampling);
+    }
+
+    fn framerate(&self) -> (i32, i32) {
+        IMAGE_SEQUENCE_FPS
+    }
+}
+
+// match a file name against a pattern where `*` is any run of characters and `?` one character
+fn wildcard_match(pattern: &str, name: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let name: Vec<char> = name.chars().collect();
+
+    let (mut p, mut n) = (0, 0);
+    let mut backtrack: Option<(usize, usize)> = None;
+
+    while n < name.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == name[n]) {
+            p += 1;
+            n += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            backtrack = Some((p, n));
+            p += 1;
+        } else if let Some((star_p, star_n)) = backtrack {
+            // let the last star swallow one more character
+            p = star_p + 1;
+            n = star_n + 1;
+            backtrack = Some((star_p, star_n + 1));
+        } else {
+            return false;
+        }
+    }
+
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+// compare names so that embedded numbers sort by value, img_2 before img_10
+fn natural_cmp(a: &str, b: &str) -> Ordering {
+    let mut a = a.chars().peekable();
+    let mut b = b.chars().peekable();
+
+    loop {
+        match (a.peek().copied(), b.peek().copied()) {
+            (None, None) => return Ordering::Equal,
+            (None, Some(_)) => return Ordering::Less,
+            (Some(_), None) => return Ordering::Greater,
+            (Some(ca), Some(cb)) if ca.is_ascii_digit() && cb.is_ascii_digit() => {
+                let mut num_a = String::new();
+                while let Some(c) = a.peek().copied().filter(|c| c.is_ascii_digit()) {
+                    num_a.push(c);
+                    a.next();
+                }
+                let mut num_b = String::new();
+                while let Some(c) = b.peek().copied().filter(|c| c.is_ascii_digit()) {
+                    num_b.push(c);
+                    b.next();
+                }
+
+                // compare by magnitude without overflowing on long digit runs
+                let trimmed_a = num_a.trim_start_matches('0');
+                let trimmed_b = num_b.trim_start_matches('0');
+                let ordering = trimmed_a
+                    .len()
+                    .cmp(&trimmed_b.len())
+                    .then_with(|| trimmed_a.cmp(trimmed_b));
+                if ordering != Ordering::Equal {
+                    return ordering;
+                }
+            }
+            (Some(ca), Some(cb)) => {
+                if ca != cb {
+                    return ca.cmp(&cb);
+                }
+                a.next();
+                b.next();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // a fresh directory in the temporary directory holding empty files with these names
+    fn directory_with(name: &str, files: &[&str]) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("anuvis_frame_source_{}", name));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        for file in files {
+            std::fs::write(dir.join(file), b"").unwrap();
+        }
+        dir
+    }
+
+    fn file_names(source: &ImageSource) -> Vec<String> {
+        source
+            .paths
+            .iter()
+            .map(|path| path.file_name().unwrap().to_string_lossy().into_owned())
+            .collect()
+    }
+
+    #[test]
+    fn wildcards() {
+        assert!(wildcard_match("img_*.png", "img_001.png"));
+        assert!(wildcard_match("img_*.png", "img_.png"));
+        assert!(!wildcard_match("img_*.png", "img_001.jpg"));
+        assert!(wildcard_match("img?.png", "img7.png"));
+        assert!(!wildcard_match("img?.png", "img10.png"));
+        assert!(wildcard_match("frame*", "frame"));
+        assert!(wildcard_match("frame*", "frame_0001.tif"));
+        assert!(wildcard_match("*", "anything"));
+        assert!(wildcard_match("a*b*c", "aXXbYYbc"));
+        assert!(!wildcard_match("a*b*c", "aXXbYY"));
+    }
+
+    #[test]
+    fn numbers_sort_by_value() {
+        assert_eq!(natural_cmp("img2", "img10"), Ordering::Less);
+        assert_eq!(natural_cmp("img10", "img9"), Ordering::Greater);
+        assert_eq!(natural_cmp("img007", "img7"), Ordering::Equal);
+        assert_eq!(natural_cmp("img", "img1"), Ordering::Less);
+        assert_eq!(natural_cmp("a10b2", "a10b10"), Ordering::Less);
+        assert_eq!(
+            natural_cmp("x99999999999999999999999", "x100000000000000000000000"),
+            Ordering::Less
+        );
+    }
+
+    #[test]
+    fn globs_only_pick_up_images() {
+        let dir = directory_with(
+            "glob",
+            &[
+                "img10.png",
+                "img2.png",
+                "img1.PNG",
+                "notes.txt",
+                "meta.json",
+            ],
+        );
+
+        let source = ImageSource::glob(dir.join("*").to_str().unwrap()).unwrap();
+        assert_eq!(file_names(&source), ["img1.PNG", "img2.png", "img10.png"]);
+
+        let source = ImageSource::glob(dir.join("img?.png").to_str().unwrap()).unwrap();
+        assert_eq!(file_names(&source), ["img2.png"]);
+
+        assert!(ImageSource::glob(dir.join("*.txt").to_str().unwrap()).is_err());
+    }
+
+    #[test]
+    fn directories_only_pick_up_images() {
+        let dir = directory_with("directory", &["b.jpg", "a.tiff", "results.jsonl"]);
+
+        let source = ImageSource::directory(dir.to_str().unwrap()).unwrap();
+        assert_eq!(file_names(&source), ["a.tiff", "b.jpg"]);
+    }
+}
@@ -0,0 +1,10 @@
+pub mod frame;
+pub mod frame_pipeline;
+pub mod frame_sampling;
+pub mod frame_source;
+pub mod pipeline_config;
+pub mod pipeline_steps;
+pub mod profiling;
+pub mod video_pipeline;
+pub mod video_source;
+pub mod video_writer;
@@ -1,21 +1,138 @@
-mod frame_pipeline;
-mod pipeline_steps;
-mod video_pipeline;
 mod host;
 
+use anuvis::frame_sampling::{self, FrameSampling};
+use anuvis::{frame_pipeline, frame_source, pipeline_config, pipeline_steps, video_writer};
 use clap::Parser;
 use host::ux_loop::launch_ux_loop;
+use std::time::Duration;
+
+// count allocations per thread so profiling can attribute them to pipeline steps
+#[cfg(feature = "count-allocations")]
+#[global_allocator]
+static ALLOCATOR: anuvis::profiling::CountingAllocator = anuvis::profiling::CountingAllocator;
 
 // handle command line arguments
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
 struct Args {
-    #[arg(short, long, help = "Input file to process", required_unless_present = "ui")]
+    #[arg(
+        short,
+        long,
+        help = "Input to process: a video file, an image, a directory or glob of images, v4l2:///dev/videoN, rtsp://, http(s):// or test://PATTERN",
+        required_unless_present = "ui"
+    )]
     input: Option<String>,
 
-    #[arg(short, long, help = "Output directory", required_unless_present = "ui")]
+    #[arg(
+        short,
+        long,
+        help = "Output directory",
+        required_unless_present = "ui"
+    )]
     output: Option<String>,
 
+    #[arg(
+        short,
+        long,
+        help = "Pipeline description file (.toml or .json), defaults to Canny edge detection"
+    )]
+    pipeline: Option<String>,
+
+    #[arg(
+        long,
+        group = "sampling",
+        value_parser = clap::value_parser!(u64).range(1..),
+        help = "Process every Nth frame (default when no sampling option is given: 100)"
+    )]
+    every: Option<u64>,
+
+    #[arg(
+        long,
+        group = "sampling",
+        value_parser = frame_sampling::parse_fps,
+        help = "Process frames at this rate of video time, e.g. 2.5"
+    )]
+    fps: Option<f64>,
+
+    #[arg(
+        long,
+        group = "sampling",
+        value_delimiter = ',',
+        value_parser = frame_sampling::parse_timestamp,
+        help = "Process the frames at these timestamps, e.g. 00:01:23.5,00:02:00"
+    )]
+    at: Vec<Duration>,
+
+    #[arg(
+        long,
+        group = "sampling",
+        value_delimiter = ',',
+        help = "Process these zero based frame numbers, e.g. 10,20,300"
+    )]
+    frames: Vec<u64>,
+
+    #[arg(
+        long,
+        group = "sampling",
+        default_value_t = false,
+        help = "Process only keyframes"
+    )]
+    keyframes_only: bool,
+
+    #[arg(
+        long,
+        help = "Encode processed frames into this video file (.mkv, .mp4, .mov, .webm, .avi)"
+    )]
+    video_out: Option<String>,
+
+    #[arg(
+        long,
+        default_value_t = false,
+        requires = "video_out",
+        help = "Encode the original and processed frames side by side"
+    )]
+    side_by_side: bool,
+
+    #[arg(long, default_value_t = false, help = "Do not write per frame PNG images")]
+    no_images: bool,
+
+    #[arg(
+        long,
+        default_value_t = false,
+        help = "Save the frame after every pipeline step as PNG"
+    )]
+    debug: bool,
+
+    #[arg(
+        short,
+        long,
+        default_value_t = 1,
+        value_parser = clap::value_parser!(u32).range(1..),
+        help = "Number of frames processed concurrently"
+    )]
+    jobs: u32,
+
+    #[arg(
+        long,
+        default_value_t = false,
+        help = "Deliver only the latest frame in real time, dropping frames when processing falls behind"
+    )]
+    live: bool,
+
+    #[arg(
+        long,
+        default_value_t = false,
+        help = "Measure the time of every step, and its allocations when built with the count-allocations feature, print a summary and save it to profile.json"
+    )]
+    profile: bool,
+
+    #[arg(
+        long,
+        default_value_t = false,
+        help = "Save the step timings to trace.json for chrome://tracing or Perfetto, implies --profile"
+    )]
+    trace: bool,
+
     #[arg(long, default_value_t = false, help = "Launch the application UI")]
     ui: bool,
 
@@ -27,6 +144,25 @@ struct Args {
     dev: bool,
 }
 
+impl Args {
+    // resolve the mutually exclusive sampling options into a single mode
+    fn sampling(&self) -> FrameSampling {
+        if let Some(n) = self.every {
+            FrameSampling::EveryNth(n)
+        } else if let Some(fps) = self.fps {
+            FrameSampling::Fps(fps)
+        } else if !self.at.is_empty() {
+            FrameSampling::Timestamps(self.at.clone())
+        } else if !self.frames.is_empty() {
+            FrameSampling::FrameNumbers(self.frames.clone())
+        } else if self.keyframes_only {
+            FrameSampling::KeyframesOnly
+        } else {
+            FrameSampling::EveryNth(100)
+        }
+    }
+}
+
 #[tokio::main]
 async fn main() {
     // parse command line arguments
@@ -40,39 +176,77 @@ async fn main() {
         let input = args.input.as_ref().unwrap();
         let output = args.output.as_ref().unwrap();
 
-        // create video pipeline
-        let pipeline = video_pipeline::VideoPipeline::new(&input).unwrap();
+        // create the frame source, offline analysis gets every frame while live mode
+        // keeps up with the clock instead
+        let mut source = frame_source::open(&input, args.live).unwrap();
 
         // create frame pipeline
         let mut frame_pipeline = frame_pipeline::FramePipeline::new(&output).unwrap();
 
-        // add canny edge detection step
-        let edge_detection =
-            pipeline_steps::canny_edge_detection::CannyEdgeDetection::new(&output)
+        match args.pipeline.as_ref() {
+            Some(path) => {
+                // build the configured steps
+                let config = pipeline_config::PipelineConfig::load(path).unwrap();
+                pipeline_config::StepRegistry::with_builtin_steps()
+                    .populate(&config, &mut frame_pipeline, &output)
+                    .unwrap();
+            }
+            None => {
+                // add canny edge detection step
+                let edge_detection =
+                    pipeline_steps::canny_edge_detection::CannyEdgeDetection::new(&output)
+                    .unwrap();
+
+                frame_pipeline.add_step(edge_detection);
+            }
+        }
+
+        frame_pipeline.set_save_images(!args.no_images);
+        frame_pipeline.set_debug(args.debug);
+        frame_pipeline.set_profiling(args.profile);
+        frame_pipeline.set_trace(args.trace);
+
+        // encode processed frames back into a video at the source frame rate
+        if let Some(video_out) = args.video_out.as_ref() {
+            let layout = if args.side_by_side {
+                video_writer::VideoLayout::SideBySide
+            } else {
+                video_writer::VideoLayout::Processed
+            };
+
+            let (numer, denom) = source.framerate();
+            let writer = video_writer::VideoWriter::new(
+                video_out,
+                gstreamer::Fraction::new(numer, denom),
+                layout,
+            )
             .unwrap();
+            frame_pipeline.set_video_writer(writer);
+        }
 
-        frame_pipeline.add_step(edge_detection);
+        // only the sampled frames are delivered by the source
+        source.set_sampling(args.sampling());
 
-        // start pipeline
-        pipeline.start().unwrap();
+        // start source
+        source.start().unwrap();
 
         // process frames
-        let mut frame_count = 0;
-        while let Some(mut frame) = pipeline.next_frame() {
-            if frame_count % 100 == 0 {
-                println!("Frame: {}, with channels: {}", frame_count, frame.channels);
+        frame_pipeline.set_jobs(args.jobs as usize);
+        let frames = frame_source::frames(source.as_mut()).inspect(|item| {
+            if let Ok((info, frame)) = item {
+                for warning in &info.warnings {
+                    eprintln!("Warning: {}", warning);
+                }
+                println!("Frame: {}, with channels: {}", info, frame.channels());
                 frame.print_pixel(10, 10);
-
-                // process frame
-                frame_pipeline
-                    .process_frame(&mut frame, frame_count)
-                    .unwrap();
             }
+        });
+        frame_pipeline.run(frames).unwrap();
 
-            frame_count += 1;
-        }
+        // finalize outputs
+        frame_pipeline.finish().unwrap();
 
-        //stop pipeline
-        pipeline.stop().unwrap();
+        //stop source
+        source.stop().unwrap();
     }
 }
@@ -0,0 +1,304 @@
+use crate::frame_pipeline::{FramePipeline, PipelineStep};
+use crate::pipeline_steps::canny_edge_detection::{CannyConfig, CannyEdgeDetection};
+use crate::pipeline_steps::connected_components::{
+    ConnectedComponents, ConnectedComponentsConfig,
+};
+use crate::pipeline_steps::contours::{ContourConfig, ContourExtraction};
+use crate::pipeline_steps::convolution::{Convolution, ConvolutionConfig};
+use crate::pipeline_steps::double_thresholding::{DoubleThreshold, DoubleThresholdConfig};
+use crate::pipeline_steps::eight_conn_edge_tracker::{Hysteresis, HysteresisConfig};
+use crate::pipeline_steps::gaussian_blur::{GaussianBlur, GaussianBlurConfig};
+use crate::pipeline_steps::gradient_calculation::{GradientCalculation, GradientConfig};
+use crate::pipeline_steps::hough::{
+    HoughCircles, HoughCirclesConfig, HoughLines, HoughLinesConfig,
+};
+use crate::pipeline_steps::morphology::{Morphology, MorphologyConfig};
+use crate::pipeline_steps::non_max_suppression::{NonMaxSuppression, NonMaxSuppressionConfig};
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::io;
+use std::path::Path;
+
+/// A single step entry in a pipeline description file.
+/// The `type` key selects the registered step, every other key is a parameter.
+#[derive(Debug, Deserialize)]
+pub struct StepConfig {
+    #[serde(rename = "type")]
+    pub kind: String,
+    #[serde(flatten)]
+    pub params: serde_json::Map<String, serde_json::Value>,
+}
+
+/// An ordered list of steps loaded from a TOML or JSON pipeline description
+///
+/// ```toml
+/// [[steps]]
+/// type = "gaussian_blur"
+/// sigma = 1.4
+///
+/// [[steps]]
+/// type = "canny"
+/// low = 20
+/// high = 60
+/// border = "reflect"
+/// ```
+#[derive(Debug, Deserialize)]
+pub struct PipelineConfig {
+    pub steps: Vec<StepConfig>,
+}
+
+impl PipelineConfig {
+    /// Load a pipeline description, the format is picked from the file extension
+    pub fn load(path: &str) -> io::Result<Self> {
+        let contents = std::fs::read_to_string(path)?;
+        let extension = Path::new(path)
+            .extension()
+            .and_then(|ext| ext.to_str())
+            .unwrap_or("")
+            .to_ascii_lowercase();
+
+        match extension.as_str() {
+            "toml" => toml::from_str(&contents).map_err(|e| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("Invalid pipeline file {}: {}", path, e),
+                )
+            }),
+            "json" => serde_json::from_str(&contents).map_err(|e| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("Invalid pipeline file {}: {}", path, e),
+                )
+            }),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "Unsupported pipeline file extension for {}, expected .toml or .json",
+                    path
+                ),
+            )),
+        }
+    }
+}
+
+/// Constructor for a pipeline step from its parameters and the output directory
+pub type StepFactory = fn(&serde_json::Value, &str) -> io::Result<Box<dyn PipelineStep>>;
+
+/// Maps step type names used in pipeline description files to their constructors
+pub struct StepRegistry {
+    factories: HashMap<String, StepFactory>,
+}
+
+impl StepRegistry {
+    pub fn new() -> Self {
+        Self {
+            factories: HashMap::new(),
+        }
+    }
+
+    /// Create a registry containing every step shipped with anuvis
+    pub fn with_builtin_steps() -> Self {
+        let mut registry = Self::new();
+
+        registry.register("gaussian_blur", |params, output_dir| {
+            let config: GaussianBlurConfig = parse_params("gaussian_blur", params)?;
+            Ok(Box::new(GaussianBlur::with_border(
+                output_dir,
+                config.sigma,
+                config.border,
+            )?))
+        });
+
+        registry.register("canny", |params, output_dir| {
+            let config: CannyConfig = parse_params("canny", params)?;
+            Ok(Box::new(CannyEdgeDetection::from_config(config, output_dir)?))
+        });
+
+        // the stages of canny as separate steps, passing data through the frame context
+        registry.register("gradient", |params, _output_dir| {
+            let config: GradientConfig = parse_params("gradient", params)?;
+            Ok(Box::new(GradientCalculation::from_config(config)))
+        });
+
+        registry.register("non_max_suppression", |params, _output_dir| {
+            let config: NonMaxSuppressionConfig = parse_params("non_max_suppression", params)?;
+            Ok(Box::new(NonMaxSuppression::from_config(config)))
+        });
+
+        registry.register("double_threshold", |params, _output_dir| {
+            let config: DoubleThresholdConfig = parse_params("double_threshold", params)?;
+            Ok(Box::new(DoubleThreshold::from_config(config)?))
+        });
+
+        registry.register("hysteresis", |params, _output_dir| {
+            let config: HysteresisConfig = parse_params("hysteresis", params)?;
+            Ok(Box::new(Hysteresis::from_config(config)))
+        });
+
+        registry.register("convolution", |params, _output_dir| {
+            let config: ConvolutionConfig = parse_params("convolution", params)?;
+            Ok(Box::new(Convolution::from_config(config)?))
+        });
+
+        registry.register("contours", |params, output_dir| {
+            let config: ContourConfig = parse_params("contours", params)?;
+            Ok(Box::new(ContourExtraction::from_config(config, output_dir)?))
+        });
+
+        registry.register("hough_lines", |params, _output_dir| {
+            let config: HoughLinesConfig = parse_params("hough_lines", params)?;
+            Ok(Box::new(HoughLines::from_config(config)?))
+        });
+
+        registry.register("hough_circles", |params, _output_dir| {
+            let config: HoughCirclesConfig = parse_params("hough_circles", params)?;
+            Ok(Box::new(HoughCircles::from_config(config)?))
+        });
+
+        registry.register("morphology", |params, _output_dir| {
+            let config: MorphologyConfig = parse_params("morphology", params)?;
+            Ok(Box::new(Morphology::from_config(config)?))
+        });
+
+        registry.register("connected_components", |params, _output_dir| {
+            let config: ConnectedComponentsConfig =
+                parse_params("connected_components", params)?;
+            Ok(Box::new(ConnectedComponents::from_config(config)?))
+        });
+
+        registry
+    }
+
+    pub fn register(&mut self, name: &str, factory: StepFactory) {
+        self.factories.insert(name.to_string(), factory);
+    }
+
+    /// Build a single step from its description
+    pub fn build(&self, config: &StepConfig, output_dir: &str) -> io::Result<Box<dyn PipelineStep>> {
+        let factory = self.factories.get(&config.kind).ok_or_else(|| {
+            let mut known: Vec<&str> = self.factories.keys().map(|k| k.as_str()).collect();
+            known.sort();
+
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "Unknown pipeline step '{}', expected one of: {}",
+                    config.kind,
+                    known.join(", ")
+                ),
+            )
+        })?;
+
+        factory(&serde_json::Value::Object(config.params.clone()), output_dir)
+    }
+
+    /// Build every step of a pipeline description and append them to the frame pipeline in order
+    pub fn populate(
+        &self,
+        config: &PipelineConfig,
+        frame_pipeline: &mut FramePipeline,
+        output_dir: &str,
+    ) -> io::Result<()> {
+        for step in &config.steps {
+            frame_pipeline.add_boxed_step(self.build(step, output_dir)?);
+        }
+
+        Ok(())
+    }
+}
+
+/// Deserialize the parameters of a step, reporting the step name on failure
+pub fn parse_params<T: DeserializeOwned>(step: &str, params: &serde_json::Value) -> io::Result<T> {
+    T::deserialize(params).map_err(|e| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Invalid parameters for step '{}': {}", step, e),
+        )
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // write a pipeline description into the temporary directory and load it
+    fn load(name: &str, contents: &str) -> io::Result<PipelineConfig> {
+        let path = std::env::temp_dir().join(format!("anuvis_pipeline_config_{}", name));
+        std::fs::write(&path, contents)?;
+        PipelineConfig::load(path.to_str().unwrap())
+    }
+
+    #[test]
+    fn toml_and_json_describe_the_same_pipeline() {
+        let toml = load(
+            "same.toml",
+            r#"
+            [[steps]]
+            type = "gaussian_blur"
+            sigma = 1.4
+
+            [[steps]]
+            type = "canny"
+            low = 20
+            high = 60
+            border = "reflect"
+            "#,
+        )
+        .unwrap();
+        let json = load(
+            "same.json",
+            r#"{"steps": [
+                {"type": "gaussian_blur", "sigma": 1.4},
+                {"type": "canny", "low": 20, "high": 60, "border": "reflect"}
+            ]}"#,
+        )
+        .unwrap();
+
+        assert_eq!(toml.steps.len(), 2);
+        let registry = StepRegistry::with_builtin_steps();
+        for (toml, json) in toml.steps.iter().zip(&json.steps) {
+            assert_eq!(toml.kind, json.kind);
+            assert_eq!(toml.params, json.params);
+            assert_eq!(
+                registry.build(toml, "").unwrap().name(),
+                registry.build(json, "").unwrap().name()
+            );
+        }
+    }
+
+    #[test]
+    fn unknown_step_types_are_rejected() {
+        let config = load("unknown.toml", "[[steps]]\ntype = \"sharpen\"\n").unwrap();
+        let error = StepRegistry::with_builtin_steps()
+            .build(&config.steps[0], "")
+            .err()
+            .unwrap();
+
+        assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+        assert!(error.to_string().contains("'sharpen'"));
+        assert!(error.to_string().contains("gaussian_blur"));
+    }
+
+    #[test]
+    fn misspelled_parameters_are_rejected() {
+        let config = load(
+            "misspelled.toml",
+            "[[steps]]\ntype = \"gaussian_blur\"\nsigma = 1.4\nborder_mode = \"wrap\"\n",
+        )
+        .unwrap();
+        let error = StepRegistry::with_builtin_steps()
+            .build(&config.steps[0], "")
+            .err()
+            .unwrap();
+
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        assert!(error.to_string().contains("border_mode"));
+    }
+
+    #[test]
+    fn unsupported_extensions_are_rejected() {
+        let error = load("pipeline.yaml", "steps: []").err().unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+    }
+}
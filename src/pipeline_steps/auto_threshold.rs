@@ -0,0 +1,307 @@
+use crate::frame::Image;
+use serde::Deserialize;
+use std::io;
+use std::sync::Mutex;
+
+use super::gradient_calculation::PixelGradient;
+
+/// Spread around the median used unless configured otherwise
+pub const DEFAULT_SIGMA: f32 = 0.33;
+/// Low threshold of the Otsu method as a fraction of the high one, unless configured otherwise
+pub const DEFAULT_LOW_RATIO: f32 = 0.5;
+
+#[derive(Debug)]
+pub enum AutoThresholdError {
+    InvalidSigma(f32),
+    InvalidRatio(f32),
+    InvalidSmoothing(f32),
+}
+
+impl std::fmt::Display for AutoThresholdError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AutoThresholdError::InvalidSigma(sigma) => {
+                write!(f, "Invalid median sigma {}, must be in 0.0..=1.0", sigma)
+            }
+            AutoThresholdError::InvalidRatio(ratio) => {
+                write!(f, "Invalid Otsu low ratio {}, must be in 0.0..=1.0", ratio)
+            }
+            AutoThresholdError::InvalidSmoothing(smoothing) => {
+                write!(f, "Invalid smoothing {}, must be in 0.0..1.0", smoothing)
+            }
+        }
+    }
+}
+
+impl std::error::Error for AutoThresholdError {}
+
+impl From<AutoThresholdError> for io::Error {
+    fn from(error: AutoThresholdError) -> Self {
+        io::Error::new(io::ErrorKind::InvalidInput, error.to_string())
+    }
+}
+
+/// How the hysteresis thresholds are derived from the gradient magnitudes of a frame
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ThresholdMethod {
+    /// The Otsu split of the magnitude histogram is the high threshold, the low
+    /// threshold is a fixed ratio of it
+    Otsu,
+    /// The thresholds lie `sigma` below and above the median magnitude
+    Median,
+}
+
+/// Derives Canny thresholds per frame from the histogram of its gradient magnitudes.
+///
+/// Pixels without any gradient are left out of the histogram, so large flat areas
+/// don't pull the thresholds down to zero. With smoothing the thresholds follow an
+/// exponential moving average over the processed frames so they don't flicker.
+pub struct AutoThreshold {
+    method: ThresholdMethod,
+    // spread around the median for the median method
+    sigma: f32,
+    // low threshold as a fraction of the high one for the otsu method
+    low_ratio: f32,
+    // weight of the previous thresholds, 0 uses every frame on its own
+    smoothing: f32,
+    // smoothed thresholds of the frames seen so far
+    previous: Mutex<Option<(f32, f32)>>,
+}
+
+impl AutoThreshold {
+    /// Create an auto threshold with the usual parameters: a sigma of 0.33 around the
+    /// median, a low threshold of half the Otsu threshold and no smoothing
+    pub fn new(method: ThresholdMethod) -> Self {
+        Self {
+            method,
+            sigma: DEFAULT_SIGMA,
+            low_ratio: DEFAULT_LOW_RATIO,
+            smoothing: 0.0,
+            previous: Mutex::new(None),
+        }
+    }
+
+    /// Create an auto threshold from the parameters of a pipeline description file
+    pub fn from_params(
+        method: ThresholdMethod,
+        sigma: f32,
+        low_ratio: f32,
+        smoothing: f32,
+    ) -> Result<Self, AutoThresholdError> {
+        Self::new(method)
+            .with_sigma(sigma)?
+            .with_low_ratio(low_ratio)?
+            .with_smoothing(smoothing)
+    }
+
+    /// Set how far the median method places the thresholds from the median,
+    /// as a fraction of the median
+    pub fn with_sigma(mut self, sigma: f32) -> Result<Self, AutoThresholdError> {
+        if !(0.0..=1.0).contains(&sigma) {
+            return Err(AutoThresholdError::InvalidSigma(sigma));
+        }
+        self.sigma = sigma;
+        Ok(self)
+    }
+
+    /// Set the low threshold of the Otsu method as a fraction of the high threshold
+    pub fn with_low_ratio(mut self, ratio: f32) -> Result<Self, AutoThresholdError> {
+        if !(0.0..=1.0).contains(&ratio) {
+            return Err(AutoThresholdError::InvalidRatio(ratio));
+        }
+        self.low_ratio = ratio;
+        Ok(self)
+    }
+
+    /// Smooth the thresholds over time
+    ///
+    /// # Arguments
+    /// * `smoothing` - Weight of the previous thresholds against those of the current
+    ///   frame, 0.0 disables smoothing and values close to 1.0 react slowly
+    pub fn with_smoothing(mut self, smoothing: f32) -> Result<Self, AutoThresholdError> {
+        if !(0.0..1.0).contains(&smoothing) {
+            return Err(AutoThresholdError::InvalidSmoothing(smoothing));
+        }
+        self.smoothing = smoothing;
+        Ok(self)
+    }
+
+    /// Whether the thresholds depend on the frames before, which needs the frames
+    /// processed one at a time in source order
+    pub fn is_smoothed(&self) -> bool {
+        self.smoothing > 0.0
+    }
+
+    /// The low and high threshold for a frame, `None` when the frame has no gradients.
+    /// Smoothing follows the order the frames are passed in.
+    pub fn thresholds(&self, gradients: &Image<PixelGradient>) -> Option<(i32, i32)> {
+        let histogram = magnitude_histogram(gradients);
+        let (low, high) = match self.method {
+            ThresholdMethod::Otsu => {
+                let high = otsu_threshold(&histogram)? as f32;
+                (high * self.low_ratio, high)
+            }
+            ThresholdMethod::Median => {
+                let median = histogram_median(&histogram)? as f32;
+                (
+                    ((1.0 - self.sigma) * median).max(0.0),
+                    ((1.0 + self.sigma) * median).min(255.0),
+                )
+            }
+        };
+
+        let mut previous = self.previous.lock().unwrap();
+        let (low, high) = match *previous {
+            Some((previous_low, previous_high)) if self.smoothing > 0.0 => (
+                self.smoothing * previous_low + (1.0 - self.smoothing) * low,
+                self.smoothing * previous_high + (1.0 - self.smoothing) * high,
+            ),
+            _ => (low, high),
+        };
+        *previous = Some((low, high));
+
+        Some((low.round() as i32, high.round() as i32))
+    }
+}
+
+/// Count the gradient magnitudes of a frame, clamped to 0..=255 like the suppressed
+/// output. Pixels without gradient are not counted.
+pub fn magnitude_histogram(gradients: &Image<PixelGradient>) -> [u32; 256] {
+    let mut histogram = [0u32; 256];
+    for gradient in gradients.pixels() {
+        let bin = gradient.magnitude.min(255.0) as usize;
+        if bin > 0 {
+            histogram[bin] += 1;
+        }
+    }
+    histogram
+}
+
+/// The Otsu threshold of a histogram, the value splitting it into the two classes
+/// with the largest between class variance. `None` for an empty histogram.
+pub fn otsu_threshold(histogram: &[u32; 256]) -> Option<u8> {
+    let total: u64 = histogram.iter().map(|&count| count as u64).sum();
+    if total == 0 {
+        return None;
+    }
+
+    let weighted_total: f64 = histogram
+        .iter()
+        .enumerate()
+        .map(|(value, &count)| value as f64 * count as f64)
+        .sum();
+
+    let mut best = (0u8, -1.0f64);
+    let mut below = 0u64;
+    let mut weighted_below = 0.0f64;
+    for (value, &count) in histogram.iter().enumerate() {
+        below += count as u64;
+        weighted_below += value as f64 * count as f64;
+        let above = total - below;
+        if below == 0 || above == 0 {
+            continue;
+        }
+
+        let mean_below = weighted_below / below as f64;
+        let mean_above = (weighted_total - weighted_below) / above as f64;
+        let variance = below as f64 * above as f64 * (mean_below - mean_above).powi(2);
+        if variance > best.1 {
+            best = (value as u8, variance);
+        }
+    }
+
+    // a single populated bin has no split, threshold at that value
+    if best.1 < 0.0 {
+        best.0 = histogram.iter().position(|&count| count > 0)? as u8;
+    }
+
+    Some(best.0)
+}
+
+/// The median value of a histogram, `None` for an empty histogram
+pub fn histogram_median(histogram: &[u32; 256]) -> Option<u8> {
+    let total: u64 = histogram.iter().map(|&count| count as u64).sum();
+    if total == 0 {
+        return None;
+    }
+
+    let half = (total + 1) / 2;
+    let mut seen = 0u64;
+    histogram
+        .iter()
+        .position(|&count| {
+            seen += count as u64;
+            seen >= half
+        })
+        .map(|value| value as u8)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // gradients with the given magnitudes along a single row
+    fn gradients(magnitudes: &[f32]) -> Image<PixelGradient> {
+        Image::from_fn(magnitudes.len(), 1, |x, _| {
+            PixelGradient::new(magnitudes[x], 0.0)
+        })
+    }
+
+    #[test]
+    fn otsu_splits_two_clusters() {
+        let mut histogram = [0u32; 256];
+        histogram[40] = 100;
+        histogram[200] = 100;
+
+        let threshold = otsu_threshold(&histogram).unwrap();
+        assert!((40..200).contains(&threshold));
+        assert_eq!(otsu_threshold(&[0; 256]), None);
+
+        // a single value has no split and is its own threshold
+        let mut single = [0u32; 256];
+        single[17] = 5;
+        assert_eq!(otsu_threshold(&single), Some(17));
+    }
+
+    #[test]
+    fn median_of_histogram() {
+        let mut histogram = [0u32; 256];
+        histogram[10] = 2;
+        histogram[50] = 1;
+        histogram[90] = 2;
+        assert_eq!(histogram_median(&histogram), Some(50));
+        assert_eq!(histogram_median(&[0; 256]), None);
+    }
+
+    #[test]
+    fn flat_pixels_are_left_out() {
+        let histogram = magnitude_histogram(&gradients(&[0.0, 0.0, 0.0, 100.0, 300.0]));
+        assert_eq!(histogram[0], 0);
+        assert_eq!(histogram[100], 1);
+        assert_eq!(histogram[255], 1);
+    }
+
+    #[test]
+    fn median_thresholds_spread_around_the_median() {
+        let auto = AutoThreshold::new(ThresholdMethod::Median)
+            .with_sigma(0.5)
+            .unwrap();
+        assert_eq!(auto.thresholds(&gradients(&[100.0; 9])), Some((50, 150)));
+        assert_eq!(auto.thresholds(&gradients(&[0.0; 9])), None);
+    }
+
+    #[test]
+    fn smoothing_follows_the_previous_frames() {
+        let auto = AutoThreshold::new(ThresholdMethod::Median)
+            .with_sigma(0.0)
+            .unwrap()
+            .with_smoothing(0.5)
+            .unwrap();
+        assert!(auto.is_smoothed());
+
+        assert_eq!(auto.thresholds(&gradients(&[100.0; 4])), Some((100, 100)));
+        assert_eq!(auto.thresholds(&gradients(&[200.0; 4])), Some((150, 150)));
+        assert!(!AutoThreshold::new(ThresholdMethod::Otsu).is_smoothed());
+    }
+}
@@ -0,0 +1,150 @@
+use serde::Deserialize;
+
+/// How convolution like steps read pixels outside the frame
+///
+/// In a pipeline description file the mode is given as a string, or as a table for
+/// a constant border:
+///
+/// ```toml
+/// border = "reflect"
+/// border = { constant = 0 }
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BorderMode {
+    /// Every pixel outside the frame has this value, `iiii|abcdefgh|iiii`
+    Constant(u8),
+    /// The edge pixel is repeated, `aaaa|abcdefgh|hhhh`
+    #[default]
+    Replicate,
+    /// The frame is mirrored around the edge pixel, `edcb|abcdefgh|gfed`
+    Reflect,
+    /// The frame repeats from the opposite edge, `efgh|abcdefgh|abcd`
+    Wrap,
+}
+
+impl BorderMode {
+    /// Map a coordinate along an axis of `len` pixels into the frame.
+    /// Returns `None` when the constant value should be used instead.
+    ///
+    /// # Arguments
+    /// * `index` - Coordinate that may lie outside `0..len`
+    /// * `len` - Number of pixels along the axis, must be at least 1
+    #[inline(always)]
+    pub fn resolve(self, index: isize, len: usize) -> Option<usize> {
+        let len = len as isize;
+        if (0..len).contains(&index) {
+            return Some(index as usize);
+        }
+
+        match self {
+            BorderMode::Constant(_) => None,
+            BorderMode::Replicate => Some(index.clamp(0, len - 1) as usize),
+            BorderMode::Reflect => {
+                if len == 1 {
+                    return Some(0);
+                }
+
+                // mirroring without repeating the edge pixel has a period of 2 * (len - 1)
+                let period = 2 * (len - 1);
+                let folded = index.rem_euclid(period);
+                Some(if folded < len {
+                    folded
+                } else {
+                    period - folded
+                } as usize)
+            }
+            BorderMode::Wrap => Some(index.rem_euclid(len) as usize),
+        }
+    }
+
+    /// Value used for pixels outside the frame in constant mode, 0 for every other mode
+    pub fn constant_value(self) -> u8 {
+        match self {
+            BorderMode::Constant(value) => value,
+            _ => 0,
+        }
+    }
+
+    /// Read a pixel of a row major single channel plane, applying the border outside it
+    ///
+    /// # Arguments
+    /// * `data` - Plane of `width * height` values
+    /// * `width` - Width of the plane
+    /// * `height` - Height of the plane
+    /// * `x` - Column, may lie outside the plane
+    /// * `y` - Row, may lie outside the plane
+    /// * `constant` - Value returned outside the plane in constant mode
+    #[inline(always)]
+    pub fn pixel<T: Copy>(
+        self,
+        data: &[T],
+        width: usize,
+        height: usize,
+        x: isize,
+        y: isize,
+        constant: T,
+    ) -> T {
+        match (self.resolve(x, width), self.resolve(y, height)) {
+            (Some(x), Some(y)) => data[y * width + x],
+            _ => constant,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // the pixels read for `range` along a row, in the notation of the mode docs
+    fn row(border: BorderMode, pixels: &str, range: std::ops::Range<isize>) -> String {
+        let pixels: Vec<char> = pixels.chars().collect();
+        range
+            .map(|index| match border.resolve(index, pixels.len()) {
+                Some(index) => pixels[index],
+                None => 'i',
+            })
+            .collect()
+    }
+
+    #[test]
+    fn modes_match_their_docs() {
+        let row = |border| row(border, "abcdefgh", -4..12);
+
+        assert_eq!(row(BorderMode::Constant(0)), "iiiiabcdefghiiii");
+        assert_eq!(row(BorderMode::Replicate), "aaaaabcdefghhhhh");
+        assert_eq!(row(BorderMode::Reflect), "edcbabcdefghgfed");
+        assert_eq!(row(BorderMode::Wrap), "efghabcdefghabcd");
+    }
+
+    #[test]
+    fn indices_far_outside_the_frame() {
+        // reflect does not repeat the edge pixel, so it has a period of 2 * (len - 1)
+        assert_eq!(row(BorderMode::Reflect, "abc", -9..9), "babcbabcbabcbabcba");
+        assert_eq!(row(BorderMode::Wrap, "abc", -7..7), "cabcabcabcabca");
+        assert_eq!(row(BorderMode::Replicate, "abc", -100..-98), "aa");
+        assert_eq!(row(BorderMode::Replicate, "abc", 100..102), "cc");
+        assert_eq!(row(BorderMode::Reflect, "ab", -3..5), "babababa");
+    }
+
+    #[test]
+    fn single_pixel_frames() {
+        for border in [BorderMode::Replicate, BorderMode::Reflect, BorderMode::Wrap] {
+            assert_eq!(row(border, "a", -3..4), "aaaaaaa", "{:?}", border);
+        }
+        assert_eq!(row(BorderMode::Constant(9), "a", -1..2), "iai");
+    }
+
+    #[test]
+    fn pixels_of_a_plane() {
+        // 3x2 plane
+        let plane = [1, 2, 3, 4, 5, 6];
+
+        assert_eq!(BorderMode::Replicate.pixel(&plane, 3, 2, -1, 5, 0), 4);
+        assert_eq!(BorderMode::Reflect.pixel(&plane, 3, 2, 3, -1, 0), 5);
+        assert_eq!(BorderMode::Wrap.pixel(&plane, 3, 2, -1, 2, 0), 3);
+        assert_eq!(BorderMode::Constant(7).pixel(&plane, 3, 2, 1, 2, 7), 7);
+        assert_eq!(BorderMode::Constant(7).constant_value(), 7);
+        assert_eq!(BorderMode::Reflect.constant_value(), 0);
+    }
+}
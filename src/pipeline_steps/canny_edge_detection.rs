@@ -1,53 +1,489 @@
-use crate::frame_pipeline::PipelineStep;
-use crate::video_pipeline::Frame;
-use super::double_thresholding::DoubleThresholder;
-use super::eight_conn_edge_tracker::eight_conn_edge_tracker_hysteris;
-use super::gaussian_blur::GaussianBlur;
-use super::gradient_calculation::SobelOperator;
-use super::non_max_suppression::GradNonMaxSuppression;
+use crate::frame_pipeline::{write_frame_json, FrameContext, PipelineStep};
+use crate::frame::{Frame, Image};
+use super::auto_threshold::{self, AutoThreshold, ThresholdMethod};
+use super::border::BorderMode;
+use super::double_thresholding::{check_thresholds, DoubleThresholder, ThresholdError};
+use super::eight_conn_edge_tracker::{edge_tracker_hysteresis, Connectivity};
+use super::gaussian_blur::{BlurError, GaussianBlur};
+use super::gradient_calculation::{GradientNorm, GradientOperator, PixelGradient, SobelOperator};
+use super::non_max_suppression::{EdgePoint, GradNonMaxSuppression};
 
+use serde::{Deserialize, Serialize};
 use std::io;
 
+/// Parameters for a `canny` step in a pipeline description file
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CannyConfig {
+    #[serde(default = "CannyConfig::default_sigma")]
+    pub sigma: f32,
+    #[serde(default = "CannyConfig::default_low")]
+    pub low: i32,
+    #[serde(default = "CannyConfig::default_high")]
+    pub high: i32,
+    #[serde(default)]
+    pub border: BorderMode,
+    /// Derive the thresholds from every frame instead of using `low` and `high`,
+    /// which remain the fallback for frames without gradients
+    #[serde(default)]
+    pub auto: Option<ThresholdMethod>,
+    #[serde(default = "CannyConfig::default_median_sigma")]
+    pub median_sigma: f32,
+    #[serde(default = "CannyConfig::default_otsu_ratio")]
+    pub otsu_ratio: f32,
+    /// Weight of the previous frame's thresholds in auto mode, 0 disables smoothing.
+    /// Smoothing needs the frames in order and therefore a single job.
+    #[serde(default)]
+    pub smoothing: f32,
+    #[serde(default)]
+    pub operator: GradientOperator,
+    #[serde(default)]
+    pub norm: GradientNorm,
+    /// `4` or `8`
+    #[serde(default)]
+    pub connectivity: Connectivity,
+    /// Output a 0/255 edge mask instead of the gradient magnitude of the edges
+    #[serde(default)]
+    pub binary: bool,
+    /// Write the sub-pixel position of every edge to a JSON file per frame
+    #[serde(default)]
+    pub subpixel: bool,
+}
+
+impl CannyConfig {
+    fn default_sigma() -> f32 {
+        3.0
+    }
+
+    fn default_low() -> i32 {
+        10
+    }
+
+    fn default_high() -> i32 {
+        40
+    }
+
+    fn default_median_sigma() -> f32 {
+        auto_threshold::DEFAULT_SIGMA
+    }
+
+    fn default_otsu_ratio() -> f32 {
+        auto_threshold::DEFAULT_LOW_RATIO
+    }
+}
+
+#[derive(Debug)]
+pub enum CannyError {
+    InvalidSigma(f32),
+    InvalidThresholds(ThresholdError),
+    Blur(BlurError),
+}
+
+impl std::fmt::Display for CannyError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CannyError::InvalidSigma(sigma) => {
+                write!(f, "Invalid sigma {}, must be greater than 0", sigma)
+            }
+            CannyError::InvalidThresholds(error) => write!(f, "{}", error),
+            CannyError::Blur(error) => write!(f, "{}", error),
+        }
+    }
+}
+
+impl std::error::Error for CannyError {}
+
+impl From<BlurError> for CannyError {
+    fn from(error: BlurError) -> Self {
+        CannyError::Blur(error)
+    }
+}
+
+impl From<ThresholdError> for CannyError {
+    fn from(error: ThresholdError) -> Self {
+        CannyError::InvalidThresholds(error)
+    }
+}
+
+impl From<CannyError> for io::Error {
+    fn from(error: CannyError) -> Self {
+        io::Error::new(io::ErrorKind::InvalidInput, error.to_string())
+    }
+}
+
 pub struct CannyEdgeDetection {
     /// Directory to store debug output and intermediate results
     output_dir: String,
     gaussian: GaussianBlur,
+    sobel: SobelOperator,
+    /// How pixels outside the frame are read by the blur, gradient and suppression stages
+    border: BorderMode,
+    /// Magnitudes at or below this value are suppressed
+    low_threshold: i32,
+    /// Magnitudes at or above this value are strong edges
+    high_threshold: i32,
+    /// Per frame thresholds, replacing the fixed ones when set
+    auto_threshold: Option<AutoThreshold>,
+    /// Which neighbours connect weak edges to strong ones
+    connectivity: Connectivity,
+    /// Output 255 for every edge pixel instead of its gradient magnitude
+    binary_mask: bool,
+    /// Write the sub-pixel edge positions of every frame to the output directory
+    subpixel_positions: bool,
 }
 
 impl CannyEdgeDetection {
     pub fn new(output_dir: &str) -> io::Result<Self> {
-        let gaussian = GaussianBlur::new(output_dir, 3.0)?;
+        Ok(Self::builder(output_dir).build()?)
+    }
 
-        Ok(Self {
+    /// Start building a CannyEdgeDetection step, every parameter starts at the
+    /// default of a `canny` pipeline step
+    ///
+    /// # Arguments
+    /// * `output_dir` - The directory to store debug output and intermediate results
+    pub fn builder(output_dir: &str) -> CannyBuilder {
+        CannyBuilder {
             output_dir: output_dir.to_string(),
-            gaussian: gaussian,
+            sigma: CannyConfig::default_sigma(),
+            low: CannyConfig::default_low(),
+            high: CannyConfig::default_high(),
+            operator: GradientOperator::default(),
+            norm: GradientNorm::default(),
+            connectivity: Connectivity::default(),
+            border: BorderMode::default(),
+            auto_threshold: None,
+            binary_mask: false,
+            subpixel_positions: false,
+        }
+    }
+
+    /// Create a CannyEdgeDetection step with explicit blur and threshold parameters
+    ///
+    /// # Arguments
+    /// * `output_dir` - The directory to store debug output and intermediate results
+    /// * `sigma` - The standard deviation of the noise reduction blur
+    /// * `low` - The weak edge threshold
+    /// * `high` - The strong edge threshold
+    /// * `border` - How pixels outside the frame are read
+    pub fn with_params(
+        output_dir: &str,
+        sigma: f32,
+        low: i32,
+        high: i32,
+        border: BorderMode,
+    ) -> io::Result<Self> {
+        Ok(Self::builder(output_dir)
+            .sigma(sigma)
+            .thresholds(low, high)
+            .border(border)
+            .build()?)
+    }
+
+    pub fn from_config(config: CannyConfig, output_dir: &str) -> io::Result<Self> {
+        let mut builder = Self::builder(output_dir)
+            .sigma(config.sigma)
+            .thresholds(config.low, config.high)
+            .operator(config.operator)
+            .norm(config.norm)
+            .connectivity(config.connectivity)
+            .border(config.border)
+            .binary_mask(config.binary)
+            .subpixel_positions(config.subpixel);
+
+        if let Some(method) = config.auto {
+            let auto_threshold = AutoThreshold::from_params(
+                method,
+                config.median_sigma,
+                config.otsu_ratio,
+                config.smoothing,
+            )?;
+            builder = builder.auto_threshold(auto_threshold);
+        }
+
+        Ok(builder.build()?)
+    }
+}
+
+/// Collects the parameters of a `CannyEdgeDetection` step, checked by `build`
+pub struct CannyBuilder {
+    output_dir: String,
+    sigma: f32,
+    low: i32,
+    high: i32,
+    operator: GradientOperator,
+    norm: GradientNorm,
+    connectivity: Connectivity,
+    border: BorderMode,
+    auto_threshold: Option<AutoThreshold>,
+    binary_mask: bool,
+    subpixel_positions: bool,
+}
+
+impl CannyBuilder {
+    /// The standard deviation of the noise reduction blur
+    pub fn sigma(mut self, sigma: f32) -> Self {
+        self.sigma = sigma;
+        self
+    }
+
+    /// The weak and strong edge thresholds on the suppressed gradient magnitude
+    pub fn thresholds(mut self, low: i32, high: i32) -> Self {
+        self.low = low;
+        self.high = high;
+        self
+    }
+
+    /// The derivative kernels used for the gradients
+    pub fn operator(mut self, operator: GradientOperator) -> Self {
+        self.operator = operator;
+        self
+    }
+
+    /// How the gradient magnitude is computed from the two derivatives
+    pub fn norm(mut self, norm: GradientNorm) -> Self {
+        self.norm = norm;
+        self
+    }
+
+    /// Which neighbours connect weak edges to strong ones during hysteresis
+    pub fn connectivity(mut self, connectivity: Connectivity) -> Self {
+        self.connectivity = connectivity;
+        self
+    }
+
+    /// How pixels outside the frame are read
+    pub fn border(mut self, border: BorderMode) -> Self {
+        self.border = border;
+        self
+    }
+
+    /// Derive the thresholds of every frame from its gradients, the fixed thresholds
+    /// are only used for frames without any gradient
+    pub fn auto_threshold(mut self, auto_threshold: AutoThreshold) -> Self {
+        self.auto_threshold = Some(auto_threshold);
+        self
+    }
+
+    /// Output 255 for every edge pixel instead of its gradient magnitude
+    pub fn binary_mask(mut self, binary_mask: bool) -> Self {
+        self.binary_mask = binary_mask;
+        self
+    }
+
+    /// Write the sub-pixel position of every edge to
+    /// `frame_<n>_output/frame_<n>_edges.json` in the output directory
+    pub fn subpixel_positions(mut self, subpixel_positions: bool) -> Self {
+        self.subpixel_positions = subpixel_positions;
+        self
+    }
+
+    /// Validate the parameters and create the step
+    pub fn build(self) -> Result<CannyEdgeDetection, CannyError> {
+        if !(self.sigma > 0.0 && self.sigma.is_finite()) {
+            return Err(CannyError::InvalidSigma(self.sigma));
+        }
+
+        check_thresholds(self.low, self.high)?;
+
+        let gaussian = GaussianBlur::with_border(&self.output_dir, self.sigma, self.border)?;
+
+        Ok(CannyEdgeDetection {
+            output_dir: self.output_dir,
+            gaussian,
+            sobel: SobelOperator::with_operator(self.operator, self.norm, self.border),
+            border: self.border,
+            low_threshold: self.low,
+            high_threshold: self.high,
+            auto_threshold: self.auto_threshold,
+            connectivity: self.connectivity,
+            binary_mask: self.binary_mask,
+            subpixel_positions: self.subpixel_positions,
         })
     }
 }
 
-impl PipelineStep for CannyEdgeDetection {
-    fn process(&self, frame: &mut Frame, frame_count: u32) -> io::Result<()> {
-        // create internal frame pipeline
+/// What `CannyEdgeDetection::detect` found in a frame
+pub struct CannyOutput {
+    /// The edge map, a binary mask or magnitudes as configured
+    pub edges: Image<u8>,
+    /// The gradients of the blurred frame the edges were traced from
+    pub gradients: Image<PixelGradient>,
+    /// The low and high threshold used, picked from the gradients in auto mode
+    pub thresholds: (i32, i32),
+}
+
+/// Summary of the edges of a frame, attached to the frame context
+#[derive(Debug, Serialize)]
+struct CannyResult {
+    low_threshold: i32,
+    high_threshold: i32,
+    edge_pixels: usize,
+}
+
+impl CannyEdgeDetection {
+    /// Run the detection on a frame, for steps building on the edges
+    ///
+    /// # Arguments
+    /// * `frame` - The frame to detect edges in, left blurred in 8 bit luma
+    /// * `frame_count` - Number of the frame, names the sub-pixel position file
+    pub fn detect(&self, frame: &mut Frame, frame_count: u64) -> io::Result<CannyOutput> {
         // step 1, gaussian noise reduction
         self.gaussian.process(frame, frame_count)?;
         // step 2, calculate gradients
-        let gradients = SobelOperator::calculate_gradient(
-            frame
-        );
-        // step 3, non max suppression of gradients back into a frame
-        *frame = GradNonMaxSuppression::suppress(gradients);
-        // step 4, double thresholding
-        let thresholder = DoubleThresholder::new(10, 40);
-        let thresholded = thresholder.threshold(frame); // NOTE: remove the clone
+        let gradients = self.sobel.calculate_gradient(frame);
+        // step 3, non max suppression of gradients
+        let (suppressed, points) = if self.subpixel_positions {
+            GradNonMaxSuppression::suppress_with_positions(&gradients, self.border)
+        } else {
+            (
+                GradNonMaxSuppression::suppress(&gradients, self.border),
+                Vec::new(),
+            )
+        };
+        // step 4, double thresholding, with thresholds from the gradients in auto mode
+        let (low, high) = self
+            .auto_threshold
+            .as_ref()
+            .and_then(|auto| auto.thresholds(&gradients))
+            .unwrap_or((self.low_threshold, self.high_threshold));
+        let thresholder = DoubleThresholder::new(low, high);
+        let thresholded = thresholder.threshold(&suppressed);
         // step 5, hysteria edge tracking
-        *frame = eight_conn_edge_tracker_hysteris(thresholded);
-        
-        //let nframe = frame;
+        let edges = edge_tracker_hysteresis(&thresholded, self.connectivity);
+        if self.subpixel_positions {
+            self.write_edge_points(&points, &edges, frame_count)?;
+        }
+
+        let edges = if self.binary_mask {
+            edges.map(|value| if value > 0 { 255 } else { 0 })
+        } else {
+            edges
+        };
+
+        Ok(CannyOutput {
+            edges,
+            gradients,
+            thresholds: (low, high),
+        })
+    }
+
+    // store the positions of the edges that survived hysteresis next to the frame output
+    fn write_edge_points(
+        &self,
+        points: &[EdgePoint],
+        edges: &Image<u8>,
+        frame_count: u64,
+    ) -> io::Result<()> {
+        let kept: Vec<&EdgePoint> = points
+            .iter()
+            .filter(|point| edges[(point.pixel[0], point.pixel[1])] > 0)
+            .collect();
+
+        write_frame_json(&self.output_dir, frame_count, "edges.json", &kept)
+    }
+}
+
+impl PipelineStep for CannyEdgeDetection {
+    fn process(&self, frame: &mut Frame, frame_count: u64) -> io::Result<()> {
+        self.process_with_context(frame, &mut FrameContext::new(frame_count))
+    }
+
+    fn process_with_context(
+        &self,
+        frame: &mut Frame,
+        context: &mut FrameContext,
+    ) -> io::Result<()> {
+        let output = self.detect(frame, context.frame_count())?;
+        context.insert(&CannyResult {
+            low_threshold: output.thresholds.0,
+            high_threshold: output.thresholds.1,
+            edge_pixels: output.edges.pixels().filter(|&value| value > 0).count(),
+        })?;
+        *frame = output.edges.into();
+        // later steps can reuse the gradients of the blurred frame
+        context.insert_data(output.gradients);
 
         Ok(())
     }
 
+    fn is_stateful(&self) -> bool {
+        self.auto_threshold
+            .as_ref()
+            .is_some_and(|auto| auto.is_smoothed())
+    }
+
     fn name(&self) -> &str {
         "CannyEdgeDetection"
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // a bright square on a dark background
+    fn square() -> Frame {
+        Image::from_fn(16, 16, |x, y| {
+            if (4..12).contains(&x) && (4..12).contains(&y) {
+                200u8
+            } else {
+                20
+            }
+        })
+        .into()
+    }
+
+    #[test]
+    fn builder_rejects_invalid_sigma() {
+        for sigma in [0.0, -1.0, f32::NAN, f32::INFINITY] {
+            let result = CannyEdgeDetection::builder("output").sigma(sigma).build();
+            assert!(
+                matches!(result, Err(CannyError::InvalidSigma(_))),
+                "sigma {}",
+                sigma
+            );
+        }
+    }
+
+    #[test]
+    fn builder_rejects_invalid_thresholds() {
+        for (low, high) in [(-1, 100), (120, 60), (50, 256)] {
+            let result = CannyEdgeDetection::builder("output")
+                .thresholds(low, high)
+                .build();
+            assert!(
+                matches!(result, Err(CannyError::InvalidThresholds(_))),
+                "thresholds {} {}",
+                low,
+                high
+            );
+        }
+    }
+
+    #[test]
+    fn binary_mask_is_0_or_255() {
+        let canny = CannyEdgeDetection::builder("output")
+            .binary_mask(true)
+            .build()
+            .unwrap();
+
+        let output = canny.detect(&mut square(), 0).unwrap();
+        assert!(output
+            .edges
+            .pixels()
+            .all(|value| value == 0 || value == 255));
+        assert!(output.edges.pixels().any(|value| value == 255));
+        // the edges run along the square, not through its inside or the background
+        assert_eq!(output.edges[(8, 8)], 0);
+        assert_eq!(output.edges[(1, 1)], 0);
+    }
+
+    #[test]
+    fn magnitudes_are_kept_without_binary_mask() {
+        let canny = CannyEdgeDetection::builder("output").build().unwrap();
+
+        let output = canny.detect(&mut square(), 0).unwrap();
+        assert!(output.edges.pixels().any(|value| value > 0 && value < 255));
+    }
+}
@@ -0,0 +1,378 @@
+use crate::frame::{Frame, Image, PixelFormat};
+use crate::frame_pipeline::{FrameContext, PipelineStep};
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::io;
+
+use super::eight_conn_edge_tracker::Connectivity;
+
+/// Parameters for a `connected_components` step in a pipeline description file
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ConnectedComponentsConfig {
+    /// `4` or `8`
+    #[serde(default)]
+    pub connectivity: Connectivity,
+    /// Blobs with fewer pixels are dropped
+    #[serde(default = "ConnectedComponentsConfig::default_min_area")]
+    pub min_area: usize,
+    /// Blobs with more pixels are dropped
+    pub max_area: Option<usize>,
+    /// Replace the frame with the labels, every blob in its own color
+    #[serde(default)]
+    pub colorize: bool,
+}
+
+impl ConnectedComponentsConfig {
+    fn default_min_area() -> usize {
+        1
+    }
+}
+
+/// Measurements of one connected component
+#[derive(Debug, Clone, Serialize)]
+pub struct BlobStats {
+    /// Label of the blob in the label image, starting at 1
+    pub label: u32,
+    /// Number of pixels
+    pub area: usize,
+    /// Smallest and largest column and row of the blob, as `[x0, y0, x1, y1]`
+    pub bounding_box: [usize; 4],
+    /// Mean pixel position, as `[x, y]`
+    pub centroid: [f32; 2],
+    /// Length of the boundary between the blob and its surroundings, counting pixel
+    /// sides, holes included
+    pub perimeter: usize,
+    /// Angle of the major axis in radians from the x axis, y pointing down
+    pub orientation: f32,
+    /// 0.0 for round blobs up to 1.0 for lines, from the second order moments
+    pub eccentricity: f32,
+}
+
+/// Label the connected non zero regions of a mask
+///
+/// # Arguments
+/// * `mask` - The pixels to group, true for foreground
+/// * `connectivity` - Which neighbours belong to the same region
+///
+/// # Returns
+/// * The label of every pixel, 0 for the background and 1.. for the regions in
+///   raster order of their first pixel, and the number of regions
+pub fn label_components(mask: &Image<bool>, connectivity: Connectivity) -> (Image<u32>, u32) {
+    let (width, height) = (mask.width() as i32, mask.height() as i32);
+    let mut labels = Image::new(mask.width(), mask.height(), 0u32);
+    let mut queue = VecDeque::new();
+    let mut count = 0;
+
+    for y in 0..height {
+        for x in 0..width {
+            let position = (x as usize, y as usize);
+            if !mask[position] || labels[position] != 0 {
+                continue;
+            }
+
+            // flood the region from its first pixel
+            count += 1;
+            labels[position] = count;
+            queue.push_back((x, y));
+            while let Some((x, y)) = queue.pop_front() {
+                for (dx, dy) in connectivity.neighbors() {
+                    let (nx, ny) = (x + dx, y + dy);
+                    if nx < 0 || nx >= width || ny < 0 || ny >= height {
+                        continue;
+                    }
+
+                    let neighbour = (nx as usize, ny as usize);
+                    if mask[neighbour] && labels[neighbour] == 0 {
+                        labels[neighbour] = count;
+                        queue.push_back((nx, ny));
+                    }
+                }
+            }
+        }
+    }
+
+    (labels, count)
+}
+
+/// Measure every labeled region, in label order
+///
+/// # Arguments
+/// * `labels` - The label image from `label_components`
+/// * `count` - The number of labels
+pub fn blob_stats(labels: &Image<u32>, count: u32) -> Vec<BlobStats> {
+    #[derive(Clone)]
+    struct Sums {
+        area: usize,
+        bounds: [usize; 4],
+        sum: [f64; 2],
+        // raw second order moments x², y² and xy
+        squares: [f64; 3],
+        perimeter: usize,
+    }
+
+    let mut sums = vec![
+        Sums {
+            area: 0,
+            bounds: [usize::MAX, usize::MAX, 0, 0],
+            sum: [0.0; 2],
+            squares: [0.0; 3],
+            perimeter: 0,
+        };
+        count as usize
+    ];
+
+    let (width, height) = (labels.width(), labels.height());
+    for y in 0..height {
+        for x in 0..width {
+            let label = labels[(x, y)];
+            if label == 0 {
+                continue;
+            }
+
+            let blob = &mut sums[label as usize - 1];
+            blob.area += 1;
+            blob.bounds = [
+                blob.bounds[0].min(x),
+                blob.bounds[1].min(y),
+                blob.bounds[2].max(x),
+                blob.bounds[3].max(y),
+            ];
+
+            let (fx, fy) = (x as f64, y as f64);
+            blob.sum[0] += fx;
+            blob.sum[1] += fy;
+            blob.squares[0] += fx * fx;
+            blob.squares[1] += fy * fy;
+            blob.squares[2] += fx * fy;
+
+            // sides of the pixel facing another region, the background or the frame edge
+            let outside = |nx: isize, ny: isize| {
+                nx < 0
+                    || ny < 0
+                    || nx >= width as isize
+                    || ny >= height as isize
+                    || labels[(nx as usize, ny as usize)] != label
+            };
+            let (ix, iy) = (x as isize, y as isize);
+            blob.perimeter += [(ix - 1, iy), (ix + 1, iy), (ix, iy - 1), (ix, iy + 1)]
+                .iter()
+                .filter(|&&(nx, ny)| outside(nx, ny))
+                .count();
+        }
+    }
+
+    sums.into_iter()
+        .enumerate()
+        .filter(|(_, blob)| blob.area > 0)
+        .map(|(index, blob)| {
+            let area = blob.area as f64;
+            let (cx, cy) = (blob.sum[0] / area, blob.sum[1] / area);
+
+            // central moments, normalized by the area
+            let mu20 = blob.squares[0] / area - cx * cx;
+            let mu02 = blob.squares[1] / area - cy * cy;
+            let mu11 = blob.squares[2] / area - cx * cy;
+
+            let orientation = 0.5 * (2.0 * mu11).atan2(mu20 - mu02);
+            let spread = ((mu20 - mu02).powi(2) + 4.0 * mu11 * mu11).sqrt();
+            let major = (mu20 + mu02 + spread) / 2.0;
+            let minor = (mu20 + mu02 - spread) / 2.0;
+            let eccentricity = if major > 0.0 {
+                (1.0 - (minor / major).max(0.0)).sqrt()
+            } else {
+                0.0
+            };
+
+            BlobStats {
+                label: index as u32 + 1,
+                area: blob.area,
+                bounding_box: blob.bounds,
+                centroid: [cx as f32, cy as f32],
+                perimeter: blob.perimeter,
+                orientation: orientation as f32,
+                eccentricity: eccentricity as f32,
+            }
+        })
+        .collect()
+}
+
+/// A distinct color for every label, spreading the hues with the golden ratio
+fn label_color(label: u32) -> [u8; 3] {
+    let hue = (label as f32 * 0.618_034).fract() * 6.0;
+    let sector = hue as u32;
+    let rising = hue.fract();
+    let (r, g, b) = match sector {
+        0 => (1.0, rising, 0.0),
+        1 => (1.0 - rising, 1.0, 0.0),
+        2 => (0.0, 1.0, rising),
+        3 => (0.0, 1.0 - rising, 1.0),
+        4 => (rising, 0.0, 1.0),
+        _ => (1.0, 0.0, 1.0 - rising),
+    };
+    [(r * 255.0) as u8, (g * 255.0) as u8, (b * 255.0) as u8]
+}
+
+/// Pipeline step labeling the connected regions of non zero pixels and attaching their
+/// measurements to the frame context. The frame passes through unchanged, or is
+/// replaced by the colorized labels.
+pub struct ConnectedComponents {
+    connectivity: Connectivity,
+    /// Blobs with fewer pixels are dropped
+    min_area: usize,
+    /// Blobs with more pixels are dropped
+    max_area: Option<usize>,
+    /// Replace the frame with the colorized labels
+    colorize: bool,
+}
+
+impl ConnectedComponents {
+    /// Create a step keeping every blob and leaving the frame unchanged
+    ///
+    /// # Arguments
+    /// * `connectivity` - Which neighbours belong to the same blob
+    pub fn new(connectivity: Connectivity) -> Self {
+        Self {
+            connectivity,
+            min_area: 1,
+            max_area: None,
+            colorize: false,
+        }
+    }
+
+    pub fn from_config(config: ConnectedComponentsConfig) -> io::Result<Self> {
+        if let Some(max_area) = config.max_area {
+            if max_area < config.min_area {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!(
+                        "Invalid blob area range {}..={}, max_area is below min_area",
+                        config.min_area, max_area
+                    ),
+                ));
+            }
+        }
+
+        Ok(Self {
+            min_area: config.min_area,
+            max_area: config.max_area,
+            colorize: config.colorize,
+            ..Self::new(config.connectivity)
+        })
+    }
+
+    /// Label the blobs of a frame and measure them, dropping those outside the area
+    /// range. The remaining blobs are numbered 1.. again in the label image.
+    pub fn analyze(&self, frame: &Frame) -> (Image<u32>, Vec<BlobStats>) {
+        let mask = frame.map_gray(|value| value > 0);
+        let (labels, count) = label_components(&mask, self.connectivity);
+        let stats = blob_stats(&labels, count);
+
+        // new label of every old one, 0 for the dropped blobs
+        let mut renumbered = vec![0u32; count as usize + 1];
+        let mut kept = Vec::new();
+        for blob in stats {
+            let too_large = self.max_area.map_or(false, |max_area| blob.area > max_area);
+            if blob.area < self.min_area || too_large {
+                continue;
+            }
+
+            renumbered[blob.label as usize] = kept.len() as u32 + 1;
+            kept.push(BlobStats {
+                label: kept.len() as u32 + 1,
+                ..blob
+            });
+        }
+
+        (labels.map(|label| renumbered[label as usize]), kept)
+    }
+}
+
+impl PipelineStep for ConnectedComponents {
+    fn process(&self, frame: &mut Frame, frame_count: u64) -> io::Result<()> {
+        self.process_with_context(frame, &mut FrameContext::new(frame_count))
+    }
+
+    fn process_with_context(
+        &self,
+        frame: &mut Frame,
+        context: &mut FrameContext,
+    ) -> io::Result<()> {
+        let (labels, blobs) = self.analyze(frame);
+        context.insert(&blobs)?;
+
+        if self.colorize {
+            let data = labels
+                .pixels()
+                .flat_map(|label| match label {
+                    0 => [0, 0, 0],
+                    label => label_color(label),
+                })
+                .collect();
+            *frame = Frame::new(data, frame.width(), frame.height(), PixelFormat::Rgb)?;
+        }
+
+        Ok(())
+    }
+
+    fn name(&self) -> &str {
+        "ConnectedComponents"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // mask from rows of text, '#' marks the foreground
+    fn mask(rows: &[&str]) -> Image<bool> {
+        let rows: Vec<&[u8]> = rows.iter().map(|row| row.as_bytes()).collect();
+        Image::from_fn(rows[0].len(), rows.len(), |x, y| rows[y][x] == b'#')
+    }
+
+    #[test]
+    fn square_blob_moments() {
+        let (labels, count) =
+            label_components(&mask(&["###.", "###.", "###."]), Connectivity::Eight);
+        assert_eq!(count, 1);
+
+        let blob = &blob_stats(&labels, count)[0];
+        assert_eq!(blob.area, 9);
+        assert_eq!(blob.centroid, [1.0, 1.0]);
+        assert_eq!(blob.bounding_box, [0, 0, 2, 2]);
+        assert_eq!(blob.perimeter, 12);
+        assert!(blob.eccentricity.abs() < 1e-3);
+    }
+
+    #[test]
+    fn line_blob_is_eccentric_along_its_axis() {
+        let (labels, count) =
+            label_components(&mask(&[".....", "#####", "....."]), Connectivity::Four);
+        let blob = &blob_stats(&labels, count)[0];
+        assert_eq!(blob.centroid, [2.0, 1.0]);
+        assert!(blob.orientation.abs() < 1e-3);
+        assert!((blob.eccentricity - 1.0).abs() < 1e-3);
+    }
+
+    #[test]
+    fn diagonal_neighbours_need_eight_connectivity() {
+        let diagonal = mask(&["#..", ".#.", "..#"]);
+        assert_eq!(label_components(&diagonal, Connectivity::Four).1, 3);
+        assert_eq!(label_components(&diagonal, Connectivity::Eight).1, 1);
+    }
+
+    #[test]
+    fn area_filter_renumbers_the_kept_blobs() {
+        let frame = Frame::from(mask(&["#.##", "..##", "...."]).map(|member| member as u8 * 255));
+        let step = ConnectedComponents {
+            min_area: 2,
+            ..ConnectedComponents::new(Connectivity::Eight)
+        };
+
+        let (labels, blobs) = step.analyze(&frame);
+        assert_eq!(blobs.len(), 1);
+        assert_eq!((blobs[0].label, blobs[0].area), (1, 4));
+        assert_eq!(labels[(0, 0)], 0);
+        assert_eq!(labels[(2, 0)], 1);
+    }
+}
@@ -0,0 +1,611 @@
+use crate::frame::{Frame, Image};
+use crate::frame_pipeline::{write_frame_file, FrameContext, PipelineStep};
+use serde::{Deserialize, Serialize};
+use std::fmt::Write;
+use std::io;
+
+/// How edge pixels are grouped into contours
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ContourMode {
+    /// Closed borders of the edge regions with their nesting, outer borders contain
+    /// holes which contain outer borders again. A one pixel wide line is traced
+    /// along both of its sides.
+    #[default]
+    Borders,
+    /// Open chains following one pixel wide edges from end to end, closed loops
+    /// are reported once. Chains have no nesting.
+    Polylines,
+}
+
+/// Parameters for a `contours` step in a pipeline description file
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ContourConfig {
+    #[serde(default)]
+    pub mode: ContourMode,
+    /// Douglas-Peucker tolerance in pixels, 0 keeps every traced point
+    #[serde(default = "ContourConfig::default_epsilon")]
+    pub epsilon: f32,
+    /// Contours with fewer traced pixels are dropped
+    #[serde(default)]
+    pub min_points: usize,
+    /// Also draw the contours into `frame_<n>_contours.svg` next to the frame output
+    #[serde(default = "ContourConfig::default_svg")]
+    pub svg: bool,
+}
+
+impl ContourConfig {
+    fn default_epsilon() -> f32 {
+        1.0
+    }
+
+    fn default_svg() -> bool {
+        true
+    }
+}
+
+/// A traced contour, its points are pixel positions
+#[derive(Debug, Clone, Serialize)]
+pub struct Contour {
+    /// Index of the contour in the list of a frame
+    pub id: usize,
+    /// Index of the contour this one lies in, if any
+    pub parent: Option<usize>,
+    /// Whether the contour is the border of a hole in its parent
+    pub hole: bool,
+    /// Whether the last point connects back to the first
+    pub closed: bool,
+    /// Points along the contour, as `[x, y]`
+    pub points: Vec<[i32; 2]>,
+}
+
+// neighbour offsets counterclockwise on screen, starting east, as (dx, dy)
+const DIRECTIONS: [(i32, i32); 8] = [
+    (1, 0),
+    (1, -1),
+    (0, -1),
+    (-1, -1),
+    (-1, 0),
+    (-1, 1),
+    (0, 1),
+    (1, 1),
+];
+
+// index into DIRECTIONS of the step from one pixel to a neighbouring one
+fn direction_index(from: (i32, i32), to: (i32, i32)) -> usize {
+    let step = (to.0 - from.0, to.1 - from.1);
+    DIRECTIONS.iter().position(|&d| d == step).unwrap()
+}
+
+/// Trace the borders of the non zero regions of a mask with their hierarchy, following
+/// the border following algorithm of Suzuki and Abe
+pub fn find_borders(mask: &Image<bool>) -> Vec<Contour> {
+    // labels with a one pixel background frame around the mask: 0 background,
+    // 1 unvisited foreground, +-n on border n (negative where its right side is background)
+    let width = mask.width() as i32 + 2;
+    let height = mask.height() as i32 + 2;
+    let mut labels = Image::from_fn(width as usize, height as usize, |x, y| {
+        let inside = x > 0 && y > 0 && x < width as usize - 1 && y < height as usize - 1;
+        (inside && mask[(x - 1, y - 1)]) as i32
+    });
+    let label = |labels: &Image<i32>, (x, y): (i32, i32)| labels[(x as usize, y as usize)];
+
+    let mut contours: Vec<Contour> = Vec::new();
+    // border number 1 is the frame, contours[n - 2] holds border n
+    let mut border_number = 1;
+
+    for y in 1..height - 1 {
+        // the last border crossed on this row
+        let mut last_border = 1;
+
+        for x in 1..width - 1 {
+            let value = label(&labels, (x, y));
+            if value == 0 {
+                continue;
+            }
+
+            let start = (x, y);
+            let found = if value == 1 && label(&labels, (x - 1, y)) == 0 {
+                Some((false, (x - 1, y)))
+            } else if value >= 1 && label(&labels, (x + 1, y)) == 0 {
+                if value > 1 {
+                    last_border = value;
+                }
+                Some((true, (x + 1, y)))
+            } else {
+                None
+            };
+
+            if let Some((hole, from)) = found {
+                border_number += 1;
+
+                // outer borders nest in the region around them, holes in the region
+                // they cut into
+                let parent = if last_border == 1 {
+                    None
+                } else {
+                    let previous = &contours[last_border as usize - 2];
+                    if previous.hole == hole {
+                        previous.parent
+                    } else {
+                        Some(previous.id)
+                    }
+                };
+
+                let points = follow_border(&mut labels, start, from, border_number)
+                    .into_iter()
+                    .map(|(x, y)| [x - 1, y - 1])
+                    .collect();
+
+                contours.push(Contour {
+                    id: contours.len(),
+                    parent,
+                    hole,
+                    closed: true,
+                    points,
+                });
+            }
+
+            let value = label(&labels, (x, y));
+            if value != 1 {
+                last_border = value.abs();
+            }
+        }
+    }
+
+    contours
+}
+
+// follow one border from its start pixel, marking it in the labels
+fn follow_border(
+    labels: &mut Image<i32>,
+    start: (i32, i32),
+    from: (i32, i32),
+    border_number: i32,
+) -> Vec<(i32, i32)> {
+    let at = |labels: &Image<i32>, (x, y): (i32, i32)| labels[(x as usize, y as usize)];
+    let neighbour = |(x, y): (i32, i32), index: usize| {
+        let (dx, dy) = DIRECTIONS[index % 8];
+        (x + dx, y + dy)
+    };
+
+    // look clockwise from the background pixel for the first neighbour on the border
+    let from_index = direction_index(start, from);
+    let first = (0..8)
+        .map(|k| neighbour(start, from_index + 8 - k))
+        .find(|&pixel| at(labels, pixel) != 0);
+
+    let first = match first {
+        Some(pixel) => pixel,
+        None => {
+            // an isolated pixel
+            labels[(start.0 as usize, start.1 as usize)] = -border_number;
+            return vec![start];
+        }
+    };
+
+    let mut points = Vec::new();
+    let mut previous = first;
+    let mut current = start;
+    loop {
+        points.push(current);
+
+        // look counterclockwise from the previous pixel for the next one
+        let previous_index = direction_index(current, previous);
+        let mut east_is_background = false;
+        let mut next = current;
+        for k in 1..=8 {
+            let index = (previous_index + k) % 8;
+            let pixel = neighbour(current, index);
+            if at(labels, pixel) != 0 {
+                next = pixel;
+                break;
+            }
+            if index == 0 {
+                east_is_background = true;
+            }
+        }
+
+        let (x, y) = (current.0 as usize, current.1 as usize);
+        if east_is_background {
+            labels[(x, y)] = -border_number;
+        } else if labels[(x, y)] == 1 {
+            labels[(x, y)] = border_number;
+        }
+
+        if next == start && current == first {
+            break;
+        }
+
+        previous = current;
+        current = next;
+    }
+
+    points
+}
+
+/// Trace one pixel wide edges of a mask into chains, starting from their end points.
+/// A chain runs on through a junction into its first unvisited neighbour, direct
+/// neighbours first, the branches it passes become chains of their own.
+pub fn find_polylines(mask: &Image<bool>) -> Vec<Contour> {
+    let width = mask.width() as i32;
+    let height = mask.height() as i32;
+    let is_edge = |x: i32, y: i32| {
+        x >= 0 && y >= 0 && x < width && y < height && mask[(x as usize, y as usize)]
+    };
+
+    let mut visited = Image::new(mask.width(), mask.height(), false);
+    let mut contours = Vec::new();
+
+    // direct neighbours first so chains don't cut corners
+    let order = [0, 2, 4, 6, 1, 3, 5, 7];
+    let mut trace = |start: (i32, i32), visited: &mut Image<bool>| {
+        let mut points = vec![[start.0, start.1]];
+        visited[(start.0 as usize, start.1 as usize)] = true;
+        let mut current = start;
+
+        loop {
+            let next = order
+                .iter()
+                .map(|&index| {
+                    let (dx, dy) = DIRECTIONS[index];
+                    (current.0 + dx, current.1 + dy)
+                })
+                .find(|&(x, y)| is_edge(x, y) && !visited[(x as usize, y as usize)]);
+
+            match next {
+                Some((x, y)) => {
+                    visited[(x as usize, y as usize)] = true;
+                    points.push([x, y]);
+                    current = (x, y);
+                }
+                None => break,
+            }
+        }
+
+        // a chain ending next to its start is a loop
+        let [last_x, last_y] = points[points.len() - 1];
+        let closed =
+            points.len() > 2 && (last_x - start.0).abs() <= 1 && (last_y - start.1).abs() <= 1;
+
+        contours.push(Contour {
+            id: contours.len(),
+            parent: None,
+            hole: false,
+            closed,
+            points,
+        });
+    };
+
+    let neighbours = |x: i32, y: i32| {
+        DIRECTIONS
+            .iter()
+            .filter(|&&(dx, dy)| is_edge(x + dx, y + dy))
+            .count()
+    };
+
+    // open chains from their end points, then whatever is left: loops and branches
+    for end_points_only in [true, false] {
+        for y in 0..height {
+            for x in 0..width {
+                if !is_edge(x, y) || visited[(x as usize, y as usize)] {
+                    continue;
+                }
+                if end_points_only && neighbours(x, y) > 1 {
+                    continue;
+                }
+                trace((x, y), &mut visited);
+            }
+        }
+    }
+
+    contours
+}
+
+/// Simplify a polyline with the Douglas-Peucker algorithm, keeping every point
+/// further than `epsilon` from the simplified line
+///
+/// # Arguments
+/// * `points` - The points of the polyline
+/// * `epsilon` - Largest allowed distance in pixels between the input and the result
+/// * `closed` - Whether the last point connects back to the first
+pub fn simplify(points: &[[i32; 2]], epsilon: f32, closed: bool) -> Vec<[i32; 2]> {
+    if points.len() < 3 || epsilon <= 0.0 {
+        return points.to_vec();
+    }
+
+    if closed {
+        // split the loop at the point furthest from the first into two open halves
+        let first = points[0];
+        let (split, _) = points
+            .iter()
+            .enumerate()
+            .map(|(i, &p)| (i, squared_distance(first, p)))
+            .fold(
+                (0, 0),
+                |best, item| if item.1 > best.1 { item } else { best },
+            );
+        if split == 0 {
+            return vec![first];
+        }
+
+        let mut result = simplify(&points[..=split], epsilon, false);
+        let mut second_half = points[split..].to_vec();
+        second_half.push(first);
+        let second = simplify(&second_half, epsilon, false);
+        result.extend_from_slice(&second[1..second.len() - 1]);
+        return result;
+    }
+
+    let mut keep = vec![false; points.len()];
+    keep[0] = true;
+    keep[points.len() - 1] = true;
+
+    let mut pending = vec![(0, points.len() - 1)];
+    while let Some((first, last)) = pending.pop() {
+        let mut furthest = (0, 0.0f32);
+        for i in first + 1..last {
+            let distance = line_distance(points[i], points[first], points[last]);
+            if distance > furthest.1 {
+                furthest = (i, distance);
+            }
+        }
+
+        if furthest.1 > epsilon {
+            keep[furthest.0] = true;
+            pending.push((first, furthest.0));
+            pending.push((furthest.0, last));
+        }
+    }
+
+    points
+        .iter()
+        .zip(keep)
+        .filter(|(_, keep)| *keep)
+        .map(|(&point, _)| point)
+        .collect()
+}
+
+fn squared_distance(a: [i32; 2], b: [i32; 2]) -> i32 {
+    (a[0] - b[0]).pow(2) + (a[1] - b[1]).pow(2)
+}
+
+// distance of a point to the segment from start to end
+fn line_distance(point: [i32; 2], start: [i32; 2], end: [i32; 2]) -> f32 {
+    let [px, py] = [point[0] as f32, point[1] as f32];
+    let [sx, sy] = [start[0] as f32, start[1] as f32];
+    let [ex, ey] = [end[0] as f32, end[1] as f32];
+    let (dx, dy) = (ex - sx, ey - sy);
+    let length_squared = dx * dx + dy * dy;
+    if length_squared == 0.0 {
+        return ((px - sx).powi(2) + (py - sy).powi(2)).sqrt();
+    }
+
+    let t = (((px - sx) * dx + (py - sy) * dy) / length_squared).clamp(0.0, 1.0);
+    ((px - sx - t * dx).powi(2) + (py - sy - t * dy).powi(2)).sqrt()
+}
+
+/// Render contours as an SVG document of the frame size, holes are drawn in red
+pub fn contours_to_svg(contours: &[Contour], width: i32, height: i32) -> String {
+    let mut svg = format!(
+        "<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{}\" height=\"{}\" viewBox=\"0 0 {} {}\">\n",
+        width, height, width, height
+    );
+    svg.push_str("<rect width=\"100%\" height=\"100%\" fill=\"black\"/>\n");
+
+    for contour in contours {
+        let element = if contour.closed {
+            "polygon"
+        } else {
+            "polyline"
+        };
+        let color = if contour.hole { "red" } else { "white" };
+        // pixel centers sit half a pixel in
+        let points: Vec<String> = contour
+            .points
+            .iter()
+            .map(|[x, y]| format!("{}.5,{}.5", x, y))
+            .collect();
+        let _ = writeln!(
+            svg,
+            "<{} id=\"contour-{}\" points=\"{}\" fill=\"none\" stroke=\"{}\" stroke-width=\"1\"/>",
+            element,
+            contour.id,
+            points.join(" "),
+            color
+        );
+    }
+
+    svg.push_str("</svg>\n");
+    svg
+}
+
+/// Pipeline step tracing the edges of a frame into contours, attaching them to the
+/// frame context and drawing them as SVG next to the frame output. The frame itself
+/// passes through unchanged.
+pub struct ContourExtraction {
+    /// Directory the per frame SVG files are written to
+    output_dir: String,
+    mode: ContourMode,
+    /// Douglas-Peucker tolerance in pixels, 0 disables simplification
+    epsilon: f32,
+    /// Contours with fewer traced pixels are dropped
+    min_points: usize,
+    svg: bool,
+}
+
+impl ContourExtraction {
+    /// Create a contour step writing SVG files
+    ///
+    /// # Arguments
+    /// * `output_dir` - The directory to write the per frame SVG files to
+    /// * `mode` - How edge pixels are grouped into contours
+    /// * `epsilon` - Douglas-Peucker tolerance in pixels, 0 keeps every point
+    pub fn new(output_dir: &str, mode: ContourMode, epsilon: f32) -> io::Result<Self> {
+        if !(epsilon >= 0.0 && epsilon.is_finite()) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Invalid contour epsilon {}, must be 0 or more", epsilon),
+            ));
+        }
+
+        Ok(Self {
+            output_dir: output_dir.to_string(),
+            mode,
+            epsilon,
+            min_points: 0,
+            svg: true,
+        })
+    }
+
+    pub fn from_config(config: ContourConfig, output_dir: &str) -> io::Result<Self> {
+        let mut contours = Self::new(output_dir, config.mode, config.epsilon)?;
+        contours.min_points = config.min_points;
+        contours.svg = config.svg;
+        Ok(contours)
+    }
+
+    /// Trace and simplify the contours of a frame, every non zero pixel is an edge
+    pub fn extract(&self, frame: &Frame) -> Vec<Contour> {
+        let mask = frame.map_gray(|value| value > 0);
+        let traced = match self.mode {
+            ContourMode::Borders => find_borders(&mask),
+            ContourMode::Polylines => find_polylines(&mask),
+        };
+
+        // renumber the kept contours, pointing children of dropped ones at nothing
+        let mut ids = vec![None; traced.len()];
+        let mut contours = Vec::new();
+        for contour in traced {
+            if contour.points.len() < self.min_points {
+                continue;
+            }
+            ids[contour.id] = Some(contours.len());
+            contours.push(Contour {
+                id: contours.len(),
+                parent: contour.parent.and_then(|parent| ids[parent]),
+                points: simplify(&contour.points, self.epsilon, contour.closed),
+                ..contour
+            });
+        }
+
+        contours
+    }
+}
+
+impl PipelineStep for ContourExtraction {
+    fn process(&self, frame: &mut Frame, frame_count: u64) -> io::Result<()> {
+        self.process_with_context(frame, &mut FrameContext::new(frame_count))
+    }
+
+    fn process_with_context(
+        &self,
+        frame: &mut Frame,
+        context: &mut FrameContext,
+    ) -> io::Result<()> {
+        let contours = self.extract(frame);
+        context.insert(&contours)?;
+
+        if self.svg {
+            write_frame_file(
+                &self.output_dir,
+                context.frame_count(),
+                "contours.svg",
+                contours_to_svg(&contours, frame.width(), frame.height()),
+            )?;
+        }
+
+        Ok(())
+    }
+
+    fn name(&self) -> &str {
+        "ContourExtraction"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // mask from rows of text, '#' marks the edge pixels
+    fn mask(rows: &[&str]) -> Image<bool> {
+        let rows: Vec<&[u8]> = rows.iter().map(|row| row.as_bytes()).collect();
+        Image::from_fn(rows[0].len(), rows.len(), |x, y| rows[y][x] == b'#')
+    }
+
+    fn bounds(contour: &Contour) -> [i32; 4] {
+        let xs = contour.points.iter().map(|p| p[0]);
+        let ys = contour.points.iter().map(|p| p[1]);
+        [
+            xs.clone().min().unwrap(),
+            ys.clone().min().unwrap(),
+            xs.max().unwrap(),
+            ys.max().unwrap(),
+        ]
+    }
+
+    #[test]
+    fn square_with_hole_has_outer_border_and_hole() {
+        let contours = find_borders(&mask(&[
+            ".......", //
+            ".#####.", //
+            ".#...#.", //
+            ".#...#.", //
+            ".#...#.", //
+            ".#####.", //
+            ".......", //
+        ]));
+
+        assert_eq!(contours.len(), 2);
+        let (outer, hole) = (&contours[0], &contours[1]);
+        assert!(!outer.hole && outer.closed);
+        assert_eq!(outer.parent, None);
+        assert_eq!(bounds(outer), [1, 1, 5, 5]);
+        assert!(hole.hole && hole.closed);
+        assert_eq!(hole.parent, Some(outer.id));
+        assert_eq!(bounds(hole), [1, 1, 5, 5]);
+    }
+
+    #[test]
+    fn polylines_continue_through_junctions() {
+        let contours = find_polylines(&mask(&[
+            "#######", //
+            "...#...", //
+            "...#...", //
+            "...#...", //
+        ]));
+
+        // the chain from the first end point takes one branch at the junction,
+        // the other branch is traced from its own end point
+        assert_eq!(contours.len(), 2);
+        assert!(contours.iter().all(|contour| !contour.closed));
+        let points: usize = contours.iter().map(|contour| contour.points.len()).sum();
+        assert_eq!(points, 10);
+        assert_eq!(contours[0].points[0], [0, 0]);
+    }
+
+    #[test]
+    fn polyline_loops_are_closed() {
+        let contours = find_polylines(&mask(&["###", "#.#", "###"]));
+        assert_eq!(contours.len(), 1);
+        assert!(contours[0].closed);
+        assert_eq!(contours[0].points.len(), 8);
+    }
+
+    #[test]
+    fn simplify_keeps_the_corners_of_a_closed_square() {
+        let mut points = Vec::new();
+        points.extend((0..4).map(|i| [i, 0]));
+        points.extend((0..4).map(|i| [4, i]));
+        points.extend((0..4).map(|i| [4 - i, 4]));
+        points.extend((0..4).map(|i| [0, 4 - i]));
+
+        let simplified = simplify(&points, 0.5, true);
+        assert_eq!(simplified, vec![[0, 0], [4, 0], [4, 4], [0, 4]]);
+
+        // an open straight line keeps only its end points
+        let line: Vec<[i32; 2]> = (0..10).map(|i| [i, 2 * i]).collect();
+        assert_eq!(simplify(&line, 0.5, false), vec![[0, 0], [9, 18]]);
+    }
+}
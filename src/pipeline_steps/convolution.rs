@@ -0,0 +1,716 @@
+use super::border::BorderMode;
+use super::gaussian_blur::gaussian_kernel;
+use crate::frame::Frame;
+use crate::frame_pipeline::PipelineStep;
+
+use serde::Deserialize;
+use std::io;
+use std::ops::{Add, Mul};
+
+/// Parameters for a `convolution` step in a pipeline description file
+///
+/// ```toml
+/// [[steps]]
+/// type = "convolution"
+/// kernel = "sobel_x"
+///
+/// [[steps]]
+/// type = "convolution"
+/// kernel = "custom"
+/// matrix = [[0, -1, 0], [-1, 5, -1], [0, -1, 0]]
+/// ```
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ConvolutionConfig {
+    /// A preset name, `custom` with a `matrix` or `separable` with `horizontal` and `vertical`
+    pub kernel: String,
+    /// Side length of the `box` kernel
+    pub size: Option<usize>,
+    /// Standard deviation of the `gaussian` kernel
+    pub sigma: Option<f32>,
+    /// Rows of a `custom` kernel
+    pub matrix: Option<Vec<Vec<f32>>>,
+    /// Row kernel of a `separable` kernel
+    pub horizontal: Option<Vec<f32>>,
+    /// Column kernel of a `separable` kernel
+    pub vertical: Option<Vec<f32>>,
+    /// Multiplier applied to every sum, defaults to the preset's normalization
+    pub scale: Option<f32>,
+    /// Added to every sum after scaling, e.g. 128 to center signed responses
+    pub offset: Option<f32>,
+    /// Take the magnitude of signed responses, defaults to true for derivative presets
+    pub absolute: Option<bool>,
+    #[serde(default)]
+    pub border: BorderMode,
+}
+
+#[derive(Debug)]
+pub enum ConvolutionError {
+    InvalidKernel(String),
+    UnknownKernel(String),
+    InvalidDimensions(String),
+}
+
+impl std::fmt::Display for ConvolutionError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConvolutionError::InvalidKernel(msg) => write!(f, "Invalid kernel: {}", msg),
+            ConvolutionError::UnknownKernel(msg) => write!(f, "Unknown kernel: {}", msg),
+            ConvolutionError::InvalidDimensions(msg) => write!(f, "Invalid dimensions: {}", msg),
+        }
+    }
+}
+
+impl std::error::Error for ConvolutionError {}
+
+impl From<ConvolutionError> for io::Error {
+    fn from(error: ConvolutionError) -> Self {
+        io::Error::new(io::ErrorKind::InvalidInput, error.to_string())
+    }
+}
+
+/// Weights of a convolution, applied as written with the center tap over the output
+/// pixel (correlation, the same orientation as the Sobel operator)
+#[derive(Debug, Clone, PartialEq)]
+pub enum Kernel {
+    /// A row kernel followed by a column kernel, equivalent to their outer product
+    Separable {
+        horizontal: Vec<f32>,
+        vertical: Vec<f32>,
+    },
+    /// A 2D kernel with row major weights
+    Full {
+        width: usize,
+        height: usize,
+        weights: Vec<f32>,
+    },
+}
+
+/// Names accepted by `Kernel::preset`
+pub const KERNEL_PRESETS: &[&str] = &[
+    "box",
+    "gaussian",
+    "laplacian",
+    "laplacian8",
+    "sobel_x",
+    "sobel_y",
+    "prewitt_x",
+    "prewitt_y",
+    "scharr_x",
+    "scharr_y",
+    "sharpen",
+    "emboss",
+];
+
+impl Kernel {
+    /// Create a separable kernel, both sides must have an odd number of taps
+    pub fn separable(horizontal: Vec<f32>, vertical: Vec<f32>) -> Result<Self, ConvolutionError> {
+        check_taps("horizontal", &horizontal)?;
+        check_taps("vertical", &vertical)?;
+
+        Ok(Kernel::Separable {
+            horizontal,
+            vertical,
+        })
+    }
+
+    /// Create a 2D kernel from its rows, every row must have the same odd length and
+    /// there must be an odd number of rows
+    pub fn matrix(rows: Vec<Vec<f32>>) -> Result<Self, ConvolutionError> {
+        let height = rows.len();
+        let width = rows.first().map(|row| row.len()).unwrap_or(0);
+
+        if rows.iter().any(|row| row.len() != width) {
+            return Err(ConvolutionError::InvalidKernel(
+                "every matrix row must have the same length".to_string(),
+            ));
+        }
+
+        let weights: Vec<f32> = rows.into_iter().flatten().collect();
+        check_size(width, height)?;
+        check_weights(&weights)?;
+
+        Ok(Kernel::Full {
+            width,
+            height,
+            weights,
+        })
+    }
+
+    /// Create one of the built in kernels, see `KERNEL_PRESETS`
+    ///
+    /// # Arguments
+    /// * `name` - The preset name
+    /// * `size` - Side length of the `box` kernel, 3 when unset
+    /// * `sigma` - Standard deviation of the `gaussian` kernel, 1.0 when unset
+    pub fn preset(
+        name: &str,
+        size: Option<usize>,
+        sigma: Option<f32>,
+    ) -> Result<Self, ConvolutionError> {
+        if size.is_some() && name != "box" {
+            return Err(ConvolutionError::InvalidKernel(format!(
+                "'size' only applies to the box kernel, not {}",
+                name
+            )));
+        }
+
+        if sigma.is_some() && name != "gaussian" {
+            return Err(ConvolutionError::InvalidKernel(format!(
+                "'sigma' only applies to the gaussian kernel, not {}",
+                name
+            )));
+        }
+
+        let derivative = vec![-1.0, 0.0, 1.0];
+
+        match name {
+            "box" => {
+                let size = size.unwrap_or(3);
+                Self::separable(vec![1.0; size], vec![1.0; size])
+            }
+            "gaussian" => {
+                let kernel = gaussian_kernel(sigma.unwrap_or(1.0))
+                    .map_err(|e| ConvolutionError::InvalidKernel(e.to_string()))?;
+                Self::separable(kernel.clone(), kernel)
+            }
+            "laplacian" => Self::matrix(vec![
+                vec![0.0, 1.0, 0.0],
+                vec![1.0, -4.0, 1.0],
+                vec![0.0, 1.0, 0.0],
+            ]),
+            "laplacian8" => Self::matrix(vec![
+                vec![1.0, 1.0, 1.0],
+                vec![1.0, -8.0, 1.0],
+                vec![1.0, 1.0, 1.0],
+            ]),
+            "sobel_x" => Self::separable(derivative, vec![1.0, 2.0, 1.0]),
+            "sobel_y" => Self::separable(vec![1.0, 2.0, 1.0], derivative),
+            "prewitt_x" => Self::separable(derivative, vec![1.0, 1.0, 1.0]),
+            "prewitt_y" => Self::separable(vec![1.0, 1.0, 1.0], derivative),
+            "scharr_x" => Self::separable(derivative, vec![3.0, 10.0, 3.0]),
+            "scharr_y" => Self::separable(vec![3.0, 10.0, 3.0], derivative),
+            "sharpen" => Self::matrix(vec![
+                vec![0.0, -1.0, 0.0],
+                vec![-1.0, 5.0, -1.0],
+                vec![0.0, -1.0, 0.0],
+            ]),
+            "emboss" => Self::matrix(vec![
+                vec![-2.0, -1.0, 0.0],
+                vec![-1.0, 1.0, 1.0],
+                vec![0.0, 1.0, 2.0],
+            ]),
+            _ => Err(ConvolutionError::UnknownKernel(format!(
+                "'{}', expected one of: {}, custom, separable",
+                name,
+                KERNEL_PRESETS.join(", ")
+            ))),
+        }
+    }
+
+    /// Sum of all weights, the response of the kernel to a flat image of ones
+    pub fn sum(&self) -> f32 {
+        match self {
+            Kernel::Separable {
+                horizontal,
+                vertical,
+            } => horizontal.iter().sum::<f32>() * vertical.iter().sum::<f32>(),
+            Kernel::Full { weights, .. } => weights.iter().sum(),
+        }
+    }
+
+    /// Whether every weight is a whole number and no sum of 8 bit pixels can leave the
+    /// i32 range, so sums can be accumulated exactly in integers
+    pub fn is_integer(&self) -> bool {
+        let integer = |weights: &[f32]| weights.iter().all(|w| w.fract() == 0.0);
+        // largest magnitude a pass can add up to, relative to the largest pixel
+        let gain = |weights: &[f32]| weights.iter().map(|w| w.abs() as f64).sum::<f64>();
+
+        let (whole, largest_sum) = match self {
+            Kernel::Separable {
+                horizontal,
+                vertical,
+            } => (
+                integer(horizontal) && integer(vertical),
+                255.0 * gain(horizontal) * gain(vertical),
+            ),
+            Kernel::Full { weights, .. } => (integer(weights), 255.0 * gain(weights)),
+        };
+
+        whole && largest_sum <= i32::MAX as f64
+    }
+}
+
+// a 1D kernel needs an odd, non zero number of finite taps
+fn check_taps(side: &str, taps: &[f32]) -> Result<(), ConvolutionError> {
+    if taps.len() % 2 == 0 {
+        return Err(ConvolutionError::InvalidKernel(format!(
+            "{} kernel must have an odd number of taps, got {}",
+            side,
+            taps.len()
+        )));
+    }
+
+    check_weights(taps)
+}
+
+fn check_size(width: usize, height: usize) -> Result<(), ConvolutionError> {
+    if width % 2 == 0 || height % 2 == 0 {
+        return Err(ConvolutionError::InvalidKernel(format!(
+            "kernel must have odd dimensions to have a center, got {}x{}",
+            width, height
+        )));
+    }
+
+    Ok(())
+}
+
+fn check_weights(weights: &[f32]) -> Result<(), ConvolutionError> {
+    if weights.iter().any(|w| !w.is_finite()) {
+        return Err(ConvolutionError::InvalidKernel(
+            "kernel weights must be finite numbers".to_string(),
+        ));
+    }
+
+    Ok(())
+}
+
+/// A `PipelineStep` applying a kernel to every channel of the frame
+pub struct Convolution {
+    /// Weights applied around every pixel
+    kernel: Kernel,
+    /// Multiplier applied to every sum
+    scale: f32,
+    /// Added to every sum after scaling
+    offset: f32,
+    /// Whether the magnitude of the scaled sum is used
+    absolute: bool,
+    /// How pixels outside the frame are read
+    border: BorderMode,
+}
+
+impl Convolution {
+    /// Create a convolution step that writes the raw kernel response
+    pub fn new(kernel: Kernel) -> Self {
+        Self {
+            kernel,
+            scale: 1.0,
+            offset: 0.0,
+            absolute: false,
+            border: BorderMode::default(),
+        }
+    }
+
+    /// Multiply every sum by `scale`, e.g. `1.0 / 9.0` for a 3x3 box of ones
+    pub fn with_scale(mut self, scale: f32) -> Self {
+        self.scale = scale;
+        self
+    }
+
+    /// Add `offset` to every scaled sum
+    pub fn with_offset(mut self, offset: f32) -> Self {
+        self.offset = offset;
+        self
+    }
+
+    /// Use the magnitude of signed responses instead of clamping them to zero
+    pub fn with_absolute(mut self, absolute: bool) -> Self {
+        self.absolute = absolute;
+        self
+    }
+
+    /// Read pixels outside the frame with the given border mode
+    pub fn with_border(mut self, border: BorderMode) -> Self {
+        self.border = border;
+        self
+    }
+
+    /// Create a convolution step from its pipeline description
+    pub fn from_config(config: ConvolutionConfig) -> Result<Self, ConvolutionError> {
+        let unused = |field: &str, present: bool| {
+            if present {
+                Err(ConvolutionError::InvalidKernel(format!(
+                    "'{}' does not apply to the {} kernel",
+                    field, config.kernel
+                )))
+            } else {
+                Ok(())
+            }
+        };
+
+        let kernel = match config.kernel.as_str() {
+            "custom" => {
+                unused("horizontal", config.horizontal.is_some())?;
+                unused("vertical", config.vertical.is_some())?;
+                let matrix = config.matrix.clone().ok_or_else(|| {
+                    ConvolutionError::InvalidKernel(
+                        "the custom kernel needs a 'matrix'".to_string(),
+                    )
+                })?;
+                Kernel::matrix(matrix)?
+            }
+            "separable" => {
+                unused("matrix", config.matrix.is_some())?;
+                match (config.horizontal.clone(), config.vertical.clone()) {
+                    (Some(horizontal), Some(vertical)) => Kernel::separable(horizontal, vertical)?,
+                    _ => {
+                        return Err(ConvolutionError::InvalidKernel(
+                            "the separable kernel needs 'horizontal' and 'vertical'".to_string(),
+                        ))
+                    }
+                }
+            }
+            name => {
+                unused("matrix", config.matrix.is_some())?;
+                unused("horizontal", config.horizontal.is_some())?;
+                unused("vertical", config.vertical.is_some())?;
+                Kernel::preset(name, config.size, config.sigma)?
+            }
+        };
+
+        // unnormalized box kernels are scaled to an average, derivative kernels
+        // report the edge strength regardless of its sign
+        let default_scale = match config.kernel.as_str() {
+            "box" => 1.0 / kernel.sum(),
+            _ => 1.0,
+        };
+        let default_absolute = matches!(
+            config.kernel.as_str(),
+            "laplacian"
+                | "laplacian8"
+                | "sobel_x"
+                | "sobel_y"
+                | "prewitt_x"
+                | "prewitt_y"
+                | "scharr_x"
+                | "scharr_y"
+        );
+
+        let scale = config.scale.unwrap_or(default_scale);
+        if !scale.is_finite() {
+            return Err(ConvolutionError::InvalidKernel(format!(
+                "scale must be a finite number, got {}",
+                scale
+            )));
+        }
+
+        Ok(Self::new(kernel)
+            .with_scale(scale)
+            .with_offset(config.offset.unwrap_or(0.0))
+            .with_absolute(config.absolute.unwrap_or(default_absolute))
+            .with_border(config.border))
+    }
+
+    /// Apply the kernel to a single channel plane
+    ///
+    /// # Arguments
+    /// * `input` - Row major 8 bit plane of `width * height` pixels
+    /// * `width` - Width of the plane in pixels
+    /// * `height` - Height of the plane in pixels
+    pub fn convolve_plane(
+        &self,
+        input: &[u8],
+        width: usize,
+        height: usize,
+    ) -> Result<Vec<u8>, ConvolutionError> {
+        if input.len() != width * height {
+            return Err(ConvolutionError::InvalidDimensions(format!(
+                "Input length {} does not match dimensions {}x{}",
+                input.len(),
+                width,
+                height
+            )));
+        }
+
+        // whole number kernels are summed exactly in integers, everything else in floats
+        let sums: Vec<f32> = if self.kernel.is_integer() {
+            self.correlate::<i32>(input, width, height, |w| w as i32)
+                .into_iter()
+                .map(|sum| sum as f32)
+                .collect()
+        } else {
+            self.correlate::<f32>(input, width, height, |w| w)
+        };
+
+        Ok(sums
+            .into_iter()
+            .map(|sum| {
+                let mut value = sum * self.scale;
+                if self.absolute {
+                    value = value.abs();
+                }
+                (value + self.offset).round().clamp(0.0, 255.0) as u8
+            })
+            .collect())
+    }
+
+    // run the kernel over the plane, accumulating in T
+    fn correlate<T>(
+        &self,
+        input: &[u8],
+        width: usize,
+        height: usize,
+        weight: impl Fn(f32) -> T,
+    ) -> Vec<T>
+    where
+        T: Copy + Default + From<u8> + Add<Output = T> + Mul<Output = T>,
+    {
+        let plane: Vec<T> = input.iter().map(|&v| T::from(v)).collect();
+        let constant = T::from(self.border.constant_value());
+        let to_weights = |weights: &[f32]| weights.iter().map(|&w| weight(w)).collect::<Vec<T>>();
+
+        match &self.kernel {
+            Kernel::Separable {
+                horizontal,
+                vertical,
+            } => {
+                let rows = correlate_plane(
+                    &plane,
+                    width,
+                    height,
+                    &to_weights(horizontal),
+                    horizontal.len(),
+                    self.border,
+                    constant,
+                );
+
+                // the intermediate rows are already filtered, so a constant border
+                // continues as the row kernel's response to the constant
+                let row_constant = to_weights(horizontal)
+                    .into_iter()
+                    .fold(T::default(), |sum, w| sum + w * constant);
+
+                correlate_plane(
+                    &rows,
+                    width,
+                    height,
+                    &to_weights(vertical),
+                    1,
+                    self.border,
+                    row_constant,
+                )
+            }
+            Kernel::Full {
+                width: kernel_width,
+                weights,
+                ..
+            } => correlate_plane(
+                &plane,
+                width,
+                height,
+                &to_weights(weights),
+                *kernel_width,
+                self.border,
+                constant,
+            ),
+        }
+    }
+}
+
+// correlate a plane with a row major kernel whose center sits over each output pixel
+fn correlate_plane<T>(
+    plane: &[T],
+    width: usize,
+    height: usize,
+    weights: &[T],
+    kernel_width: usize,
+    border: BorderMode,
+    constant: T,
+) -> Vec<T>
+where
+    T: Copy + Default + Add<Output = T> + Mul<Output = T>,
+{
+    let radius_x = kernel_width / 2;
+    let radius_y = weights.len() / kernel_width / 2;
+    let padded_width = width + 2 * radius_x;
+
+    // widen every row once with the border pixels on both sides
+    let mut padded = Vec::with_capacity(padded_width * height);
+    for row in plane.chunks_exact(width) {
+        for i in 0..padded_width {
+            padded.push(
+                match border.resolve(i as isize - radius_x as isize, width) {
+                    Some(x) => row[x],
+                    None => constant,
+                },
+            );
+        }
+    }
+    let constant_row = vec![constant; padded_width];
+
+    let mut output = vec![T::default(); width * height];
+    for (y, dst_row) in output.chunks_exact_mut(width).enumerate() {
+        for (ky, kernel_row) in weights.chunks_exact(kernel_width).enumerate() {
+            let src_row = match border.resolve((y + ky) as isize - radius_y as isize, height) {
+                Some(src_y) => &padded[src_y * padded_width..(src_y + 1) * padded_width],
+                None => &constant_row[..],
+            };
+
+            for (x, dst) in dst_row.iter_mut().enumerate() {
+                let mut sum = *dst;
+                for (kx, &w) in kernel_row.iter().enumerate() {
+                    sum = sum + src_row[x + kx] * w;
+                }
+                *dst = sum;
+            }
+        }
+    }
+
+    output
+}
+
+impl PipelineStep for Convolution {
+    fn process(&self, frame: &mut Frame, _frame_count: u64) -> io::Result<()> {
+        if frame.width() <= 0 || frame.height() <= 0 {
+            return Err(ConvolutionError::InvalidDimensions(format!(
+                "Invalid frame dimensions: {}x{}",
+                frame.width(),
+                frame.height()
+            ))
+            .into());
+        }
+
+        // kernels run on 8 bit samples, deeper and planar frames are converted first
+        frame.to_packed_u8();
+
+        let width = frame.width() as usize;
+        let height = frame.height() as usize;
+        let channels = frame.channels() as usize;
+
+        if frame.data().len() != width * height * channels {
+            return Err(ConvolutionError::InvalidDimensions(format!(
+                "Frame data length {} does not match {}x{}x{}",
+                frame.data().len(),
+                width,
+                height,
+                channels
+            ))
+            .into());
+        }
+
+        // filter every colour channel of the interleaved frame on its own, alpha is the
+        // last channel and passes through unchanged
+        let color_channels = if frame.format().has_alpha() {
+            channels - 1
+        } else {
+            channels
+        };
+        for channel in 0..color_channels {
+            let plane: Vec<u8> = frame
+                .data()
+                .iter()
+                .skip(channel)
+                .step_by(channels)
+                .copied()
+                .collect();
+            let filtered = self.convolve_plane(&plane, width, height)?;
+
+            for (dst, value) in frame
+                .data_mut()
+                .iter_mut()
+                .skip(channel)
+                .step_by(channels)
+                .zip(filtered)
+            {
+                *dst = value;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn name(&self) -> &str {
+        "Convolution"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::frame::PixelFormat;
+
+    #[test]
+    fn large_integer_kernels_fall_back_to_floats() {
+        // 255 * 12288 * 12288 is far beyond i32::MAX
+        let kernel = Kernel::separable(vec![4096.0; 3], vec![4096.0; 3]).unwrap();
+        assert!(!kernel.is_integer());
+
+        let convolution = Convolution::new(kernel);
+        let output = convolution.convolve_plane(&[255; 25], 5, 5).unwrap();
+        assert!(output.iter().all(|&value| value == 255));
+
+        let full = Kernel::matrix(vec![vec![i32::MAX as f32; 3]; 3]).unwrap();
+        assert!(!full.is_integer());
+    }
+
+    #[test]
+    fn integer_sums_match_float_sums() {
+        let input: Vec<u8> = (0..63u32).map(|i| (i * 37 % 256) as u8).collect();
+        let borders = [
+            BorderMode::Constant(200),
+            BorderMode::Replicate,
+            BorderMode::Reflect,
+            BorderMode::Wrap,
+        ];
+
+        for name in [
+            "sobel_x",
+            "sobel_y",
+            "scharr_x",
+            "laplacian",
+            "sharpen",
+            "emboss",
+        ] {
+            let kernel = Kernel::preset(name, None, None).unwrap();
+            assert!(kernel.is_integer(), "{} has whole number weights", name);
+
+            for border in borders {
+                let convolution = Convolution::new(kernel.clone()).with_border(border);
+                let integer = convolution.correlate::<i32>(&input, 9, 7, |w| w as i32);
+                let float = convolution.correlate::<f32>(&input, 9, 7, |w| w);
+
+                for (integer, float) in integer.into_iter().zip(float) {
+                    assert_eq!(integer as f32, float, "{} with {:?}", name, border);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn sobel_of_a_ramp() {
+        let kernel = Kernel::preset("sobel_x", None, None).unwrap();
+
+        // a horizontal ramp has the same gradient everywhere away from the border
+        let input: Vec<u8> = (0..49).map(|i| (i % 7 * 10) as u8).collect();
+        let output = Convolution::new(kernel)
+            .with_border(BorderMode::Replicate)
+            .convolve_plane(&input, 7, 7)
+            .unwrap();
+        for y in 0..7 {
+            for x in 1..6 {
+                assert_eq!(output[y * 7 + x], 80);
+            }
+        }
+    }
+
+    #[test]
+    fn alpha_passes_through_unchanged() {
+        let config: ConvolutionConfig = serde_json::from_value(serde_json::json!({
+            "kernel": "sobel_x",
+        }))
+        .unwrap();
+        let convolution = Convolution::from_config(config).unwrap();
+
+        // a horizontal ramp in red, flat green and blue, opaque alpha
+        let data: Vec<u8> = (0..25)
+            .flat_map(|i| [(i % 5 * 10) as u8, 50, 50, 255])
+            .collect();
+        let mut frame = Frame::new(data, 5, 5, PixelFormat::Rgba).unwrap();
+        convolution.process(&mut frame, 0).unwrap();
+
+        for pixel in frame.data().chunks_exact(4) {
+            assert_eq!(pixel[1..], [0, 0, 255]);
+        }
+        assert_eq!(frame.data()[2 * 4..2 * 4 + 4], [80, 0, 0, 255]);
+    }
+}
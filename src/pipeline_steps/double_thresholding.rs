@@ -1,12 +1,82 @@
-use crate::video_pipeline::Frame;
+use super::auto_threshold::{self, AutoThreshold, ThresholdMethod};
+use super::gradient_calculation::PixelGradient;
+use crate::frame::{Frame, Image};
+use crate::frame_pipeline::{FrameContext, PipelineStep};
+use serde::{Deserialize, Serialize};
+use std::io;
 
-#[derive(PartialEq)]
+/// Parameters for a `double_threshold` step in a pipeline description file
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DoubleThresholdConfig {
+    pub low: i32,
+    pub high: i32,
+    /// Derive the thresholds from the gradients of an earlier `gradient` step instead of
+    /// using `low` and `high`, which remain the fallback for frames without gradients
+    #[serde(default)]
+    pub auto: Option<ThresholdMethod>,
+    #[serde(default = "DoubleThresholdConfig::default_median_sigma")]
+    pub median_sigma: f32,
+    #[serde(default = "DoubleThresholdConfig::default_otsu_ratio")]
+    pub otsu_ratio: f32,
+    /// Weight of the previous frame's thresholds in auto mode, 0 disables smoothing.
+    /// Smoothing needs the frames in order and therefore a single job.
+    #[serde(default)]
+    pub smoothing: f32,
+}
+
+impl DoubleThresholdConfig {
+    fn default_median_sigma() -> f32 {
+        auto_threshold::DEFAULT_SIGMA
+    }
+
+    fn default_otsu_ratio() -> f32 {
+        auto_threshold::DEFAULT_LOW_RATIO
+    }
+}
+
+/// Thresholds outside of `0 <= low <= high <= 255`
+#[derive(Debug)]
+pub struct ThresholdError {
+    pub low: i32,
+    pub high: i32,
+}
+
+impl std::fmt::Display for ThresholdError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Invalid thresholds low {} high {}, must satisfy 0 <= low <= high <= 255",
+            self.low, self.high
+        )
+    }
+}
+
+impl std::error::Error for ThresholdError {}
+
+impl From<ThresholdError> for io::Error {
+    fn from(error: ThresholdError) -> Self {
+        io::Error::new(io::ErrorKind::InvalidInput, error.to_string())
+    }
+}
+
+/// Check a low and high threshold for the magnitudes of 8 bit edges
+pub fn check_thresholds(low: i32, high: i32) -> Result<(), ThresholdError> {
+    if low < 0 || low > high || high > 255 {
+        return Err(ThresholdError { low, high });
+    }
+
+    Ok(())
+}
+
+#[derive(Clone, Copy, PartialEq)]
 pub enum Strength {
     Strong,
     Weak,
     Suppressed,
 }
 
+#[derive(Clone, Copy)]
 pub struct MeasuredPixel {
     pub weight: Strength,
     pub value: i32,
@@ -22,40 +92,234 @@ impl DoubleThresholder {
         Self { min, max }
     }
 
-    pub fn threshold(&self, frame: &Frame) -> Vec<Vec<MeasuredPixel>> {
-        let mut result = Vec::with_capacity(frame.height as usize);
+    /// Classify every magnitude as a strong edge, a weak edge or suppressed
+    pub fn threshold(&self, magnitudes: &Image<u8>) -> Image<MeasuredPixel> {
+        magnitudes.map(|value| self.classify(value))
+    }
+
+    /// Classify a single magnitude
+    pub fn classify(&self, value: u8) -> MeasuredPixel {
+        let pixel = value as i32;
+
+        // Determine strength based on thresholds
+        let weight = if pixel <= self.min {
+            Strength::Suppressed
+        } else if pixel >= self.max {
+            Strength::Strong
+        } else {
+            Strength::Weak
+        };
+
+        MeasuredPixel {
+            weight,
+            value: pixel,
+        }
+    }
+}
+
+/// Pipeline step classifying the magnitudes of the frame as strong or weak edges. The
+/// classification is handed to a later `hysteresis` step through the frame context, the
+/// frame shows strong edges as 255 and weak ones as 128.
+///
+/// In auto mode the thresholds are picked from the gradients an earlier `gradient` step
+/// left in the frame context, the same way the `canny` step picks them, and reported in
+/// the results of the frame.
+pub struct DoubleThreshold {
+    thresholder: DoubleThresholder,
+    auto_threshold: Option<AutoThreshold>,
+}
+
+/// The thresholds picked for a frame in auto mode, attached to the frame context
+#[derive(Debug, Serialize)]
+struct DoubleThresholdResult {
+    low_threshold: i32,
+    high_threshold: i32,
+}
+
+impl DoubleThreshold {
+    /// Create a thresholding step
+    ///
+    /// # Arguments
+    /// * `low` - Magnitudes up to this value are suppressed
+    /// * `high` - Magnitudes from this value on are strong edges
+    pub fn new(low: i32, high: i32) -> Result<Self, ThresholdError> {
+        check_thresholds(low, high)?;
+
+        Ok(Self {
+            thresholder: DoubleThresholder::new(low, high),
+            auto_threshold: None,
+        })
+    }
+
+    /// Pick the thresholds of every frame from its gradients, `low` and `high` are
+    /// used for frames without gradients
+    pub fn with_auto_threshold(mut self, auto_threshold: AutoThreshold) -> Self {
+        self.auto_threshold = Some(auto_threshold);
+        self
+    }
 
-        for y in 0..frame.height {
-            let mut row = Vec::with_capacity(frame.width as usize);
+    pub fn from_config(config: DoubleThresholdConfig) -> io::Result<Self> {
+        let mut step = Self::new(config.low, config.high)?;
 
-            for x in 0..frame.width {
-                // Get pixel value - we'll use the first channel since we expect grayscale
-                let pixel = frame
-                    .get_pixel(x, y)
-                    .map(|(v, _, _)| v) // Take first channel value
-                    .unwrap_or(0); // Default to 0 if out of bounds
+        if let Some(method) = config.auto {
+            step = step.with_auto_threshold(AutoThreshold::from_params(
+                method,
+                config.median_sigma,
+                config.otsu_ratio,
+                config.smoothing,
+            )?);
+        }
 
-                // Determine strength based on thresholds
-                let weight = if pixel <= self.min {
-                    Strength::Suppressed
-                } else if pixel >= self.max {
-                    Strength::Strong
-                } else {
-                    Strength::Weak
-                };
+        Ok(step)
+    }
+}
 
-                // Create measured pixel
-                let measured = MeasuredPixel {
-                    weight,
-                    value: pixel,
-                };
+impl PipelineStep for DoubleThreshold {
+    fn process(&self, frame: &mut Frame, frame_count: u64) -> io::Result<()> {
+        self.process_with_context(frame, &mut FrameContext::new(frame_count))
+    }
+
+    fn process_with_context(
+        &self,
+        frame: &mut Frame,
+        context: &mut FrameContext,
+    ) -> io::Result<()> {
+        let measured = match &self.auto_threshold {
+            Some(auto) => {
+                let gradients = context.data::<Image<PixelGradient>>().ok_or_else(|| {
+                    io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        "Automatic thresholds need the gradients of an earlier gradient step",
+                    )
+                })?;
+                let (low, high) = auto
+                    .thresholds(gradients)
+                    .unwrap_or((self.thresholder.min, self.thresholder.max));
+                context.insert(&DoubleThresholdResult {
+                    low_threshold: low,
+                    high_threshold: high,
+                })?;
 
-                row.push(measured);
+                let thresholder = DoubleThresholder::new(low, high);
+                frame.map_gray(|value| thresholder.classify(value))
             }
+            None => frame.map_gray(|value| self.thresholder.classify(value)),
+        };
+
+        *frame = measured
+            .map(|pixel| match pixel.weight {
+                Strength::Strong => 255,
+                Strength::Weak => 128,
+                Strength::Suppressed => 0,
+            })
+            .into();
+        context.insert_data(measured);
+
+        Ok(())
+    }
+
+    fn is_stateful(&self) -> bool {
+        self.auto_threshold
+            .as_ref()
+            .is_some_and(|auto| auto.is_smoothed())
+    }
+
+    fn name(&self) -> &str {
+        "DoubleThreshold"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // magnitudes 0, 20, .., 200 along a single row
+    fn ramp() -> Image<u8> {
+        Image::from_fn(11, 1, |x, _| (x * 20) as u8)
+    }
+
+    fn classified(frame: &Frame) -> Vec<u8> {
+        frame.map_gray(|value| value).pixels().collect()
+    }
 
-            result.push(row);
+    #[test]
+    fn invalid_thresholds_are_rejected() {
+        for (low, high) in [(-1, 40), (50, 40), (10, 256)] {
+            let error = DoubleThreshold::new(low, high).err().unwrap();
+            assert_eq!((error.low, error.high), (low, high));
         }
+        assert!(DoubleThreshold::new(40, 40).is_ok());
+    }
+
+    #[test]
+    fn fixed_thresholds_classify_the_frame() {
+        let step = DoubleThreshold::new(40, 100).unwrap();
+        let mut frame: Frame = ramp().into();
+        let mut context = FrameContext::new(0);
+
+        step.process_with_context(&mut frame, &mut context).unwrap();
+
+        assert_eq!(
+            classified(&frame),
+            [0, 0, 0, 128, 128, 255, 255, 255, 255, 255, 255]
+        );
+        assert!(context.data::<Image<MeasuredPixel>>().is_some());
+        assert!(context.is_empty());
+    }
+
+    #[test]
+    fn auto_thresholds_come_from_the_context_gradients() {
+        let config: DoubleThresholdConfig = serde_json::from_value(serde_json::json!({
+            "low": 0,
+            "high": 255,
+            "auto": "median",
+            "median_sigma": 0.5,
+        }))
+        .unwrap();
+        let step = DoubleThreshold::from_config(config).unwrap();
+        assert!(!step.is_stateful());
+
+        // every gradient has a magnitude of 80, the thresholds are 40 and 120
+        let gradients = Image::from_fn(11, 1, |_, _| PixelGradient::new(80.0, 0.0));
+        let mut frame: Frame = ramp().into();
+        let mut context = FrameContext::new(0);
+        context.set_step(1, step.name());
+        context.insert_data(gradients);
+
+        step.process_with_context(&mut frame, &mut context).unwrap();
+
+        assert_eq!(
+            classified(&frame),
+            [0, 0, 0, 128, 128, 128, 255, 255, 255, 255, 255]
+        );
+        assert_eq!(
+            context.get(1, "DoubleThreshold").unwrap(),
+            &serde_json::json!({ "low_threshold": 40, "high_threshold": 120 })
+        );
+    }
+
+    #[test]
+    fn auto_thresholds_need_gradients() {
+        let step = DoubleThreshold::new(10, 40)
+            .unwrap()
+            .with_auto_threshold(AutoThreshold::new(ThresholdMethod::Otsu));
+        let mut frame: Frame = ramp().into();
+
+        let error = step
+            .process_with_context(&mut frame, &mut FrameContext::new(0))
+            .unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn smoothed_auto_thresholds_are_stateful() {
+        let auto = AutoThreshold::new(ThresholdMethod::Median)
+            .with_smoothing(0.5)
+            .unwrap();
+        let step = DoubleThreshold::new(10, 40)
+            .unwrap()
+            .with_auto_threshold(auto);
 
-        result
+        assert!(step.is_stateful());
     }
 }
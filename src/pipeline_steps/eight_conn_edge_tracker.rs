@@ -1,38 +1,83 @@
-use crate::video_pipeline::Frame;
+use crate::frame::{Frame, Image};
+use crate::frame_pipeline::{FrameContext, PipelineStep};
+use serde::Deserialize;
 use std::collections::VecDeque;
+use std::io;
 
 use super::double_thresholding::{MeasuredPixel, Strength};
 
-pub fn eight_conn_edge_tracker_hysteris(mut pixels: Vec<Vec<MeasuredPixel>>) -> Frame {
-    let height = pixels.len() as i32;
-    let width = pixels[0].len() as i32;
-    let mut output_data = vec![0u8; (width * height) as usize];
-    let mut visited = vec![vec![false; width as usize]; height as usize];
+/// Which neighbours of a pixel count as connected, written as `4` or `8` in a
+/// pipeline description file
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(try_from = "u8")]
+pub enum Connectivity {
+    /// Left, right, above and below
+    Four,
+    /// The four direct neighbours and the four diagonals
+    #[default]
+    Eight,
+}
+
+impl TryFrom<u8> for Connectivity {
+    type Error = String;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            4 => Ok(Connectivity::Four),
+            8 => Ok(Connectivity::Eight),
+            other => Err(format!("Invalid connectivity {}, must be 4 or 8", other)),
+        }
+    }
+}
+
+impl Connectivity {
+    /// Offsets of the connected neighbours of a pixel
+    pub fn neighbors(&self) -> &'static [(i32, i32)] {
+        match self {
+            Connectivity::Four => &[(0, -1), (-1, 0), (1, 0), (0, 1)],
+            Connectivity::Eight => &[
+                (-1, -1),
+                (0, -1),
+                (1, -1),
+                (-1, 0),
+                (1, 0),
+                (-1, 1),
+                (0, 1),
+                (1, 1),
+            ],
+        }
+    }
+}
+
+/// Keep the strong edges and the weak edges connected to them
+///
+/// # Arguments
+/// * `pixels` - The thresholded magnitudes
+/// * `connectivity` - Which neighbours a weak edge can be connected through
+pub fn edge_tracker_hysteresis(
+    pixels: &Image<MeasuredPixel>,
+    connectivity: Connectivity,
+) -> Image<u8> {
+    let height = pixels.height() as i32;
+    let width = pixels.width() as i32;
+    let mut output = Image::new(width as usize, height as usize, 0u8);
+    let mut visited = Image::new(width as usize, height as usize, false);
     let mut queue = VecDeque::new();
 
     // First pass: Add all strong pixels to queue and mark them with their original value
     for y in 0..height {
         for x in 0..width {
-            if pixels[y as usize][x as usize].weight == Strength::Strong {
+            let position = (x as usize, y as usize);
+            if pixels[position].weight == Strength::Strong {
                 queue.push_back((x, y));
-                visited[y as usize][x as usize] = true;
-                let idx = (y * width + x) as usize;
+                visited[position] = true;
                 // Use original pixel value for strong pixels
-                output_data[idx] = pixels[y as usize][x as usize].value as u8;
+                output[position] = pixels[position].value as u8;
             }
         }
     }
 
-    let neighbors = [
-        (-1, -1),
-        (0, -1),
-        (1, -1),
-        (-1, 0),
-        (1, 0),
-        (-1, 1),
-        (0, 1),
-        (1, 1),
-    ];
+    let neighbors = connectivity.neighbors();
 
     while let Some((x, y)) = queue.pop_front() {
         for (dx, dy) in neighbors.iter() {
@@ -43,33 +88,132 @@ pub fn eight_conn_edge_tracker_hysteris(mut pixels: Vec<Vec<MeasuredPixel>>) ->
                 continue;
             }
 
-            if visited[new_y as usize][new_x as usize] {
+            let position = (new_x as usize, new_y as usize);
+            if visited[position] {
                 continue;
             }
 
-            let pixel = &pixels[new_y as usize][new_x as usize];
+            let pixel = pixels[position];
             if pixel.weight == Strength::Weak {
-                visited[new_y as usize][new_x as usize] = true;
+                visited[position] = true;
                 queue.push_back((new_x, new_y));
 
-                let idx = (new_y * width + new_x) as usize;
                 // Use the original pixel value for weak pixels
-                output_data[idx] = pixel.value as u8;
+                output[position] = pixel.value as u8;
             }
         }
     }
 
-    // We can drop pixels row by row as we process them
-    for row in pixels.drain(..) {
-        drop(row);
+    output
+}
+
+/// Parameters for a `hysteresis` step in a pipeline description file
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct HysteresisConfig {
+    /// `4` or `8`
+    #[serde(default)]
+    pub connectivity: Connectivity,
+    /// Write edges as 255 instead of their magnitude
+    #[serde(default)]
+    pub binary: bool,
+}
+
+/// Pipeline step tracing the edges classified by a `DoubleThreshold` step earlier in the
+/// pipeline. The frame is replaced by the edges.
+pub struct Hysteresis {
+    connectivity: Connectivity,
+    /// Write edges as 255 instead of their magnitude
+    binary: bool,
+}
+
+impl Hysteresis {
+    pub fn new(connectivity: Connectivity, binary: bool) -> Self {
+        Self {
+            connectivity,
+            binary,
+        }
+    }
+
+    pub fn from_config(config: HysteresisConfig) -> Self {
+        Self::new(config.connectivity, config.binary)
+    }
+}
+
+impl PipelineStep for Hysteresis {
+    fn process(&self, frame: &mut Frame, frame_count: u64) -> io::Result<()> {
+        self.process_with_context(frame, &mut FrameContext::new(frame_count))
+    }
+
+    fn process_with_context(
+        &self,
+        frame: &mut Frame,
+        context: &mut FrameContext,
+    ) -> io::Result<()> {
+        let measured = context.take_data::<Image<MeasuredPixel>>().ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Hysteresis needs the thresholded pixels of an earlier double_threshold step",
+            )
+        })?;
+
+        let edges = edge_tracker_hysteresis(&measured, self.connectivity);
+        *frame = if self.binary {
+            edges.map(|value| if value > 0 { 255 } else { 0 })
+        } else {
+            edges
+        }
+        .into();
+
+        Ok(())
+    }
+
+    fn name(&self) -> &str {
+        "Hysteresis"
     }
-    // Explicitly drop the outer vector
-    drop(pixels);
-
-    Frame {
-        data: output_data,
-        width,
-        height,
-        channels: 1,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 'S' marks a strong pixel, 'w' a weak one, anything else is suppressed
+    fn measured(rows: &[&str]) -> Image<MeasuredPixel> {
+        Image::from_fn(rows[0].len(), rows.len(), |x, y| {
+            let (weight, value) = match rows[y].as_bytes()[x] {
+                b'S' => (Strength::Strong, 200),
+                b'w' => (Strength::Weak, 50),
+                _ => (Strength::Suppressed, 0),
+            };
+            MeasuredPixel { weight, value }
+        })
+    }
+
+    #[test]
+    fn diagonal_weak_edges_need_eight_connectivity() {
+        let pixels = measured(&["S..", ".w.", "..w"]);
+
+        let four = edge_tracker_hysteresis(&pixels, Connectivity::Four);
+        assert_eq!(four.into_vec(), [200, 0, 0, 0, 0, 0, 0, 0, 0]);
+
+        let eight = edge_tracker_hysteresis(&pixels, Connectivity::Eight);
+        assert_eq!(eight.into_vec(), [200, 0, 0, 0, 50, 0, 0, 0, 50]);
+    }
+
+    #[test]
+    fn weak_edges_follow_a_chain_to_a_strong_edge() {
+        let pixels = measured(&["Swww.w", "......", "ww...."]);
+
+        let edges = edge_tracker_hysteresis(&pixels, Connectivity::Four);
+        assert_eq!(edges.row(0), [200, 50, 50, 50, 0, 0]);
+        // weak pixels without a path to a strong one are dropped
+        assert!(edges.row(2).iter().all(|&value| value == 0));
+    }
+
+    #[test]
+    fn connectivity_parses_from_4_or_8() {
+        assert_eq!(Connectivity::try_from(4), Ok(Connectivity::Four));
+        assert_eq!(Connectivity::try_from(8), Ok(Connectivity::Eight));
+        assert!(Connectivity::try_from(6).is_err());
     }
 }
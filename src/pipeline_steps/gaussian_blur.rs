@@ -1,7 +1,22 @@
+use super::border::BorderMode;
 use crate::frame_pipeline::PipelineStep;
-use crate::video_pipeline::Frame;
+use crate::frame::Frame;
 
+use serde::Deserialize;
 use std::io;
+use wide::f32x8;
+
+// number of pixels processed per vector
+const LANES: usize = 8;
+
+/// Parameters for a `gaussian_blur` step in a pipeline description file
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct GaussianBlurConfig {
+    pub sigma: f32,
+    #[serde(default)]
+    pub border: BorderMode,
+}
 
 #[derive(Debug)]
 pub enum BlurError {
@@ -34,6 +49,8 @@ pub struct GaussianBlur {
     output_dir: String,
     kernel: Vec<f32>,
     radius: usize,
+    /// How taps outside the frame are read
+    border: BorderMode,
 }
 
 impl GaussianBlur {
@@ -43,49 +60,33 @@ impl GaussianBlur {
     /// * `output_dir` - The directory to store debug output and intermediate results
     /// * `sigma` - The standard deviation of the Gaussian kernel (determines how smooth the blur is)
     pub fn new(output_dir: &str, sigma: f32) -> Result<Self, BlurError> {
-        // calculate the kernel radius
-        let radius = (3.0 * sigma).ceil() as usize;
-        let size = 2 * radius + 1;
-        let mut kernel = Vec::with_capacity(size);
-
-        // Calculate kernel values
-        let two_sigma_sq = 2.0 * sigma * sigma;
-        let mut sum = 0.0;
-
-        for i in 0..size {
-            let x = (i as i32 - radius as i32) as f32;
-            let g = (-x * x / two_sigma_sq).exp();
-            kernel.push(g);
-            sum += g;
-        }
-
-        // Check for numerical stability
-        if sum.abs() < f32::EPSILON {
-            return Err(BlurError::ProcessingError(
-                "Kernel sum too close to zero".to_string(),
-            ));
-        }
+        Self::with_border(output_dir, sigma, BorderMode::default())
+    }
 
-        // Normalize kernel
-        for val in kernel.iter_mut() {
-            *val /= sum;
-        }
+    /// Create a new GaussianBlur step with an explicit border mode
+    ///
+    /// # Arguments
+    /// * `output_dir` - The directory to store debug output and intermediate results
+    /// * `sigma` - The standard deviation of the Gaussian kernel (determines how smooth the blur is)
+    /// * `border` - How pixels outside the frame are read
+    pub fn with_border(
+        output_dir: &str,
+        sigma: f32,
+        border: BorderMode,
+    ) -> Result<Self, BlurError> {
+        let kernel = gaussian_kernel(sigma)?;
+        let radius = kernel.len() / 2;
 
         Ok(Self {
             output_dir: output_dir.to_string(),
             kernel,
             radius,
+            border,
         })
     }
 
-    #[inline(always)]
-    fn horizontal_pass(
-        &self,
-        input: &[u8],
-        output: &mut [u8],
-        width: usize,
-        height: usize,
-    ) -> Result<(), BlurError> {
+    // check a plane matches the dimensions it is processed with
+    fn check_dimensions(input: &[u8], width: usize, height: usize) -> Result<(), BlurError> {
         if input.len() != width * height {
             return Err(BlurError::InvalidDimensions(format!(
                 "Input length {} does not match dimensions {}x{}",
@@ -95,6 +96,44 @@ impl GaussianBlur {
             )));
         }
 
+        Ok(())
+    }
+
+    /// Blur a single channel plane with the separable kernel
+    ///
+    /// # Arguments
+    /// * `input` - Row major 8 bit plane of `width * height` pixels
+    /// * `width` - Width of the plane in pixels
+    /// * `height` - Height of the plane in pixels
+    pub fn blur_plane(
+        &self,
+        input: &[u8],
+        width: usize,
+        height: usize,
+    ) -> Result<Vec<u8>, BlurError> {
+        let mut temp = vec![0u8; width * height];
+        let mut output = vec![0u8; width * height];
+
+        self.horizontal_pass(input, &mut temp, width, height)?;
+        self.vertical_pass(&temp, &mut output, width, height)?;
+
+        Ok(output)
+    }
+
+    /// Blur a plane with the scalar passes the step used before they were vectorized,
+    /// the reference the vectorized passes are tested and benchmarked against.
+    ///
+    /// Ignores the border mode: taps before the first pixel of a row or column repeat
+    /// it, taps past the last pixel are left out.
+    pub fn blur_plane_scalar(
+        &self,
+        input: &[u8],
+        width: usize,
+        height: usize,
+    ) -> Result<Vec<u8>, BlurError> {
+        Self::check_dimensions(input, width, height)?;
+
+        let mut temp = vec![0u8; width * height];
         for y in 0..height {
             let row = y * width;
             for x in 0..width {
@@ -106,13 +145,79 @@ impl GaussianBlur {
                     }
                     sum += input[row + src_x] as f32 * k;
                 }
-                output[row + x] = sum.clamp(0.0, 255.0) as u8;
+                temp[row + x] = sum.clamp(0.0, 255.0) as u8;
+            }
+        }
+
+        let mut output = vec![0u8; width * height];
+        for x in 0..width {
+            for y in 0..height {
+                let mut sum = 0.0;
+                for (i, &k) in self.kernel.iter().enumerate() {
+                    let src_y = y.saturating_add(i).saturating_sub(self.radius);
+                    if src_y >= height {
+                        continue;
+                    }
+                    sum += temp[src_y * width + x] as f32 * k;
+                }
+                output[y * width + x] = sum.clamp(0.0, 255.0) as u8;
+            }
+        }
+
+        Ok(output)
+    }
+
+    // horizontal pass over eight output pixels at a time.
+    //
+    // each row is widened to f32 once with the border pixels on both sides, so the
+    // inner loop needs no bounds checks. every lane accumulates the taps in kernel
+    // order without fused multiply add, so the result is bit for bit the scalar result.
+    #[inline(always)]
+    fn horizontal_pass(
+        &self,
+        input: &[u8],
+        output: &mut [u8],
+        width: usize,
+        height: usize,
+    ) -> Result<(), BlurError> {
+        Self::check_dimensions(input, width, height)?;
+
+        let radius = self.radius;
+        let lanes = (width + LANES - 1) / LANES * LANES;
+        let mut padded = vec![0.0f32; lanes + 2 * radius];
+        let kernel: Vec<f32x8> = self.kernel.iter().map(|&k| f32x8::splat(k)).collect();
+        let constant = self.border.constant_value() as f32;
+
+        for (src_row, dst_row) in input
+            .chunks_exact(width)
+            .zip(output.chunks_exact_mut(width))
+        {
+            for (i, dst) in padded[..width + 2 * radius].iter_mut().enumerate() {
+                *dst = match self.border.resolve(i as isize - radius as isize, width) {
+                    Some(src_x) => src_row[src_x] as f32,
+                    None => constant,
+                };
+            }
+
+            for x in (0..width).step_by(LANES) {
+                let mut sum = f32x8::ZERO;
+                for (i, &k) in kernel.iter().enumerate() {
+                    sum += load_f32x8(&padded[x + i..]) * k;
+                }
+
+                store_u8x8(sum, &mut dst_row[x..]);
             }
         }
 
         Ok(())
     }
 
+    // vertical pass walking the image row by row.
+    //
+    // each output row is the weighted sum of the 2 * radius + 1 input rows around
+    // it, accumulated eight columns at a time so memory is read sequentially
+    // instead of column by column. rows outside the image are picked by the border
+    // mode, in constant mode they are a row filled with the constant.
     #[inline(always)]
     fn vertical_pass(
         &self,
@@ -121,26 +226,44 @@ impl GaussianBlur {
         width: usize,
         height: usize,
     ) -> Result<(), BlurError> {
-        if input.len() != width * height {
-            return Err(BlurError::InvalidDimensions(format!(
-                "Input length {} does not match dimensions {}x{}",
-                input.len(),
-                width,
-                height
-            )));
-        }
+        Self::check_dimensions(input, width, height)?;
 
-        for x in 0..width {
-            for y in 0..height {
+        let constant_row = vec![self.border.constant_value(); width];
+
+        // rows feeding the current output row, paired with their kernel weight
+        let mut taps: Vec<(&[u8], f32)> = Vec::with_capacity(self.kernel.len());
+
+        for (y, dst_row) in output.chunks_exact_mut(width).enumerate() {
+            taps.clear();
+            for (i, &k) in self.kernel.iter().enumerate() {
+                let row = match self
+                    .border
+                    .resolve((y + i) as isize - self.radius as isize, height)
+                {
+                    Some(src_y) => &input[src_y * width..(src_y + 1) * width],
+                    None => &constant_row[..],
+                };
+                taps.push((row, k));
+            }
+
+            let mut x = 0;
+            while x + LANES <= width {
+                let mut sum = f32x8::ZERO;
+                for &(row, k) in taps.iter() {
+                    sum += widen_u8x8(&row[x..x + LANES]) * f32x8::splat(k);
+                }
+
+                store_u8x8(sum, &mut dst_row[x..]);
+                x += LANES;
+            }
+
+            // remaining columns of rows that are not a multiple of eight wide
+            for x in x..width {
                 let mut sum = 0.0;
-                for (i, &k) in self.kernel.iter().enumerate() {
-                    let src_y = y.saturating_add(i).saturating_sub(self.radius);
-                    if src_y >= height {
-                        continue;
-                    }
-                    sum += input[src_y * width + x] as f32 * k;
+                for &(row, k) in taps.iter() {
+                    sum += row[x] as f32 * k;
                 }
-                output[y * width + x] = sum.clamp(0.0, 255.0) as u8;
+                dst_row[x] = sum.clamp(0.0, 255.0) as u8;
             }
         }
 
@@ -149,41 +272,32 @@ impl GaussianBlur {
 }
 
 impl PipelineStep for GaussianBlur {
-    fn process(&self, frame: &mut Frame, _frame_count: u32) -> io::Result<()> {
+    fn process(&self, frame: &mut Frame, _frame_count: u64) -> io::Result<()> {
         // Input validation
-        if frame.width <= 0 || frame.height <= 0 {
+        if frame.width() <= 0 || frame.height() <= 0 {
             return Err(BlurError::InvalidDimensions(format!(
                 "Invalid frame dimensions: {}x{}",
-                frame.width, frame.height
+                frame.width(),
+                frame.height()
             ))
             .into());
         }
 
-        if frame.data.is_empty() {
+        if frame.data().is_empty() {
             return Err(BlurError::EmptyInput("Frame data is empty".to_string()).into());
         }
 
         // Convert to grayscale
         frame.to_grayscale();
 
-        let width = frame.width as usize;
-        let height = frame.height as usize;
-
-        // Create temporary buffers that will be dropped at end of scope
-        let mut temp = vec![0u8; width * height];
-
-        // Process horizontal pass
-        self.horizontal_pass(&frame.data, &mut temp, width, height)
-            .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
-
-        // Update frame data
-        frame.data.clear();
-        frame.data.reserve_exact(width * height);
-        frame.data.resize(width * height, 0);
+        let width = frame.width() as usize;
+        let height = frame.height() as usize;
 
-        // Process vertical pass
-        self.vertical_pass(&temp, &mut frame.data, width, height)
+        // Process horizontal and vertical passes
+        let blurred = self
+            .blur_plane(frame.data(), width, height)
             .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
+        frame.data_mut().copy_from_slice(&blurred);
 
         Ok(())
     }
@@ -192,3 +306,201 @@ impl PipelineStep for GaussianBlur {
         "GaussianBlur"
     }
 }
+
+/// Normalized 1D Gaussian kernel covering three standard deviations on each side
+///
+/// # Arguments
+/// * `sigma` - The standard deviation of the Gaussian, must be positive
+pub fn gaussian_kernel(sigma: f32) -> Result<Vec<f32>, BlurError> {
+    if !(sigma > 0.0) || !sigma.is_finite() {
+        return Err(BlurError::InvalidSigma(format!(
+            "sigma must be a positive number, got {}",
+            sigma
+        )));
+    }
+
+    // calculate the kernel radius
+    let radius = (3.0 * sigma).ceil() as usize;
+    let size = 2 * radius + 1;
+    let mut kernel = Vec::with_capacity(size);
+
+    // Calculate kernel values
+    let two_sigma_sq = 2.0 * sigma * sigma;
+    let mut sum = 0.0;
+
+    for i in 0..size {
+        let x = (i as i32 - radius as i32) as f32;
+        let g = (-x * x / two_sigma_sq).exp();
+        kernel.push(g);
+        sum += g;
+    }
+
+    // Check for numerical stability
+    if sum.abs() < f32::EPSILON {
+        return Err(BlurError::ProcessingError(
+            "Kernel sum too close to zero".to_string(),
+        ));
+    }
+
+    // Normalize kernel
+    for val in kernel.iter_mut() {
+        *val /= sum;
+    }
+
+    Ok(kernel)
+}
+
+// load the first eight floats of a slice
+#[inline(always)]
+fn load_f32x8(values: &[f32]) -> f32x8 {
+    let lanes: [f32; LANES] = values[..LANES].try_into().unwrap();
+    f32x8::from(lanes)
+}
+
+// widen eight bytes to floats
+#[inline(always)]
+fn widen_u8x8(values: &[u8]) -> f32x8 {
+    f32x8::from([
+        values[0] as f32,
+        values[1] as f32,
+        values[2] as f32,
+        values[3] as f32,
+        values[4] as f32,
+        values[5] as f32,
+        values[6] as f32,
+        values[7] as f32,
+    ])
+}
+
+// clamp to the byte range and store as many lanes as fit in the output,
+// truncating like the scalar `as u8` conversion
+#[inline(always)]
+fn store_u8x8(sum: f32x8, output: &mut [u8]) {
+    let clamped = sum.max(f32x8::ZERO).min(f32x8::splat(255.0)).to_array();
+    for (dst, value) in output.iter_mut().zip(clamped) {
+        *dst = value as u8;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // the scalar passes of `blur_plane_scalar` reading taps through the border mode
+    fn blur_plane_bordered(
+        blur: &GaussianBlur,
+        input: &[u8],
+        width: usize,
+        height: usize,
+    ) -> Vec<u8> {
+        let constant = blur.border.constant_value();
+        let taps = |x: usize, y: usize, horizontal: bool, plane: &[u8]| {
+            let mut sum = 0.0;
+            for (i, &k) in blur.kernel.iter().enumerate() {
+                let offset = i as isize - blur.radius as isize;
+                let (src_x, src_y) = if horizontal {
+                    (x as isize + offset, y as isize)
+                } else {
+                    (x as isize, y as isize + offset)
+                };
+                let value = blur
+                    .border
+                    .pixel(plane, width, height, src_x, src_y, constant);
+                sum += value as f32 * k;
+            }
+            sum.clamp(0.0, 255.0) as u8
+        };
+
+        let mut temp = vec![0u8; width * height];
+        for y in 0..height {
+            for x in 0..width {
+                temp[y * width + x] = taps(x, y, true, input);
+            }
+        }
+
+        let mut output = vec![0u8; width * height];
+        for y in 0..height {
+            for x in 0..width {
+                output[y * width + x] = taps(x, y, false, &temp);
+            }
+        }
+        output
+    }
+
+    // gradient with pseudo random noise so every kernel tap matters
+    fn noise_plane(width: usize, height: usize) -> Vec<u8> {
+        let mut state = 0x2545_f491u32;
+        (0..width * height)
+            .map(|i| {
+                state ^= state << 13;
+                state ^= state >> 17;
+                state ^= state << 5;
+                let gradient = (i % width * 255 / width) as u32;
+                ((gradient + (state & 0x3f)) & 0xff) as u8
+            })
+            .collect()
+    }
+
+    #[test]
+    fn simd_matches_scalar_for_every_border_mode() {
+        let borders = [
+            BorderMode::Constant(0),
+            BorderMode::Constant(200),
+            BorderMode::Replicate,
+            BorderMode::Reflect,
+            BorderMode::Wrap,
+        ];
+
+        // widths below, at and around multiples of the vector width
+        for border in borders {
+            for (width, height) in [(1, 5), (7, 3), (8, 8), (13, 11), (31, 9), (64, 4)] {
+                for sigma in [0.8, 1.4, 3.0] {
+                    let blur = GaussianBlur::with_border("", sigma, border).unwrap();
+                    let input = noise_plane(width, height);
+
+                    let simd = blur.blur_plane(&input, width, height).unwrap();
+                    let scalar = blur_plane_bordered(&blur, &input, width, height);
+
+                    assert_eq!(
+                        simd, scalar,
+                        "{:?} {}x{} sigma {}",
+                        border, width, height, sigma
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn simd_matches_the_original_scalar_blur() {
+        for (width, height) in [(13, 11), (31, 29), (64, 40)] {
+            for sigma in [0.8, 1.4, 3.0] {
+                // the original passes replicate the first pixel like the replicate mode
+                let blur = GaussianBlur::with_border("", sigma, BorderMode::Replicate).unwrap();
+                let input = noise_plane(width, height);
+
+                let simd = blur.blur_plane(&input, width, height).unwrap();
+                let original = blur.blur_plane_scalar(&input, width, height).unwrap();
+
+                // past the last pixel the original leaves taps out, compare up to there
+                for y in 0..height - blur.radius {
+                    for x in 0..width - blur.radius {
+                        assert_eq!(
+                            simd[y * width + x],
+                            original[y * width + x],
+                            "{}x{} sigma {} at ({}, {})",
+                            width, height, sigma, x, y
+                        );
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn rejects_invalid_sigma() {
+        assert!(gaussian_kernel(0.0).is_err());
+        assert!(gaussian_kernel(-1.0).is_err());
+        assert!(gaussian_kernel(f32::NAN).is_err());
+    }
+}
@@ -1,7 +1,62 @@
-use crate::video_pipeline::Frame;
+use super::border::BorderMode;
+use crate::frame::{Frame, Image, PixelFormat};
+use crate::frame_pipeline::{FrameContext, PipelineStep};
+use serde::Deserialize;
 use std::f32::consts::PI;
+use std::io;
+
+/// Parameters for a `gradient` step in a pipeline description file
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct GradientConfig {
+    #[serde(default)]
+    pub operator: GradientOperator,
+    #[serde(default)]
+    pub norm: GradientNorm,
+    #[serde(default)]
+    pub border: BorderMode,
+}
+
+/// The 3x3 derivative kernels a `SobelOperator` can use
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum GradientOperator {
+    #[default]
+    Sobel,
+    /// Better rotational symmetry than Sobel, with larger magnitudes
+    Scharr,
+    /// Unweighted central differences, the most sensitive to noise
+    Prewitt,
+}
 
-#[derive(Clone)]
+impl GradientOperator {
+    /// The horizontal and vertical derivative kernels, row by row
+    pub fn kernels(&self) -> ([[i32; 3]; 3], [[i32; 3]; 3]) {
+        let (side, center) = match self {
+            GradientOperator::Sobel => (1, 2),
+            GradientOperator::Scharr => (3, 10),
+            GradientOperator::Prewitt => (1, 1),
+        };
+
+        (
+            [[-side, 0, side], [-center, 0, center], [-side, 0, side]],
+            [[-side, -center, -side], [0, 0, 0], [side, center, side]],
+        )
+    }
+}
+
+/// How the gradient magnitude is computed from its two components
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum GradientNorm {
+    /// `|gx| + |gy|`, cheaper and slightly larger on diagonals
+    L1,
+    /// `sqrt(gx² + gy²)`
+    #[default]
+    L2,
+}
+
+#[derive(Clone, Copy)]
 pub struct PixelGradient {
     pub magnitude: f32,
     pub direction: f32,
@@ -19,14 +74,39 @@ impl PixelGradient {
 pub struct SobelOperator {
     kernel_x: [[i32; 3]; 3],
     kernel_y: [[i32; 3]; 3],
+    /// How the magnitude is computed from the two derivatives
+    norm: GradientNorm,
+    /// How neighbours outside the frame are read
+    border: BorderMode,
 }
 
 impl SobelOperator {
     pub fn new() -> Self {
+        Self::with_border(BorderMode::default())
+    }
+
+    /// Create a Sobel operator reading pixels outside the frame with the given border mode
+    pub fn with_border(border: BorderMode) -> Self {
+        Self::with_operator(GradientOperator::Sobel, GradientNorm::L2, border)
+    }
+
+    /// Create a gradient operator with explicit kernels and magnitude norm
+    ///
+    /// # Arguments
+    /// * `operator` - The derivative kernels for the x and y directions
+    /// * `norm` - How the magnitude is computed from the two derivatives
+    /// * `border` - How pixels outside the frame are read
+    pub fn with_operator(
+        operator: GradientOperator,
+        norm: GradientNorm,
+        border: BorderMode,
+    ) -> Self {
+        let (kernel_x, kernel_y) = operator.kernels();
         Self {
-            // Sobel kernels for x and y directions
-            kernel_x: [[-1, 0, 1], [-2, 0, 2], [-1, 0, 1]],
-            kernel_y: [[-1, -2, -1], [0, 0, 0], [1, 2, 1]],
+            kernel_x,
+            kernel_y,
+            norm,
+            border,
         }
     }
 
@@ -34,60 +114,133 @@ impl SobelOperator {
         let mut gx = 0;
         let mut gy = 0;
 
+        let width = frame.width() as usize;
+        let height = frame.height() as usize;
+        let constant = self.border.constant_value();
+
         // Apply both kernels simultaneously
         for ky in 0..3 {
             for kx in 0..3 {
                 // Get the pixel value from the 3x3 neighborhood
-                if let Some((value, _, _)) =
-                    frame.get_pixel(x + (kx as i32 - 1), y + (ky as i32 - 1))
-                {
-                    gx += value * self.kernel_x[ky][kx];
-                    gy += value * self.kernel_y[ky][kx];
-                }
+                let value = self.border.pixel(
+                    frame.data(),
+                    width,
+                    height,
+                    (x + kx as i32 - 1) as isize,
+                    (y + ky as i32 - 1) as isize,
+                    constant,
+                ) as i32;
+                gx += value * self.kernel_x[ky][kx];
+                gy += value * self.kernel_y[ky][kx];
             }
         }
 
         (gx, gy)
     }
 
-    pub fn calculate_gradient(frame: &Frame) -> Vec<Vec<PixelGradient>> {
-        // Ensure the frame is grayscale
-        if frame.channels != 1 {
-            panic!("Frame must be grayscale (1 channel) for Sobel operator");
+    /// Gradient of every pixel of the frame's 8 bit luma, other formats are converted first
+    pub fn calculate_gradient(&self, frame: &Frame) -> Image<PixelGradient> {
+        if frame.format() != PixelFormat::Gray8 {
+            return self.calculate_gradient(&frame.convert(PixelFormat::Gray8));
         }
 
-        let height = frame.height as usize;
-        let width = frame.width as usize;
+        let height = frame.height() as usize;
+        let width = frame.width() as usize;
 
-        // Initialize output gradients matrix
-        let mut gradients = vec![vec![PixelGradient::new(0.0, 0.0); width]; height];
+        // Calculate gradients for every pixel, the border mode supplies the
+        // neighbours outside the frame
+        Image::from_fn(width, height, |x, y| {
+            let (gx, gy) = self.apply_kernels(frame, x as i32, y as i32);
 
-        // Create Sobel operator with kernels
-        let sobel = SobelOperator::new();
+            // Calculate magnitude and direction
+            let magnitude = match self.norm {
+                GradientNorm::L1 => (gx.abs() + gy.abs()) as f32,
+                GradientNorm::L2 => ((gx * gx + gy * gy) as f32).sqrt(),
+            };
 
-        // Calculate gradients for each pixel (excluding borders)
-        for y in 1..height - 1 {
-            for x in 1..width - 1 {
-                let (gx, gy) = sobel.apply_kernels(frame, x as i32, y as i32);
+            // Calculate direction in radians, handle division by zero
+            let direction = if gx == 0 {
+                if gy == 0 {
+                    0.0
+                } else {
+                    PI / 2.0 * gy.signum() as f32
+                }
+            } else {
+                (gy as f32).atan2(gx as f32)
+            };
 
-                // Calculate magnitude and direction
-                let magnitude = ((gx * gx + gy * gy) as f32).sqrt();
+            PixelGradient::new(magnitude, direction)
+        })
+    }
+}
 
-                // Calculate direction in radians, handle division by zero
-                let direction = if gx == 0 {
-                    if gy == 0 {
-                        0.0
-                    } else {
-                        PI / 2.0 * gy.signum() as f32
-                    }
-                } else {
-                    (gy as f32).atan2(gx as f32)
-                };
+/// Pipeline step computing the gradient of every pixel. The gradients are handed to later
+/// steps through the frame context, the frame is replaced by the magnitudes clamped to
+/// 8 bits.
+pub struct GradientCalculation {
+    operator: SobelOperator,
+}
 
-                gradients[y][x] = PixelGradient::new(magnitude, direction);
-            }
-        }
+impl GradientCalculation {
+    pub fn new(operator: SobelOperator) -> Self {
+        Self { operator }
+    }
+
+    pub fn from_config(config: GradientConfig) -> Self {
+        Self::new(SobelOperator::with_operator(
+            config.operator,
+            config.norm,
+            config.border,
+        ))
+    }
+}
+
+impl PipelineStep for GradientCalculation {
+    fn process(&self, frame: &mut Frame, frame_count: u64) -> io::Result<()> {
+        self.process_with_context(frame, &mut FrameContext::new(frame_count))
+    }
+
+    fn process_with_context(
+        &self,
+        frame: &mut Frame,
+        context: &mut FrameContext,
+    ) -> io::Result<()> {
+        frame.to_grayscale();
+        let gradients = self.operator.calculate_gradient(frame);
+
+        *frame = gradients
+            .map(|gradient| gradient.magnitude.min(255.0) as u8)
+            .into();
+        context.insert_data(gradients);
+
+        Ok(())
+    }
+
+    fn name(&self) -> &str {
+        "GradientCalculation"
+    }
+}
 
-        gradients
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn color_frames_use_their_luma() {
+        let data: Vec<u8> = (0..5 * 4).flat_map(|i| [(i * 13) as u8, 40, 90]).collect();
+        let color = Frame::new(data, 5, 4, PixelFormat::Rgb).unwrap();
+        let gray = color.convert(PixelFormat::Gray8);
+        let sobel = SobelOperator::with_operator(
+            GradientOperator::default(),
+            GradientNorm::default(),
+            BorderMode::default(),
+        );
+
+        let from_color = sobel.calculate_gradient(&color);
+        let from_gray = sobel.calculate_gradient(&gray);
+        assert_eq!(
+            from_color.map(|gradient| gradient.magnitude),
+            from_gray.map(|gradient| gradient.magnitude)
+        );
     }
 }
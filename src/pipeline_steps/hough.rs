@@ -0,0 +1,847 @@
+use crate::frame::{Frame, Image};
+use crate::frame_pipeline::{FrameContext, PipelineStep};
+use serde::{Deserialize, Serialize};
+use std::f32::consts::PI;
+use std::io;
+
+use super::gradient_calculation::PixelGradient;
+use super::overlay;
+
+/// Which variant of the line transform to run
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum HoughLineMethod {
+    /// Infinite lines from the peaks of the full accumulator, clipped to the frame
+    #[default]
+    Standard,
+    /// Finite segments found by the progressive probabilistic transform
+    Probabilistic,
+}
+
+/// Parameters for a `hough_lines` step in a pipeline description file
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct HoughLinesConfig {
+    #[serde(default)]
+    pub method: HoughLineMethod,
+    /// Distance resolution of the accumulator in pixels
+    #[serde(default = "HoughLinesConfig::default_rho")]
+    pub rho: f32,
+    /// Angle resolution of the accumulator in degrees
+    #[serde(default = "HoughLinesConfig::default_theta")]
+    pub theta: f32,
+    /// Votes a line needs to be reported
+    #[serde(default = "HoughLinesConfig::default_threshold")]
+    pub threshold: u32,
+    /// Shortest segment reported by the probabilistic method, in pixels
+    #[serde(default = "HoughLinesConfig::default_min_length")]
+    pub min_length: f32,
+    /// Longest run of missing edge pixels bridged within a segment
+    #[serde(default = "HoughLinesConfig::default_max_gap")]
+    pub max_gap: u32,
+    /// Most lines reported per frame, strongest first, 0 reports all
+    #[serde(default)]
+    pub max_lines: usize,
+    /// Draw the lines onto the frame instead of leaving it unchanged
+    #[serde(default)]
+    pub overlay: bool,
+}
+
+impl HoughLinesConfig {
+    fn default_rho() -> f32 {
+        1.0
+    }
+
+    fn default_theta() -> f32 {
+        1.0
+    }
+
+    fn default_threshold() -> u32 {
+        80
+    }
+
+    fn default_min_length() -> f32 {
+        30.0
+    }
+
+    fn default_max_gap() -> u32 {
+        5
+    }
+}
+
+/// Parameters for a `hough_circles` step in a pipeline description file
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct HoughCirclesConfig {
+    #[serde(default = "HoughCirclesConfig::default_min_radius")]
+    pub min_radius: u32,
+    #[serde(default = "HoughCirclesConfig::default_max_radius")]
+    pub max_radius: u32,
+    /// Smallest distance between two reported centers, in pixels
+    #[serde(default = "HoughCirclesConfig::default_min_distance")]
+    pub min_distance: f32,
+    /// Votes a center needs to be considered
+    #[serde(default = "HoughCirclesConfig::default_threshold")]
+    pub threshold: u32,
+    /// Fraction of the circumference that has to be covered by edges
+    #[serde(default = "HoughCirclesConfig::default_min_coverage")]
+    pub min_coverage: f32,
+    /// Most circles reported per frame, strongest first, 0 reports all
+    #[serde(default)]
+    pub max_circles: usize,
+    /// Draw the circles onto the frame instead of leaving it unchanged
+    #[serde(default)]
+    pub overlay: bool,
+}
+
+impl HoughCirclesConfig {
+    fn default_min_radius() -> u32 {
+        5
+    }
+
+    fn default_max_radius() -> u32 {
+        100
+    }
+
+    fn default_min_distance() -> f32 {
+        10.0
+    }
+
+    fn default_threshold() -> u32 {
+        20
+    }
+
+    fn default_min_coverage() -> f32 {
+        0.5
+    }
+}
+
+/// A detected line, `x * cos(theta) + y * sin(theta) = rho`
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct HoughLine {
+    /// Signed distance of the line from the top left corner, in pixels
+    pub rho: f32,
+    /// Angle of the line normal in radians, 0.0..PI
+    pub theta: f32,
+    /// Edge pixels that voted for the line
+    pub votes: u32,
+    /// First end of the segment, or where the line enters the frame, as `[x, y]`
+    pub start: [f32; 2],
+    /// Second end of the segment, or where the line leaves the frame, as `[x, y]`
+    pub end: [f32; 2],
+}
+
+/// A detected circle
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct HoughCircle {
+    /// Center of the circle, as `[x, y]`
+    pub center: [f32; 2],
+    pub radius: f32,
+    /// Edge pixels whose gradient points through the center
+    pub votes: u32,
+    /// Fraction of the circumference covered by edge pixels
+    pub coverage: f32,
+}
+
+/// Accumulator of the line transform, counts per angle and distance bin
+struct LineAccumulator {
+    // cosine and sine of every angle bin
+    angles: Vec<(f32, f32)>,
+    // size of an angle bin in radians
+    theta_step: f32,
+    // size of a distance bin in pixels
+    rho_step: f32,
+    // bin index of distance 0
+    rho_offset: i32,
+    rho_bins: usize,
+    // counts, angle major
+    counts: Vec<u32>,
+}
+
+impl LineAccumulator {
+    fn new(width: usize, height: usize, rho_step: f32, theta_step: f32) -> Self {
+        let angle_bins = (PI / theta_step).round().max(1.0) as usize;
+        let angles = (0..angle_bins)
+            .map(|t| {
+                let (sin, cos) = (t as f32 * theta_step).sin_cos();
+                (cos, sin)
+            })
+            .collect();
+
+        let diagonal = ((width * width + height * height) as f32).sqrt();
+        let rho_offset = (diagonal / rho_step).ceil() as i32;
+        let rho_bins = 2 * rho_offset as usize + 1;
+
+        Self {
+            angles,
+            theta_step,
+            rho_step,
+            rho_offset,
+            rho_bins,
+            counts: vec![0; angle_bins * rho_bins],
+        }
+    }
+
+    fn rho_bin(&self, x: usize, y: usize, angle: usize) -> usize {
+        let (cos, sin) = self.angles[angle];
+        (((x as f32 * cos + y as f32 * sin) / self.rho_step).round() as i32 + self.rho_offset)
+            as usize
+    }
+
+    fn rho(&self, bin: usize) -> f32 {
+        (bin as i32 - self.rho_offset) as f32 * self.rho_step
+    }
+
+    fn theta(&self, angle: usize) -> f32 {
+        angle as f32 * self.theta_step
+    }
+
+    // add a pixel to every line through it, returning the fullest bin it reached
+    fn vote(&mut self, x: usize, y: usize) -> (u32, usize, usize) {
+        let mut best = (0, 0, 0);
+        for angle in 0..self.angles.len() {
+            let index = angle * self.rho_bins + self.rho_bin(x, y, angle);
+            self.counts[index] += 1;
+            if self.counts[index] > best.0 {
+                best = (self.counts[index], angle, index % self.rho_bins);
+            }
+        }
+        best
+    }
+
+    fn unvote(&mut self, x: usize, y: usize) {
+        for angle in 0..self.angles.len() {
+            let index = angle * self.rho_bins + self.rho_bin(x, y, angle);
+            self.counts[index] -= 1;
+        }
+    }
+}
+
+/// Find lines with the standard Hough transform, strongest first
+///
+/// # Arguments
+/// * `edges` - The edge map, true for edge pixels
+/// * `rho` - Distance resolution in pixels
+/// * `theta` - Angle resolution in radians
+/// * `threshold` - Votes a line needs to be reported
+pub fn hough_lines(edges: &Image<bool>, rho: f32, theta: f32, threshold: u32) -> Vec<HoughLine> {
+    let (width, height) = (edges.width(), edges.height());
+    let mut accumulator = LineAccumulator::new(width, height, rho, theta);
+    for y in 0..height {
+        for x in 0..width {
+            if edges[(x, y)] {
+                accumulator.vote(x, y);
+            }
+        }
+    }
+
+    // local maxima of the accumulator, ties go to the earlier bin
+    let angle_bins = accumulator.angles.len();
+    let rho_bins = accumulator.rho_bins;
+    let count = |angle: usize, bin: usize| accumulator.counts[angle * rho_bins + bin];
+    let mut lines = Vec::new();
+    for angle in 0..angle_bins {
+        for bin in 0..rho_bins {
+            let votes = count(angle, bin);
+            if votes < threshold
+                || (angle > 0 && count(angle - 1, bin) >= votes)
+                || (bin > 0 && count(angle, bin - 1) >= votes)
+                || (angle + 1 < angle_bins && count(angle + 1, bin) > votes)
+                || (bin + 1 < rho_bins && count(angle, bin + 1) > votes)
+            {
+                continue;
+            }
+
+            let (rho, theta) = (accumulator.rho(bin), accumulator.theta(angle));
+            let (start, end) = clip_line(rho, theta, width, height);
+            lines.push(HoughLine {
+                rho,
+                theta,
+                votes,
+                start,
+                end,
+            });
+        }
+    }
+
+    lines.sort_by(|a, b| b.votes.cmp(&a.votes));
+    lines
+}
+
+// where a line enters and leaves the frame, both ends equal when it misses the frame
+fn clip_line(rho: f32, theta: f32, width: usize, height: usize) -> ([f32; 2], [f32; 2]) {
+    let (sin, cos) = theta.sin_cos();
+    let (right, bottom) = ((width - 1) as f32, (height - 1) as f32);
+    let mut points = Vec::with_capacity(4);
+
+    if sin.abs() > 1e-6 {
+        for x in [0.0, right] {
+            points.push([x, (rho - x * cos) / sin]);
+        }
+    }
+    if cos.abs() > 1e-6 {
+        for y in [0.0, bottom] {
+            points.push([(rho - y * sin) / cos, y]);
+        }
+    }
+
+    let inside: Vec<[f32; 2]> = points
+        .into_iter()
+        .filter(|[x, y]| *x >= -0.5 && *x <= right + 0.5 && *y >= -0.5 && *y <= bottom + 0.5)
+        .collect();
+
+    // the two crossings furthest apart
+    let mut best = ([0.0, 0.0], [0.0, 0.0], -1.0);
+    for (i, a) in inside.iter().enumerate() {
+        for b in &inside[i..] {
+            let distance = (a[0] - b[0]).powi(2) + (a[1] - b[1]).powi(2);
+            if distance > best.2 {
+                best = (*a, *b, distance);
+            }
+        }
+    }
+
+    (best.0, best.1)
+}
+
+/// Find line segments with the progressive probabilistic Hough transform. Edge pixels
+/// vote in a fixed pseudo random order, once a line has enough votes its segment is
+/// walked and its pixels are removed, so results are the same for every run.
+///
+/// # Arguments
+/// * `edges` - The edge map, true for edge pixels
+/// * `rho` - Distance resolution in pixels
+/// * `theta` - Angle resolution in radians
+/// * `threshold` - Votes a line needs before its segment is extracted
+/// * `min_length` - Shortest segment reported, in pixels
+/// * `max_gap` - Longest run of missing pixels bridged within a segment
+/// * `max_lines` - Stop after this many segments, 0 finds all
+pub fn probabilistic_hough_lines(
+    edges: &Image<bool>,
+    rho: f32,
+    theta: f32,
+    threshold: u32,
+    min_length: f32,
+    max_gap: u32,
+    max_lines: usize,
+) -> Vec<HoughLine> {
+    let (width, height) = (edges.width(), edges.height());
+    let mut accumulator = LineAccumulator::new(width, height, rho, theta);
+    let mut remaining = edges.clone();
+    let mut voted = Image::new(width, height, false);
+
+    let mut points: Vec<(usize, usize)> = (0..height)
+        .flat_map(|y| (0..width).map(move |x| (x, y)))
+        .filter(|&(x, y)| edges[(x, y)])
+        .collect();
+    shuffle(&mut points);
+
+    let mut lines = Vec::new();
+    for (x, y) in points {
+        // removed as part of an earlier segment
+        if !remaining[(x, y)] {
+            continue;
+        }
+
+        let (votes, angle, bin) = accumulator.vote(x, y);
+        voted[(x, y)] = true;
+        if votes < threshold {
+            continue;
+        }
+
+        // walk along the line in both directions, bridging short gaps
+        let (cos, sin) = accumulator.angles[angle];
+        let direction = [-sin, cos];
+        let mut ends = [[x as f32, y as f32]; 2];
+        for (end, sign) in ends.iter_mut().zip([1.0f32, -1.0]) {
+            let mut gap = 0;
+            for step in 1.. {
+                let px = (x as f32 + sign * direction[0] * step as f32).round();
+                let py = (y as f32 + sign * direction[1] * step as f32).round();
+                if px < 0.0 || py < 0.0 || px >= width as f32 || py >= height as f32 {
+                    break;
+                }
+
+                if remaining[(px as usize, py as usize)] {
+                    *end = [px, py];
+                    gap = 0;
+                } else {
+                    gap += 1;
+                    if gap > max_gap {
+                        break;
+                    }
+                }
+            }
+        }
+
+        let [start, end] = ends;
+        let length = ((end[0] - start[0]).powi(2) + (end[1] - start[1]).powi(2)).sqrt();
+        let good = length >= min_length;
+
+        // clear the segment so its pixels don't vote again, taking back their votes
+        // when it is kept
+        let steps = length.ceil() as usize;
+        for step in 0..=steps {
+            let t = if steps == 0 {
+                0.0
+            } else {
+                step as f32 / steps as f32
+            };
+            let px = (start[0] + (end[0] - start[0]) * t).round() as usize;
+            let py = (start[1] + (end[1] - start[1]) * t).round() as usize;
+            if !remaining[(px, py)] {
+                continue;
+            }
+
+            remaining[(px, py)] = false;
+            if good && voted[(px, py)] {
+                accumulator.unvote(px, py);
+                voted[(px, py)] = false;
+            }
+        }
+
+        if good {
+            lines.push(HoughLine {
+                rho: accumulator.rho(bin),
+                theta: accumulator.theta(angle),
+                votes,
+                start,
+                end,
+            });
+
+            if lines.len() == max_lines {
+                break;
+            }
+        }
+    }
+
+    lines
+}
+
+// fixed seed xorshift shuffle, reproducible between runs
+fn shuffle<T>(items: &mut [T]) {
+    let mut state: u64 = 0x9E37_79B9_7F4A_7C15;
+    for i in (1..items.len()).rev() {
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        items.swap(i, (state % (i as u64 + 1)) as usize);
+    }
+}
+
+/// Find circles with the Hough gradient method: every edge pixel votes for the centers
+/// along its gradient direction, then the radius of each center is picked from the
+/// distances of the edge pixels around it. Strongest first.
+///
+/// # Arguments
+/// * `edges` - The edge map, true for edge pixels
+/// * `gradients` - Gradients of the frame the edges were detected in
+/// * `min_radius` - Smallest radius searched, in pixels
+/// * `max_radius` - Largest radius searched, in pixels
+/// * `min_distance` - Smallest distance between two reported centers
+/// * `threshold` - Votes a center needs to be considered
+/// * `min_coverage` - Fraction of the circumference that has to lie on edges
+pub fn hough_circles(
+    edges: &Image<bool>,
+    gradients: &Image<PixelGradient>,
+    min_radius: u32,
+    max_radius: u32,
+    min_distance: f32,
+    threshold: u32,
+    min_coverage: f32,
+) -> Vec<HoughCircle> {
+    let (width, height) = (edges.width(), edges.height());
+    let mut centers = Image::new(width, height, 0u32);
+
+    let points: Vec<(usize, usize)> = (0..height)
+        .flat_map(|y| (0..width).map(move |x| (x, y)))
+        .filter(|&(x, y)| edges[(x, y)])
+        .collect();
+
+    // the center lies along the gradient, on either side depending on the contrast
+    for &(x, y) in &points {
+        let gradient = gradients[(x, y)];
+        if gradient.magnitude <= 0.0 {
+            continue;
+        }
+
+        let (sin, cos) = gradient.direction.sin_cos();
+        for radius in min_radius..=max_radius {
+            for sign in [1.0, -1.0] {
+                let cx = (x as f32 + sign * radius as f32 * cos).round();
+                let cy = (y as f32 + sign * radius as f32 * sin).round();
+                if cx >= 0.0 && cy >= 0.0 && cx < width as f32 && cy < height as f32 {
+                    centers[(cx as usize, cy as usize)] += 1;
+                }
+            }
+        }
+    }
+
+    // local maxima of the center votes, ties go to the earlier pixel
+    let mut candidates = Vec::new();
+    for y in 0..height {
+        for x in 0..width {
+            let votes = centers[(x, y)];
+            if votes < threshold {
+                continue;
+            }
+
+            let is_peak = (-1i32..=1).all(|dy| {
+                (-1i32..=1).all(|dx| {
+                    let (nx, ny) = (x as i32 + dx, y as i32 + dy);
+                    if (dx, dy) == (0, 0)
+                        || nx < 0
+                        || ny < 0
+                        || nx >= width as i32
+                        || ny >= height as i32
+                    {
+                        return true;
+                    }
+
+                    let other = centers[(nx as usize, ny as usize)];
+                    let earlier = dy < 0 || (dy == 0 && dx < 0);
+                    if earlier {
+                        other < votes
+                    } else {
+                        other <= votes
+                    }
+                })
+            });
+            if is_peak {
+                candidates.push((x, y, votes));
+            }
+        }
+    }
+    candidates.sort_by(|a, b| b.2.cmp(&a.2));
+
+    let mut circles: Vec<HoughCircle> = Vec::new();
+    let mut histogram = vec![0u32; max_radius as usize + 2];
+    for (cx, cy, votes) in candidates {
+        let center = [cx as f32, cy as f32];
+        let too_close = circles.iter().any(|circle| {
+            (circle.center[0] - center[0]).powi(2) + (circle.center[1] - center[1]).powi(2)
+                < min_distance * min_distance
+        });
+        if too_close {
+            continue;
+        }
+
+        // count the edge pixels at every distance from the center
+        histogram.iter_mut().for_each(|count| *count = 0);
+        for &(x, y) in &points {
+            let distance = ((x as f32 - center[0]).powi(2) + (y as f32 - center[1]).powi(2)).sqrt();
+            let bin = distance.round() as usize;
+            if bin >= min_radius as usize && bin <= max_radius as usize {
+                histogram[bin] += 1;
+            }
+        }
+
+        // the radius whose ring, one pixel either side, covers most of its circumference
+        let ring =
+            |radius: usize| histogram[radius - 1] + histogram[radius] + histogram[radius + 1];
+        let best = (min_radius.max(1) as usize..=max_radius as usize)
+            .map(|radius| (radius, ring(radius) as f32 / (2.0 * PI * radius as f32)))
+            .fold(
+                (0, 0.0f32),
+                |best, item| if item.1 > best.1 { item } else { best },
+            );
+        let (radius, coverage) = best;
+        if radius == 0 || coverage < min_coverage {
+            continue;
+        }
+
+        // refine the radius to the mean distance within the ring
+        let weights = [radius - 1, radius, radius + 1].map(|r| (r as f32, histogram[r] as f32));
+        let total: f32 = weights.iter().map(|(_, count)| count).sum();
+        let radius = weights.iter().map(|(r, count)| r * count).sum::<f32>() / total;
+
+        circles.push(HoughCircle {
+            center,
+            radius,
+            votes,
+            coverage: coverage.min(1.0),
+        });
+    }
+
+    circles
+}
+
+fn invalid_input(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, message)
+}
+
+/// Pipeline step detecting straight lines in an edge map, such as the output of a
+/// `canny` step, every non zero pixel is an edge. The lines are attached to the frame
+/// context, the frame passes through unchanged or with the lines drawn onto it.
+pub struct HoughLines {
+    method: HoughLineMethod,
+    /// Distance resolution in pixels
+    rho: f32,
+    /// Angle resolution in radians
+    theta: f32,
+    threshold: u32,
+    min_length: f32,
+    max_gap: u32,
+    max_lines: usize,
+    overlay: bool,
+}
+
+impl HoughLines {
+    pub fn from_config(config: HoughLinesConfig) -> io::Result<Self> {
+        if !(config.rho > 0.0) {
+            return Err(invalid_input(format!(
+                "Invalid Hough rho {}, must be greater than 0",
+                config.rho
+            )));
+        }
+        if !(config.theta > 0.0 && config.theta < 180.0) {
+            return Err(invalid_input(format!(
+                "Invalid Hough theta {}, must be between 0 and 180 degrees",
+                config.theta
+            )));
+        }
+
+        Ok(Self {
+            method: config.method,
+            rho: config.rho,
+            theta: config.theta.to_radians(),
+            threshold: config.threshold,
+            min_length: config.min_length,
+            max_gap: config.max_gap,
+            max_lines: config.max_lines,
+            overlay: config.overlay,
+        })
+    }
+
+    /// Detect the lines of an edge map, strongest first for the standard method and in
+    /// detection order for the probabilistic one
+    pub fn detect(&self, frame: &Frame) -> Vec<HoughLine> {
+        let edges = frame.map_gray(|value| value > 0);
+
+        let mut lines = match self.method {
+            HoughLineMethod::Standard => hough_lines(&edges, self.rho, self.theta, self.threshold),
+            HoughLineMethod::Probabilistic => probabilistic_hough_lines(
+                &edges,
+                self.rho,
+                self.theta,
+                self.threshold,
+                self.min_length,
+                self.max_gap,
+                self.max_lines,
+            ),
+        };
+
+        if self.max_lines > 0 {
+            lines.truncate(self.max_lines);
+        }
+        lines
+    }
+}
+
+impl PipelineStep for HoughLines {
+    fn process(&self, frame: &mut Frame, frame_count: u64) -> io::Result<()> {
+        self.process_with_context(frame, &mut FrameContext::new(frame_count))
+    }
+
+    fn process_with_context(
+        &self,
+        frame: &mut Frame,
+        context: &mut FrameContext,
+    ) -> io::Result<()> {
+        let lines = self.detect(frame);
+        context.insert(&lines)?;
+
+        if self.overlay {
+            overlay::to_canvas(frame);
+            for line in &lines {
+                overlay::draw_line(frame, line.start, line.end, [255, 0, 0]);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn name(&self) -> &str {
+        "HoughLines"
+    }
+}
+
+/// Pipeline step detecting circles in an edge map from the gradients an earlier `canny`
+/// or `gradient` step left in the frame context, every non zero pixel is an edge. The
+/// circles are attached to the frame context, the frame passes through unchanged or
+/// with the circles drawn onto it.
+pub struct HoughCircles {
+    min_radius: u32,
+    max_radius: u32,
+    min_distance: f32,
+    threshold: u32,
+    min_coverage: f32,
+    max_circles: usize,
+    overlay: bool,
+}
+
+impl HoughCircles {
+    pub fn from_config(config: HoughCirclesConfig) -> io::Result<Self> {
+        if config.min_radius == 0 || config.min_radius > config.max_radius {
+            return Err(invalid_input(format!(
+                "Invalid Hough circle radii {}..{}, must satisfy 0 < min_radius <= max_radius",
+                config.min_radius, config.max_radius
+            )));
+        }
+
+        Ok(Self {
+            min_radius: config.min_radius,
+            max_radius: config.max_radius,
+            min_distance: config.min_distance,
+            threshold: config.threshold,
+            min_coverage: config.min_coverage,
+            max_circles: config.max_circles,
+            overlay: config.overlay,
+        })
+    }
+
+    /// Detect the circles of an edge map, strongest first
+    ///
+    /// # Arguments
+    /// * `frame` - The edge map, every non zero pixel is an edge
+    /// * `gradients` - Gradients of the frame the edges were detected in
+    pub fn detect(
+        &self,
+        frame: &Frame,
+        gradients: &Image<PixelGradient>,
+    ) -> io::Result<Vec<HoughCircle>> {
+        let edges = frame.map_gray(|value| value > 0);
+        if (edges.width(), edges.height()) != (gradients.width(), gradients.height()) {
+            return Err(invalid_input(format!(
+                "Gradients of {}x{} do not match the {}x{} edge map",
+                gradients.width(),
+                gradients.height(),
+                edges.width(),
+                edges.height()
+            )));
+        }
+
+        let mut circles = hough_circles(
+            &edges,
+            gradients,
+            self.min_radius,
+            self.max_radius,
+            self.min_distance,
+            self.threshold,
+            self.min_coverage,
+        );
+
+        if self.max_circles > 0 {
+            circles.truncate(self.max_circles);
+        }
+        Ok(circles)
+    }
+}
+
+impl PipelineStep for HoughCircles {
+    fn process(&self, frame: &mut Frame, frame_count: u64) -> io::Result<()> {
+        self.process_with_context(frame, &mut FrameContext::new(frame_count))
+    }
+
+    fn process_with_context(
+        &self,
+        frame: &mut Frame,
+        context: &mut FrameContext,
+    ) -> io::Result<()> {
+        let gradients = context.data::<Image<PixelGradient>>().ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Hough circles need the gradients of an earlier canny or gradient step",
+            )
+        })?;
+
+        let circles = self.detect(frame, gradients)?;
+        context.insert(&circles)?;
+
+        if self.overlay {
+            overlay::to_canvas(frame);
+            for circle in &circles {
+                overlay::draw_circle(frame, circle.center, circle.radius, [0, 255, 0]);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn name(&self) -> &str {
+        "HoughCircles"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn edges(width: usize, height: usize, points: &[(usize, usize)]) -> Image<bool> {
+        Image::from_fn(width, height, |x, y| points.contains(&(x, y)))
+    }
+
+    #[test]
+    fn known_lines_give_their_rho_and_theta() {
+        // long enough that the neighbouring angles spread their votes over several bins
+        let horizontal: Vec<(usize, usize)> = (0..100).map(|x| (x, 5)).collect();
+        let lines = hough_lines(&edges(100, 20, &horizontal), 1.0, 1f32.to_radians(), 90);
+        assert_eq!(lines[0].votes, 100);
+        assert!((lines[0].rho - 5.0).abs() < 1e-3);
+        assert!((lines[0].theta - PI / 2.0).abs() < 1e-3);
+
+        let vertical: Vec<(usize, usize)> = (0..100).map(|y| (3, y)).collect();
+        let lines = hough_lines(&edges(20, 100, &vertical), 1.0, 1f32.to_radians(), 90);
+        assert_eq!(lines[0].votes, 100);
+        assert!((lines[0].rho - 3.0).abs() < 1e-3);
+        assert!(lines[0].theta.abs() < 1e-3);
+        assert_eq!(lines[0].start, [3.0, 0.0]);
+        assert_eq!(lines[0].end, [3.0, 99.0]);
+    }
+
+    #[test]
+    fn probabilistic_segments_end_at_the_edge_pixels() {
+        let segment: Vec<(usize, usize)> = (2..18).map(|x| (x, 5)).collect();
+        let lines = probabilistic_hough_lines(
+            &edges(20, 10, &segment),
+            1.0,
+            1f32.to_radians(),
+            5,
+            10.0,
+            2,
+            0,
+        );
+
+        assert_eq!(lines.len(), 1);
+        let mut ends = [lines[0].start, lines[0].end];
+        ends.sort_by(|a, b| a[0].total_cmp(&b[0]));
+        assert_eq!(ends, [[2.0, 5.0], [17.0, 5.0]]);
+    }
+
+    #[test]
+    fn circle_center_and_radius_from_gradients() {
+        let (center, radius) = ((20.0f32, 20.0f32), 8.0f32);
+        let mut points = Vec::new();
+        for step in 0..360 {
+            let angle = (step as f32).to_radians();
+            let x = (center.0 + radius * angle.cos()).round() as usize;
+            let y = (center.1 + radius * angle.sin()).round() as usize;
+            if !points.contains(&(x, y)) {
+                points.push((x, y));
+            }
+        }
+
+        // gradients point away from the center like those of a bright disc
+        let gradients = Image::from_fn(41, 41, |x, y| {
+            let direction = (y as f32 - center.1).atan2(x as f32 - center.0);
+            PixelGradient::new(1.0, direction)
+        });
+
+        let circles = hough_circles(&edges(41, 41, &points), &gradients, 5, 12, 10.0, 10, 0.5);
+        assert_eq!(circles.len(), 1);
+        assert!((circles[0].center[0] - center.0).abs() <= 1.0);
+        assert!((circles[0].center[1] - center.1).abs() <= 1.0);
+        assert!((circles[0].radius - radius).abs() < 0.5);
+        assert!(circles[0].coverage > 0.8);
+    }
+}
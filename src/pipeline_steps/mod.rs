@@ -1,6 +1,14 @@
+pub mod auto_threshold;
+pub mod border;
 pub mod canny_edge_detection;
+pub mod connected_components;
+pub mod contours;
+pub mod convolution;
 pub mod gaussian_blur;
 pub mod gradient_calculation;
+pub mod hough;
+pub mod morphology;
 pub mod non_max_suppression;
+pub mod overlay;
 pub mod double_thresholding;
 pub mod eight_conn_edge_tracker;
@@ -0,0 +1,551 @@
+use crate::frame::{Frame, Image};
+use crate::frame_pipeline::PipelineStep;
+use serde::Deserialize;
+use std::io;
+
+use super::border::BorderMode;
+
+/// Parameters for a `morphology` step in a pipeline description file
+///
+/// ```toml
+/// [[steps]]
+/// type = "morphology"
+/// operation = "close"
+/// element = "ellipse"
+/// size = 5
+/// ```
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MorphologyConfig {
+    pub operation: MorphOperation,
+    #[serde(default)]
+    pub element: ElementShape,
+    /// Side length of the `rect`, `cross` and `ellipse` elements
+    #[serde(default = "MorphologyConfig::default_size")]
+    pub size: usize,
+    /// Rows of a `custom` element, non zero entries are part of it
+    pub matrix: Option<Vec<Vec<u8>>>,
+    /// How often erosion and dilation are applied within the operation
+    #[serde(default = "MorphologyConfig::default_iterations")]
+    pub iterations: usize,
+    /// How pixels outside the frame are read, by default they are ignored
+    pub border: Option<BorderMode>,
+}
+
+impl MorphologyConfig {
+    fn default_size() -> usize {
+        3
+    }
+
+    fn default_iterations() -> usize {
+        1
+    }
+}
+
+#[derive(Debug)]
+pub enum MorphologyError {
+    InvalidElement(String),
+    InvalidIterations(usize),
+}
+
+impl std::fmt::Display for MorphologyError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MorphologyError::InvalidElement(msg) => {
+                write!(f, "Invalid structuring element: {}", msg)
+            }
+            MorphologyError::InvalidIterations(iterations) => {
+                write!(f, "Invalid iterations {}, must be at least 1", iterations)
+            }
+        }
+    }
+}
+
+impl std::error::Error for MorphologyError {}
+
+impl From<MorphologyError> for io::Error {
+    fn from(error: MorphologyError) -> Self {
+        io::Error::new(io::ErrorKind::InvalidInput, error.to_string())
+    }
+}
+
+/// The operations of the `morphology` step
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MorphOperation {
+    /// Minimum under the element, shrinks bright regions and removes specks
+    Erode,
+    /// Maximum under the element, grows bright regions and bridges gaps
+    Dilate,
+    /// Erosion then dilation, removes bright details smaller than the element
+    Open,
+    /// Dilation then erosion, fills dark gaps smaller than the element
+    Close,
+    /// The frame minus its opening, keeps the bright details the opening removes
+    TopHat,
+    /// Dilation minus erosion, the outline of regions
+    Gradient,
+    /// Thin non zero regions to one pixel wide lines, ignores the element
+    Skeletonize,
+}
+
+/// Shape of a structuring element
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ElementShape {
+    #[default]
+    Rect,
+    Cross,
+    Ellipse,
+    /// Given by the `matrix` parameter
+    Custom,
+}
+
+/// The neighbourhood a morphological operation takes its minimum or maximum over,
+/// anchored at its center
+#[derive(Debug, Clone, PartialEq)]
+pub struct StructuringElement {
+    width: usize,
+    height: usize,
+    // offsets of the members from the anchor, as (dx, dy)
+    offsets: Vec<(i32, i32)>,
+}
+
+impl StructuringElement {
+    /// Every position of a `width` x `height` rectangle
+    pub fn rect(width: usize, height: usize) -> Result<Self, MorphologyError> {
+        Self::from_fn(width, height, |_, _| true)
+    }
+
+    /// The middle row and column of a `width` x `height` rectangle
+    pub fn cross(width: usize, height: usize) -> Result<Self, MorphologyError> {
+        Self::from_fn(width, height, |x, y| x == width / 2 || y == height / 2)
+    }
+
+    /// The positions inside the ellipse inscribed in a `width` x `height` rectangle
+    pub fn ellipse(width: usize, height: usize) -> Result<Self, MorphologyError> {
+        let (rx, ry) = (width as f32 / 2.0, height as f32 / 2.0);
+        Self::from_fn(width, height, |x, y| {
+            let dx = (x as f32 + 0.5 - rx) / rx;
+            let dy = (y as f32 + 0.5 - ry) / ry;
+            dx * dx + dy * dy <= 1.0
+        })
+    }
+
+    /// An element from rows of a matrix, non zero entries are members
+    pub fn custom(matrix: &[Vec<u8>]) -> Result<Self, MorphologyError> {
+        let height = matrix.len();
+        let width = matrix.first().map_or(0, |row| row.len());
+        if matrix.iter().any(|row| row.len() != width) {
+            return Err(MorphologyError::InvalidElement(
+                "every row of the matrix needs the same length".to_string(),
+            ));
+        }
+
+        Self::from_fn(width, height, |x, y| matrix[y][x] != 0)
+    }
+
+    fn from_fn(
+        width: usize,
+        height: usize,
+        is_member: impl Fn(usize, usize) -> bool,
+    ) -> Result<Self, MorphologyError> {
+        if width == 0 || height == 0 {
+            return Err(MorphologyError::InvalidElement(format!(
+                "size {}x{} is empty",
+                width, height
+            )));
+        }
+
+        let (ax, ay) = ((width / 2) as i32, (height / 2) as i32);
+        let offsets: Vec<(i32, i32)> = (0..height)
+            .flat_map(|y| (0..width).map(move |x| (x, y)))
+            .filter(|&(x, y)| is_member(x, y))
+            .map(|(x, y)| (x as i32 - ax, y as i32 - ay))
+            .collect();
+
+        if offsets.is_empty() {
+            return Err(MorphologyError::InvalidElement(
+                "the element has no members".to_string(),
+            ));
+        }
+
+        Ok(Self {
+            width,
+            height,
+            offsets,
+        })
+    }
+
+    // whether the element covers its whole bounding rectangle, which allows separate
+    // row and column passes
+    fn is_rect(&self) -> bool {
+        self.offsets.len() == self.width * self.height
+    }
+
+    /// The element mirrored through its anchor
+    pub fn reflected(&self) -> Self {
+        Self {
+            width: self.width,
+            height: self.height,
+            offsets: self.offsets.iter().map(|&(dx, dy)| (-dx, -dy)).collect(),
+        }
+    }
+}
+
+/// Morphological operations on 8 bit luma frames, gray values take the minimum or
+/// maximum under the element so binary edge maps stay binary
+pub struct Morphology {
+    operation: MorphOperation,
+    element: StructuringElement,
+    /// How often erosion and dilation are applied within the operation
+    iterations: usize,
+    /// How pixels outside the frame are read, `None` ignores them
+    border: Option<BorderMode>,
+}
+
+impl Morphology {
+    /// Create a morphology step
+    ///
+    /// # Arguments
+    /// * `operation` - The operation to apply
+    /// * `element` - The neighbourhood of the minimum and maximum filters
+    pub fn new(operation: MorphOperation, element: StructuringElement) -> Self {
+        Self {
+            operation,
+            element,
+            iterations: 1,
+            border: None,
+        }
+    }
+
+    /// Apply erosion and dilation `iterations` times within the operation
+    pub fn with_iterations(mut self, iterations: usize) -> Result<Self, MorphologyError> {
+        if iterations == 0 {
+            return Err(MorphologyError::InvalidIterations(iterations));
+        }
+        self.iterations = iterations;
+        Ok(self)
+    }
+
+    /// Read pixels outside the frame with a border mode instead of ignoring them
+    pub fn with_border(mut self, border: BorderMode) -> Self {
+        self.border = Some(border);
+        self
+    }
+
+    pub fn from_config(config: MorphologyConfig) -> io::Result<Self> {
+        let size = config.size;
+        let element = match config.element {
+            ElementShape::Rect => StructuringElement::rect(size, size)?,
+            ElementShape::Cross => StructuringElement::cross(size, size)?,
+            ElementShape::Ellipse => StructuringElement::ellipse(size, size)?,
+            ElementShape::Custom => match &config.matrix {
+                Some(matrix) => StructuringElement::custom(matrix)?,
+                None => {
+                    return Err(MorphologyError::InvalidElement(
+                        "a custom element needs a matrix".to_string(),
+                    )
+                    .into())
+                }
+            },
+        };
+
+        let morphology = Self::new(config.operation, element).with_iterations(config.iterations)?;
+        Ok(match config.border {
+            Some(border) => morphology.with_border(border),
+            None => morphology,
+        })
+    }
+
+    /// Apply the operation to an 8 bit image
+    pub fn apply(&self, image: &Image<u8>) -> Image<u8> {
+        match self.operation {
+            MorphOperation::Erode => self.repeat(image, Extremum::Min),
+            MorphOperation::Dilate => self.repeat(image, Extremum::Max),
+            MorphOperation::Open => self.open(image),
+            MorphOperation::Close => {
+                let dilated = self.repeat(image, Extremum::Max);
+                self.repeat(&dilated, Extremum::Min)
+            }
+            MorphOperation::TopHat => {
+                let opened = self.open(image);
+                Image::from_fn(image.width(), image.height(), |x, y| {
+                    image[(x, y)].saturating_sub(opened[(x, y)])
+                })
+            }
+            MorphOperation::Gradient => {
+                let dilated = self.repeat(image, Extremum::Max);
+                let eroded = self.repeat(image, Extremum::Min);
+                Image::from_fn(image.width(), image.height(), |x, y| {
+                    dilated[(x, y)].saturating_sub(eroded[(x, y)])
+                })
+            }
+            MorphOperation::Skeletonize => skeletonize(&image.map(|value| value > 0))
+                .map(|member| if member { 255 } else { 0 }),
+        }
+    }
+
+    fn open(&self, image: &Image<u8>) -> Image<u8> {
+        let eroded = self.repeat(image, Extremum::Min);
+        self.repeat(&eroded, Extremum::Max)
+    }
+
+    fn repeat(&self, image: &Image<u8>, extremum: Extremum) -> Image<u8> {
+        let mut result = self.filter(image, extremum);
+        for _ in 1..self.iterations {
+            result = self.filter(&result, extremum);
+        }
+        result
+    }
+
+    // minimum or maximum under the element, dilation uses the reflected element so
+    // opening and closing are idempotent for asymmetric elements
+    fn filter(&self, image: &Image<u8>, extremum: Extremum) -> Image<u8> {
+        let element = match extremum {
+            Extremum::Min => self.element.clone(),
+            Extremum::Max => self.element.reflected(),
+        };
+
+        if element.is_rect() {
+            // a rectangle is a row of the element followed by a column
+            let (first_x, first_y) = element.offsets[0];
+            let row: Vec<(i32, i32)> = element
+                .offsets
+                .iter()
+                .filter(|&&(_, dy)| dy == first_y)
+                .map(|&(dx, _)| (dx, 0))
+                .collect();
+            let column: Vec<(i32, i32)> = element
+                .offsets
+                .iter()
+                .filter(|&&(dx, _)| dx == first_x)
+                .map(|&(_, dy)| (0, dy))
+                .collect();
+            let horizontal = self.filter_offsets(image, &row, extremum);
+            self.filter_offsets(&horizontal, &column, extremum)
+        } else {
+            self.filter_offsets(image, &element.offsets, extremum)
+        }
+    }
+
+    fn filter_offsets(
+        &self,
+        image: &Image<u8>,
+        offsets: &[(i32, i32)],
+        extremum: Extremum,
+    ) -> Image<u8> {
+        let neutral = extremum.neutral();
+        let (width, height) = (image.width(), image.height());
+        Image::from_fn(width, height, |x, y| {
+            offsets.iter().fold(neutral, |result, &(dx, dy)| {
+                let (nx, ny) = (x as isize + dx as isize, y as isize + dy as isize);
+                let value = match self.border {
+                    Some(border) => image.get_with_border(nx, ny, border, border.constant_value()),
+                    None if nx < 0 || ny < 0 => neutral,
+                    None => image.get(nx as usize, ny as usize).unwrap_or(neutral),
+                };
+                extremum.pick(result, value)
+            })
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Extremum {
+    Min,
+    Max,
+}
+
+impl Extremum {
+    // the value that never wins, used for ignored pixels
+    fn neutral(self) -> u8 {
+        match self {
+            Extremum::Min => u8::MAX,
+            Extremum::Max => 0,
+        }
+    }
+
+    fn pick(self, a: u8, b: u8) -> u8 {
+        match self {
+            Extremum::Min => a.min(b),
+            Extremum::Max => a.max(b),
+        }
+    }
+}
+
+/// Thin a mask to one pixel wide, 8 connected lines with the Zhang-Suen algorithm,
+/// keeping the connectivity and end points of every region
+pub fn skeletonize(mask: &Image<bool>) -> Image<bool> {
+    let (width, height) = (mask.width() as i32, mask.height() as i32);
+    let mut skeleton = mask.clone();
+    let at = |image: &Image<bool>, x: i32, y: i32| {
+        x >= 0 && y >= 0 && x < width && y < height && image[(x as usize, y as usize)]
+    };
+
+    let mut removed = Vec::new();
+    loop {
+        let mut changed = false;
+
+        for pass in 0..2 {
+            removed.clear();
+            for y in 0..height {
+                for x in 0..width {
+                    if !at(&skeleton, x, y) {
+                        continue;
+                    }
+
+                    // neighbours clockwise from north: P2 to P9
+                    let p = [
+                        at(&skeleton, x, y - 1),
+                        at(&skeleton, x + 1, y - 1),
+                        at(&skeleton, x + 1, y),
+                        at(&skeleton, x + 1, y + 1),
+                        at(&skeleton, x, y + 1),
+                        at(&skeleton, x - 1, y + 1),
+                        at(&skeleton, x - 1, y),
+                        at(&skeleton, x - 1, y - 1),
+                    ];
+
+                    let neighbours = p.iter().filter(|&&member| member).count();
+                    let transitions = (0..8).filter(|&i| !p[i] && p[(i + 1) % 8]).count();
+                    let (north, east, south, west) = (p[0], p[2], p[4], p[6]);
+                    let outer = if pass == 0 {
+                        !(north && east && south) && !(east && south && west)
+                    } else {
+                        !(north && east && west) && !(north && south && west)
+                    };
+
+                    if (2..=6).contains(&neighbours) && transitions == 1 && outer {
+                        removed.push((x as usize, y as usize));
+                    }
+                }
+            }
+
+            for &position in &removed {
+                skeleton[position] = false;
+            }
+            changed |= !removed.is_empty();
+        }
+
+        if !changed {
+            return skeleton;
+        }
+    }
+}
+
+impl PipelineStep for Morphology {
+    fn process(&self, frame: &mut Frame, _frame_count: u64) -> io::Result<()> {
+        // operate on 8 bit luma, edge maps already are
+        let output = self.apply(frame.gray_image());
+        *frame = output.into();
+
+        Ok(())
+    }
+
+    fn name(&self) -> &str {
+        "Morphology"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // image from rows of text, '#' is 255 and everything else 0
+    fn image(rows: &[&str]) -> Image<u8> {
+        let rows: Vec<&[u8]> = rows.iter().map(|row| row.as_bytes()).collect();
+        Image::from_fn(rows[0].len(), rows.len(), |x, y| {
+            if rows[y][x] == b'#' {
+                255
+            } else {
+                0
+            }
+        })
+    }
+
+    fn apply(
+        operation: MorphOperation,
+        element: StructuringElement,
+        input: &Image<u8>,
+    ) -> Image<u8> {
+        Morphology::new(operation, element).apply(input)
+    }
+
+    #[test]
+    fn element_shapes() {
+        assert_eq!(StructuringElement::rect(3, 3).unwrap().offsets.len(), 9);
+        assert_eq!(StructuringElement::cross(3, 3).unwrap().offsets.len(), 5);
+        let ellipse = StructuringElement::ellipse(5, 5).unwrap();
+        assert!(ellipse.offsets.contains(&(0, 0)) && !ellipse.offsets.contains(&(-2, -2)));
+        assert!(StructuringElement::custom(&[vec![0, 0]]).is_err());
+    }
+
+    #[test]
+    fn dilate_then_erode_a_single_pixel() {
+        let point = image(&[".....", ".....", "..#..", ".....", "....."]);
+        let block = image(&[".....", ".###.", ".###.", ".###.", "....."]);
+
+        let rect = || StructuringElement::rect(3, 3).unwrap();
+        assert_eq!(apply(MorphOperation::Dilate, rect(), &point), block);
+        assert_eq!(apply(MorphOperation::Erode, rect(), &block), point);
+        assert_eq!(
+            apply(MorphOperation::Gradient, rect(), &point),
+            apply(MorphOperation::Dilate, rect(), &point)
+        );
+    }
+
+    #[test]
+    fn open_removes_specks_and_close_fills_gaps() {
+        let rect = || StructuringElement::rect(3, 3).unwrap();
+        let speck = image(&["#......", ".......", "...###.", "...###.", "...###."]);
+        let cleaned = image(&[".......", ".......", "...###.", "...###.", "...###."]);
+        assert_eq!(apply(MorphOperation::Open, rect(), &speck), cleaned);
+
+        // a one pixel gap between two regions, bridged by a row element
+        let gap = image(&["..###.#..", "..###.#.."]);
+        let filled = image(&["..#####..", "..#####.."]);
+        let row = StructuringElement::rect(3, 1).unwrap();
+        assert_eq!(apply(MorphOperation::Close, row, &gap), filled);
+    }
+
+    #[test]
+    fn rect_passes_match_the_full_element() {
+        let input = Image::from_fn(9, 7, |x, y| ((x * 37 + y * 91) % 256) as u8);
+        let morphology = Morphology::new(
+            MorphOperation::Erode,
+            StructuringElement::rect(5, 3).unwrap(),
+        );
+        let offsets = morphology.element.offsets.clone();
+
+        for extremum in [Extremum::Min, Extremum::Max] {
+            assert_eq!(
+                morphology.filter(&input, extremum),
+                morphology.filter_offsets(&input, &offsets, extremum)
+            );
+        }
+    }
+
+    #[test]
+    fn skeleton_of_a_bar_is_one_pixel_wide() {
+        let bar = image(&[
+            "............",
+            ".##########.",
+            ".##########.",
+            ".##########.",
+            "............",
+        ])
+        .map(|value| value > 0);
+        let skeleton = skeletonize(&bar);
+
+        let members: Vec<(usize, usize)> = (0..bar.height())
+            .flat_map(|y| (0..bar.width()).map(move |x| (x, y)))
+            .filter(|&position| skeleton[position])
+            .collect();
+        assert!(members.len() >= 6);
+        assert!(members.iter().all(|&position| bar[position]));
+        for x in 0..bar.width() {
+            assert!(members.iter().filter(|&&(mx, _)| mx == x).count() <= 1);
+        }
+    }
+}
@@ -1,73 +1,234 @@
-use crate::video_pipeline::Frame;
+use crate::frame::{Frame, Image};
+use crate::frame_pipeline::{FrameContext, PipelineStep};
+use serde::{Deserialize, Serialize};
+use std::io;
 
+use super::border::BorderMode;
 use super::gradient_calculation::PixelGradient;
 
+/// An edge located with sub-pixel accuracy along its gradient
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct EdgePoint {
+    /// Column and row of the pixel holding the edge
+    pub pixel: [usize; 2],
+    /// Column of the edge, 0.0 is the center of the first pixel
+    pub x: f32,
+    /// Row of the edge, 0.0 is the center of the first pixel
+    pub y: f32,
+    /// Gradient magnitude at the pixel holding the edge
+    pub magnitude: f32,
+    /// Gradient direction in radians, `atan2(gy, gx)` with y pointing down
+    pub direction: f32,
+}
+
+/// Parameters for a `non_max_suppression` step in a pipeline description file
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct NonMaxSuppressionConfig {
+    #[serde(default)]
+    pub border: BorderMode,
+}
+
 pub struct GradNonMaxSuppression {}
 
 impl GradNonMaxSuppression {
-    pub fn suppress(gradients: Vec<Vec<PixelGradient>>) -> Frame {
-        let height = gradients.len() as i32;
-        let width = gradients[0].len() as i32;
-
-        // create output buffer for final image
-        let size = (width * height) as usize;
-        let mut output_data = vec![0u8; size];
-
-        // Process all pixels except borders
-        for y in 1..(height - 1) {
-            for x in 1..(width - 1) {
-                let current = &gradients[y as usize][x as usize];
-
-                // Normalize angle to 0-180 degrees
-                // We use modulo to handle negative angles and angles > 180
-                let mut angle = current.direction % 180.0;
-                if angle < 0.0 {
-                    angle += 180.0;
-                }
+    /// Thin gradients to one pixel wide ridges
+    ///
+    /// A pixel is kept when its magnitude is a maximum against both neighbours one pixel
+    /// along the gradient direction, read with bilinear interpolation.
+    ///
+    /// # Arguments
+    /// * `gradients` - Gradient of every pixel
+    /// * `border` - How neighbours outside the frame are read, in constant mode
+    ///   they have no gradient
+    pub fn suppress(gradients: &Image<PixelGradient>, border: BorderMode) -> Image<u8> {
+        Self::suppress_with_positions(gradients, border).0
+    }
 
-                // Initialize neighbor magnitudes
-                let mut neighbor1_mag = 0.0;
-                let mut neighbor2_mag = 0.0;
+    /// Thin gradients like `suppress`, also locating every kept edge with sub-pixel
+    /// accuracy by fitting a parabola through the magnitudes along the gradient
+    ///
+    /// # Arguments
+    /// * `gradients` - Gradient of every pixel
+    /// * `border` - How neighbours outside the frame are read
+    ///
+    /// # Returns
+    /// * The suppressed magnitudes and the edge positions, row by row
+    pub fn suppress_with_positions(
+        gradients: &Image<PixelGradient>,
+        border: BorderMode,
+    ) -> (Image<u8>, Vec<EdgePoint>) {
+        let width = gradients.width();
+        let height = gradients.height();
 
-                // Select neighbors based on gradient direction
-                // Horizontal edge (angle ~ 0° or 180°)
-                if (0.0 <= angle && angle < 22.5) || (157.5 <= angle && angle <= 180.0) {
-                    neighbor1_mag = gradients[y as usize][(x - 1) as usize].magnitude;
-                    neighbor2_mag = gradients[y as usize][(x + 1) as usize].magnitude;
-                }
-                // Diagonal edge (angle ~ 45°)
-                else if 22.5 <= angle && angle < 67.5 {
-                    neighbor1_mag = gradients[(y - 1) as usize][(x + 1) as usize].magnitude;
-                    neighbor2_mag = gradients[(y + 1) as usize][(x - 1) as usize].magnitude;
-                }
-                // Vertical edge (angle ~ 90°)
-                else if 67.5 <= angle && angle < 112.5 {
-                    neighbor1_mag = gradients[(y - 1) as usize][x as usize].magnitude;
-                    neighbor2_mag = gradients[(y + 1) as usize][x as usize].magnitude;
-                }
-                // Diagonal edge (angle ~ 135°)
-                else if 112.5 <= angle && angle < 157.5 {
-                    neighbor1_mag = gradients[(y - 1) as usize][(x - 1) as usize].magnitude;
-                    neighbor2_mag = gradients[(y + 1) as usize][(x + 1) as usize].magnitude;
+        // create output image of suppressed magnitudes
+        let mut output = Image::new(width, height, 0u8);
+        let mut points = Vec::new();
+
+        // Process all pixels, including the frame edges
+        for y in 0..height {
+            for x in 0..width {
+                let current = gradients[(x, y)];
+                if current.magnitude <= 0.0 {
+                    continue;
                 }
 
-                // If current pixel is local maximum, keep its magnitude
-                let idx = (y * width + x) as usize;
-                if current.magnitude >= neighbor1_mag && current.magnitude >= neighbor2_mag {
-                    // Convert f32 magnitude to u8 for the output Frame
-                    // Clamp value between 0 and 255
-                    output_data[idx] = (current.magnitude.min(255.0).max(0.0)) as u8;
+                // unit step along the gradient, across the edge
+                let (dy, dx) = current.direction.sin_cos();
+                let (fx, fy) = (x as f32, y as f32);
+                let ahead = interpolate_magnitude(gradients, border, fx + dx, fy + dy);
+                let behind = interpolate_magnitude(gradients, border, fx - dx, fy - dy);
+
+                // If current pixel is local maximum, keep its magnitude. Ties with the
+                // pixel ahead go to that pixel so a ridge of equal magnitudes between
+                // two pixels stays one pixel wide
+                if current.magnitude <= ahead || current.magnitude < behind {
+                    continue;
                 }
-                // Otherwise, magnitude remains 0 (suppressed)
+
+                // Convert f32 magnitude to u8 for the output image
+                // Clamp value between 0 and 255
+                output[(x, y)] = current.magnitude.min(255.0) as u8;
+
+                // vertex of the parabola through behind, current and ahead
+                let curvature = behind - 2.0 * current.magnitude + ahead;
+                let offset = if curvature < 0.0 {
+                    (0.5 * (behind - ahead) / curvature).clamp(-0.5, 0.5)
+                } else {
+                    0.0
+                };
+
+                points.push(EdgePoint {
+                    pixel: [x, y],
+                    x: fx + offset * dx,
+                    y: fy + offset * dy,
+                    magnitude: current.magnitude,
+                    direction: current.direction,
+                });
+            }
+        }
+
+        (output, points)
+    }
+}
+
+// bilinear interpolation of the gradient magnitude between pixel centers
+fn interpolate_magnitude(
+    gradients: &Image<PixelGradient>,
+    border: BorderMode,
+    x: f32,
+    y: f32,
+) -> f32 {
+    let x0 = x.floor();
+    let y0 = y.floor();
+    let tx = x - x0;
+    let ty = y - y0;
+    let (x0, y0) = (x0 as isize, y0 as isize);
+
+    let no_gradient = PixelGradient::new(0.0, 0.0);
+    let magnitude = |x: isize, y: isize| {
+        gradients
+            .get_with_border(x, y, border, no_gradient)
+            .magnitude
+    };
+
+    let top = magnitude(x0, y0) * (1.0 - tx) + magnitude(x0 + 1, y0) * tx;
+    let bottom = magnitude(x0, y0 + 1) * (1.0 - tx) + magnitude(x0 + 1, y0 + 1) * tx;
+    top * (1.0 - ty) + bottom * ty
+}
+
+/// Pipeline step thinning the gradients a `GradientCalculation` step earlier in the
+/// pipeline left in the frame context. The frame is replaced by the suppressed
+/// magnitudes.
+pub struct NonMaxSuppression {
+    /// How neighbours outside the frame are read
+    border: BorderMode,
+}
+
+impl NonMaxSuppression {
+    pub fn new(border: BorderMode) -> Self {
+        Self { border }
+    }
+
+    pub fn from_config(config: NonMaxSuppressionConfig) -> Self {
+        Self::new(config.border)
+    }
+}
+
+impl PipelineStep for NonMaxSuppression {
+    fn process(&self, frame: &mut Frame, frame_count: u64) -> io::Result<()> {
+        self.process_with_context(frame, &mut FrameContext::new(frame_count))
+    }
+
+    fn process_with_context(
+        &self,
+        frame: &mut Frame,
+        context: &mut FrameContext,
+    ) -> io::Result<()> {
+        let gradients = context.data::<Image<PixelGradient>>().ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Non max suppression needs the gradients of an earlier gradient step",
+            )
+        })?;
+
+        *frame = GradNonMaxSuppression::suppress(gradients, self.border).into();
+
+        Ok(())
+    }
+
+    fn name(&self) -> &str {
+        "NonMaxSuppression"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::f32::consts::{FRAC_PI_2, FRAC_PI_4};
+
+    #[test]
+    fn diagonal_ramp_keeps_only_the_ridge() {
+        // magnitudes fall off on both sides of the anti-diagonal x + y = 8, with the
+        // gradients pointing across it
+        let gradients = Image::from_fn(9, 9, |x, y| {
+            let distance = (x as f32 + y as f32 - 8.0).abs();
+            PixelGradient::new((100.0 - 20.0 * distance).max(0.0), FRAC_PI_4)
+        });
+
+        let suppressed = GradNonMaxSuppression::suppress(&gradients, BorderMode::default());
+        for y in 0..9 {
+            for x in 0..9 {
+                let expected = if x + y == 8 { 100 } else { 0 };
+                assert_eq!(suppressed[(x, y)], expected, "({}, {})", x, y);
             }
         }
+    }
+
+    #[test]
+    fn subpixel_offset_of_a_parabola_is_recovered() {
+        // a parabola shaped profile with its peak between pixels 4 and 5
+        let peak = 4.3;
+        let profile = |position: usize| 100.0 - 4.0 * (position as f32 - peak).powi(2);
+
+        let horizontal = Image::from_fn(9, 3, |x, _| PixelGradient::new(profile(x), 0.0));
+        let (_, points) =
+            GradNonMaxSuppression::suppress_with_positions(&horizontal, BorderMode::default());
+        assert_eq!(points.len(), 3);
+        for (row, point) in points.iter().enumerate() {
+            assert_eq!(point.pixel, [4, row]);
+            assert!((point.x - peak).abs() < 1e-3, "x {}", point.x);
+            assert!((point.y - row as f32).abs() < 1e-3, "y {}", point.y);
+        }
 
-        // create and return the frame
-        Frame {
-            data: output_data,
-            width,
-            height,
-            channels: 1,
+        let vertical = Image::from_fn(3, 9, |_, y| PixelGradient::new(profile(y), FRAC_PI_2));
+        let (_, points) =
+            GradNonMaxSuppression::suppress_with_positions(&vertical, BorderMode::default());
+        assert_eq!(points.len(), 3);
+        for (column, point) in points.iter().enumerate() {
+            assert_eq!(point.pixel, [column, 4]);
+            assert!((point.x - column as f32).abs() < 1e-3, "x {}", point.x);
+            assert!((point.y - peak).abs() < 1e-3, "y {}", point.y);
         }
     }
 }
@@ -0,0 +1,92 @@
+use crate::frame::{Frame, PixelFormat};
+
+/// Convert a frame to RGB so colored overlays can be drawn onto it
+pub fn to_canvas(frame: &mut Frame) {
+    if frame.format() != PixelFormat::Rgb {
+        *frame = frame.convert(PixelFormat::Rgb);
+    }
+}
+
+/// Color a single pixel of an RGB frame, positions outside the frame are ignored
+pub fn set_pixel(frame: &mut Frame, x: i32, y: i32, color: [u8; 3]) {
+    if x < 0 || y < 0 || x >= frame.width() || y >= frame.height() {
+        return;
+    }
+
+    let index = ((y * frame.width() + x) * 3) as usize;
+    frame.data_mut()[index..index + 3].copy_from_slice(&color);
+}
+
+/// Draw a one pixel wide line between two points of an RGB frame
+///
+/// # Arguments
+/// * `frame` - The RGB frame to draw onto
+/// * `start` - First end of the line, as `[x, y]`
+/// * `end` - Second end of the line, as `[x, y]`
+/// * `color` - Red, green and blue of the line
+pub fn draw_line(frame: &mut Frame, start: [f32; 2], end: [f32; 2], color: [u8; 3]) {
+    let (mut x, mut y) = (start[0].round() as i32, start[1].round() as i32);
+    let (x1, y1) = (end[0].round() as i32, end[1].round() as i32);
+
+    // Bresenham, stepping along the major axis
+    let dx = (x1 - x).abs();
+    let dy = -(y1 - y).abs();
+    let step_x = if x < x1 { 1 } else { -1 };
+    let step_y = if y < y1 { 1 } else { -1 };
+    let mut error = dx + dy;
+
+    loop {
+        set_pixel(frame, x, y, color);
+        if x == x1 && y == y1 {
+            break;
+        }
+
+        let doubled = 2 * error;
+        if doubled >= dy {
+            error += dy;
+            x += step_x;
+        }
+        if doubled <= dx {
+            error += dx;
+            y += step_y;
+        }
+    }
+}
+
+/// Draw a one pixel wide circle onto an RGB frame
+///
+/// # Arguments
+/// * `frame` - The RGB frame to draw onto
+/// * `center` - Center of the circle, as `[x, y]`
+/// * `radius` - Radius of the circle in pixels
+/// * `color` - Red, green and blue of the circle
+pub fn draw_circle(frame: &mut Frame, center: [f32; 2], radius: f32, color: [u8; 3]) {
+    let (cx, cy) = (center[0].round() as i32, center[1].round() as i32);
+    let radius = radius.round() as i32;
+
+    // midpoint circle, mirroring one octant into the other seven
+    let (mut x, mut y) = (radius, 0);
+    let mut error = 1 - radius;
+    while x >= y {
+        for (px, py) in [
+            (x, y),
+            (y, x),
+            (-y, x),
+            (-x, y),
+            (-x, -y),
+            (-y, -x),
+            (y, -x),
+            (x, -y),
+        ] {
+            set_pixel(frame, cx + px, cy + py, color);
+        }
+
+        y += 1;
+        if error < 0 {
+            error += 2 * y + 1;
+        } else {
+            x -= 1;
+            error += 2 * (y - x) + 1;
+        }
+    }
+}
@@ -0,0 +1,479 @@
+use serde::Serialize;
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
+use std::fmt;
+use std::io;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+thread_local! {
+    // bytes and number of allocations made by the current thread, kept by the allocator
+    static ALLOCATED_BYTES: Cell<u64> = const { Cell::new(0) };
+    static ALLOCATIONS: Cell<u64> = const { Cell::new(0) };
+    // small stable number of the current thread for the trace viewer
+    static THREAD_INDEX: u64 = NEXT_THREAD_INDEX.fetch_add(1, Ordering::Relaxed);
+}
+
+static NEXT_THREAD_INDEX: AtomicU64 = AtomicU64::new(1);
+
+fn count_allocation(bytes: usize) {
+    // the counters are gone while a thread shuts down, those allocations are not counted
+    let _ = ALLOCATED_BYTES.try_with(|total| total.set(total.get() + bytes as u64));
+    let _ = ALLOCATIONS.try_with(|count| count.set(count.get() + 1));
+}
+
+/// Global allocator forwarding to the system allocator while counting how much every
+/// thread allocates, so the profiler can attribute allocations to the step running
+/// on that thread. Growing an allocation counts the added bytes.
+///
+/// Counting costs two thread local updates on every allocation, whether profiling or
+/// not, so the binary only installs it with the `count-allocations` feature.
+pub struct CountingAllocator;
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        count_allocation(layout.size());
+        System.alloc(layout)
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        count_allocation(layout.size());
+        System.alloc_zeroed(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        count_allocation(new_size.saturating_sub(layout.size()));
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+// bytes and allocations of the current thread so far
+fn thread_allocations() -> (u64, u64) {
+    (
+        ALLOCATED_BYTES.with(|total| total.get()),
+        ALLOCATIONS.with(|count| count.get()),
+    )
+}
+
+/// Runs of a step kept for the percentiles, longer runs keep a uniform sample of them
+const RESERVOIR_SIZE: usize = 4096;
+
+/// Whether the binary counts allocations, which needs the `count-allocations` feature
+/// to install `CountingAllocator` as the global allocator
+pub const COUNTS_ALLOCATIONS: bool = cfg!(feature = "count-allocations");
+
+/// One run of a step on a frame, kept for the trace
+#[derive(Debug, Clone)]
+pub struct StepSample {
+    /// Position of the step in the pipeline, starting at 1
+    pub step: usize,
+    pub frame: u64,
+    /// Number of the worker thread that ran the step
+    pub thread: u64,
+    /// When the step started, relative to the creation of the profiler
+    pub start: Duration,
+    pub duration: Duration,
+    /// Bytes allocated while the step ran
+    pub allocated_bytes: u64,
+    pub allocations: u64,
+}
+
+/// Running totals of one step
+struct StepTotals {
+    name: String,
+    runs: u64,
+    duration: Duration,
+    max_duration: Duration,
+    allocated_bytes: u64,
+    allocations: u64,
+    // uniform sample of the run durations in milliseconds
+    reservoir: Vec<f64>,
+}
+
+/// What the profiler collected so far
+struct ProfileState {
+    // totals of every step, by position in the pipeline
+    steps: Vec<Option<StepTotals>>,
+    // wall time from the first step starting to the last step finishing
+    first_start: Option<Duration>,
+    last_end: Duration,
+    // every run, only kept for the trace
+    samples: Option<Vec<StepSample>>,
+    // xorshift state picking the runs kept in the reservoirs
+    random: u64,
+}
+
+impl ProfileState {
+    fn next_random(&mut self) -> u64 {
+        self.random ^= self.random << 13;
+        self.random ^= self.random >> 7;
+        self.random ^= self.random << 17;
+        self.random
+    }
+}
+
+/// Collects the wall time and allocations of every step on every frame. Keeps running
+/// totals and a bounded sample of the durations per step, so memory stays flat over
+/// long runs, and every single run only when recording a trace.
+/// Shared between worker threads.
+pub struct Profiler {
+    // time zero of the sample start times
+    epoch: Instant,
+    state: Mutex<ProfileState>,
+    frames: AtomicU64,
+}
+
+impl Profiler {
+    pub fn new() -> Self {
+        Self::with_samples(false)
+    }
+
+    /// Create a profiler that also keeps every run for `write_chrome_trace`
+    pub fn with_trace() -> Self {
+        Self::with_samples(true)
+    }
+
+    fn with_samples(keep_samples: bool) -> Self {
+        Self {
+            epoch: Instant::now(),
+            state: Mutex::new(ProfileState {
+                steps: Vec::new(),
+                first_start: None,
+                last_end: Duration::ZERO,
+                samples: keep_samples.then(Vec::new),
+                random: 0x9E37_79B9_7F4A_7C15,
+            }),
+            frames: AtomicU64::new(0),
+        }
+    }
+
+    /// Run a step and record how long it took and how much it allocated
+    ///
+    /// # Arguments
+    /// * `step` - Position of the step in the pipeline, starting at 1
+    /// * `name` - Name of the step
+    /// * `frame` - Number of the frame the step runs on
+    /// * `run` - Runs the step on the current thread
+    pub fn measure<T>(&self, step: usize, name: &str, frame: u64, run: impl FnOnce() -> T) -> T {
+        let (bytes_before, allocations_before) = thread_allocations();
+        let start = Instant::now();
+
+        let result = run();
+
+        let duration = start.elapsed();
+        let (bytes_after, allocations_after) = thread_allocations();
+
+        let sample = StepSample {
+            step,
+            frame,
+            thread: THREAD_INDEX.with(|index| *index),
+            start: start.duration_since(self.epoch),
+            duration,
+            allocated_bytes: bytes_after - bytes_before,
+            allocations: allocations_after - allocations_before,
+        };
+        self.record(name, sample);
+
+        result
+    }
+
+    fn record(&self, name: &str, sample: StepSample) {
+        let mut state = self.state.lock().unwrap();
+
+        let first_start = state
+            .first_start
+            .map_or(sample.start, |first| first.min(sample.start));
+        state.first_start = Some(first_start);
+        state.last_end = state.last_end.max(sample.start + sample.duration);
+
+        if state.steps.len() < sample.step {
+            state.steps.resize_with(sample.step, || None);
+        }
+        let random = state.next_random();
+        let totals = state.steps[sample.step - 1].get_or_insert_with(|| StepTotals {
+            name: name.to_string(),
+            runs: 0,
+            duration: Duration::ZERO,
+            max_duration: Duration::ZERO,
+            allocated_bytes: 0,
+            allocations: 0,
+            reservoir: Vec::new(),
+        });
+
+        totals.runs += 1;
+        totals.duration += sample.duration;
+        totals.max_duration = totals.max_duration.max(sample.duration);
+        totals.allocated_bytes += sample.allocated_bytes;
+        totals.allocations += sample.allocations;
+
+        // reservoir sampling, every run ends up in the reservoir with the same chance
+        let milliseconds = sample.duration.as_secs_f64() * 1e3;
+        if totals.reservoir.len() < RESERVOIR_SIZE {
+            totals.reservoir.push(milliseconds);
+        } else {
+            let slot = (random % totals.runs) as usize;
+            if slot < RESERVOIR_SIZE {
+                totals.reservoir[slot] = milliseconds;
+            }
+        }
+
+        if let Some(samples) = state.samples.as_mut() {
+            samples.push(sample);
+        }
+    }
+
+    /// Count a frame that went through every step
+    pub fn frame_done(&self) {
+        self.frames.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Summarize the runs per step, in pipeline order
+    pub fn report(&self) -> ProfileReport {
+        let state = self.state.lock().unwrap();
+
+        let elapsed = match state.first_start {
+            Some(first) => state.last_end - first,
+            None => Duration::ZERO,
+        };
+
+        let frames = self.frames.load(Ordering::Relaxed);
+        let fps = if elapsed > Duration::ZERO {
+            frames as f64 / elapsed.as_secs_f64()
+        } else {
+            0.0
+        };
+
+        let steps = state
+            .steps
+            .iter()
+            .enumerate()
+            .filter_map(|(index, totals)| Some(StepStats::from_totals(index + 1, totals.as_ref()?)))
+            .collect();
+
+        ProfileReport {
+            frames,
+            elapsed_seconds: elapsed.as_secs_f64(),
+            fps,
+            steps,
+        }
+    }
+
+    /// Write every run as a complete event of the Chrome trace event format, which
+    /// chrome://tracing and Perfetto open. Needs a profiler created with `with_trace`.
+    pub fn write_chrome_trace(&self, path: &Path) -> io::Result<()> {
+        let state = self.state.lock().unwrap();
+        let samples = state.samples.as_ref().ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "The profiler was created without keeping the runs for a trace",
+            )
+        })?;
+
+        let events: Vec<serde_json::Value> = samples
+            .iter()
+            .map(|sample| {
+                let name = state.steps[sample.step - 1]
+                    .as_ref()
+                    .map_or("", |totals| totals.name.as_str());
+
+                serde_json::json!({
+                    "name": name,
+                    "cat": "step",
+                    "ph": "X",
+                    "ts": sample.start.as_secs_f64() * 1e6,
+                    "dur": sample.duration.as_secs_f64() * 1e6,
+                    "pid": 1,
+                    "tid": sample.thread,
+                    "args": {
+                        "step": sample.step,
+                        "frame": sample.frame,
+                        "allocated_bytes": sample.allocated_bytes,
+                        "allocations": sample.allocations,
+                    },
+                })
+            })
+            .collect();
+
+        let json = serde_json::to_string(&serde_json::json!({
+            "traceEvents": events,
+            "displayTimeUnit": "ms",
+        }))
+        .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
+        std::fs::write(path, json)
+    }
+}
+
+/// Timing and allocation statistics of one step over all frames. The percentiles come
+/// from a uniform sample of the runs once a step ran more than 4096 times.
+#[derive(Debug, Serialize)]
+pub struct StepStats {
+    /// Position of the step in the pipeline, starting at 1
+    pub step: usize,
+    pub name: String,
+    pub runs: u64,
+    pub mean_ms: f64,
+    pub p50_ms: f64,
+    pub p95_ms: f64,
+    pub max_ms: f64,
+    pub total_ms: f64,
+    /// Mean bytes allocated per run, `None` when allocations are not counted
+    pub mean_allocated_bytes: Option<f64>,
+    /// Mean number of allocations per run, `None` when allocations are not counted
+    pub mean_allocations: Option<f64>,
+}
+
+impl StepStats {
+    fn from_totals(step: usize, totals: &StepTotals) -> Self {
+        let runs = totals.runs as f64;
+
+        let mut durations = totals.reservoir.clone();
+        durations.sort_by(|a, b| a.total_cmp(b));
+
+        // nearest rank percentile of the sorted durations
+        let count = durations.len();
+        let percentile =
+            |p: f64| durations[((p * count as f64).ceil() as usize).clamp(1, count) - 1];
+        let total_ms = totals.duration.as_secs_f64() * 1e3;
+
+        Self {
+            step,
+            name: totals.name.clone(),
+            runs: totals.runs,
+            mean_ms: total_ms / runs,
+            p50_ms: percentile(0.5),
+            p95_ms: percentile(0.95),
+            max_ms: totals.max_duration.as_secs_f64() * 1e3,
+            total_ms,
+            mean_allocated_bytes: COUNTS_ALLOCATIONS.then(|| totals.allocated_bytes as f64 / runs),
+            mean_allocations: COUNTS_ALLOCATIONS.then(|| totals.allocations as f64 / runs),
+        }
+    }
+}
+
+/// Summary of a profiled run
+#[derive(Debug, Serialize)]
+pub struct ProfileReport {
+    /// Number of frames that went through every step
+    pub frames: u64,
+    /// Wall time from the first step starting to the last step finishing
+    pub elapsed_seconds: f64,
+    /// Frames per second over the elapsed time
+    pub fps: f64,
+    pub steps: Vec<StepStats>,
+}
+
+impl ProfileReport {
+    /// Save the report as JSON
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        let json = serde_json::to_string_pretty(self)
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
+        std::fs::write(path, json)
+    }
+}
+
+impl fmt::Display for ProfileReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "{:<32} {:>6} {:>10} {:>10} {:>10} {:>10} {:>12}",
+            "step", "runs", "mean ms", "p50 ms", "p95 ms", "max ms", "alloc/run"
+        )?;
+
+        for step in &self.steps {
+            writeln!(
+                f,
+                "{:<32} {:>6} {:>10.3} {:>10.3} {:>10.3} {:>10.3} {:>12}",
+                format!("{} {}", step.step, step.name),
+                step.runs,
+                step.mean_ms,
+                step.p50_ms,
+                step.p95_ms,
+                step.max_ms,
+                step.mean_allocated_bytes
+                    .map_or_else(|| "-".to_string(), format_bytes),
+            )?;
+        }
+
+        write!(
+            f,
+            "{} frames in {:.3} s, {:.2} fps",
+            self.frames, self.elapsed_seconds, self.fps
+        )
+    }
+}
+
+// human readable byte count
+fn format_bytes(bytes: f64) -> String {
+    const UNITS: [&str; 4] = ["B", "KiB", "MiB", "GiB"];
+
+    let mut value = bytes;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+
+    format!("{:.1} {}", value, UNITS[unit])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn totals_per_step_in_pipeline_order() {
+        let profiler = Profiler::new();
+        for frame in 0..3 {
+            profiler.measure(3, "Contours", frame, || ());
+            profiler.measure(1, "Canny", frame, || ());
+            profiler.frame_done();
+        }
+
+        let report = profiler.report();
+        assert_eq!(report.frames, 3);
+        let steps: Vec<(usize, &str, u64)> = report
+            .steps
+            .iter()
+            .map(|stats| (stats.step, stats.name.as_str(), stats.runs))
+            .collect();
+        assert_eq!(steps, [(1, "Canny", 3), (3, "Contours", 3)]);
+        assert!(report.steps[0].p50_ms <= report.steps[0].max_ms);
+    }
+
+    #[test]
+    fn memory_stays_bounded_without_trace() {
+        let profiler = Profiler::new();
+        let runs = RESERVOIR_SIZE as u64 + 100;
+        for frame in 0..runs {
+            profiler.measure(1, "Blur", frame, || ());
+        }
+
+        let state = profiler.state.lock().unwrap();
+        let totals = state.steps[0].as_ref().unwrap();
+        assert_eq!(totals.runs, runs);
+        assert_eq!(totals.reservoir.len(), RESERVOIR_SIZE);
+        assert!(state.samples.is_none());
+    }
+
+    #[test]
+    fn trace_needs_the_runs() {
+        let path = std::env::temp_dir().join("anuvis_profiler_trace.json");
+        assert!(Profiler::new().write_chrome_trace(&path).is_err());
+
+        let profiler = Profiler::with_trace();
+        profiler.measure(2, "Hough", 7, || ());
+        profiler.write_chrome_trace(&path).unwrap();
+
+        let trace: serde_json::Value =
+            serde_json::from_str(&std::fs::read_to_string(&path).unwrap()).unwrap();
+        let event = &trace["traceEvents"][0];
+        assert_eq!(event["name"], "Hough");
+        assert_eq!(event["args"]["frame"], 7);
+    }
+}
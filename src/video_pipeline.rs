@@ -1,178 +1,215 @@
+use crate::frame::{Frame, PixelFormat};
+use crate::frame_sampling::{FrameSampler, FrameSampling, SampleDecision};
+use crate::video_source::{TestPattern, VideoSource};
 use gst::prelude::*;
 use gstreamer as gst;
 use gstreamer_app::{self as gst_app, AppSink};
-use image::{ImageBuffer, Rgb};
+use gstreamer_video as gst_video;
+use serde::{Serialize, Serializer};
+use std::error::Error;
+use std::fmt;
 use std::io;
-use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
 use std::thread;
 use std::time::Duration;
 
-#[derive(Debug, Clone)]
-pub struct Frame {
-    pub data: Vec<u8>,
-    pub width: i32,
-    pub height: i32,
-    pub channels: i32,
+/// Where a frame came from and when it is presented in the source video
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct FrameInfo {
+    /// Zero based position of the frame in the source
+    pub frame_number: u64,
+    /// Presentation timestamp relative to the start of the stream
+    #[serde(rename = "pts_seconds", serialize_with = "serialize_seconds")]
+    pub pts: Option<Duration>,
+    /// How long the frame is displayed
+    #[serde(rename = "duration_seconds", serialize_with = "serialize_seconds")]
+    pub duration: Option<Duration>,
+    /// Identifier of the stream the frame was decoded from
+    pub stream_id: Option<String>,
+    /// Pixel format produced by the decoder, before conversion
+    pub source_format: Option<String>,
+    /// Pixel format of the delivered frame data
+    pub pixel_format: String,
+    /// Problems the source reported since the previous frame that did not stop it,
+    /// such as a corrupt frame the decoder skipped
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub warnings: Vec<String>,
 }
 
-impl Frame {
-    pub fn get_pixel(&self, x: i32, y: i32) -> Option<(i32, i32, i32)> {
-        if x < 0 || x >= self.width || y < 0 || y >= self.height {
-            return None;
-        }
+// write durations as fractional seconds, which is what analytics tooling expects
+fn serialize_seconds<S: Serializer>(
+    value: &Option<Duration>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    match value {
+        Some(duration) => serializer.serialize_some(&duration.as_secs_f64()),
+        None => serializer.serialize_none(),
+    }
+}
 
-        // Calculate position in the data array
-        let index = match self.channels {
-            1 => (y * self.width + x) as usize,
-            3 => (y * self.width * 3 + x * 3) as usize,
-            _ => return None,
-        };
+/// Format a duration as HH:MM:SS.mmm
+pub fn format_timestamp(duration: Duration) -> String {
+    let millis = duration.as_millis();
+    format!(
+        "{:02}:{:02}:{:02}.{:03}",
+        millis / 3_600_000,
+        (millis / 60_000) % 60,
+        (millis / 1000) % 60,
+        millis % 1000
+    )
+}
 
-        match self.channels {
-            1 => {
-                if index >= self.data.len() {
-                    return None;
-                }
-                // For grayscale, return the same value for R, G, and B
-                let value = self.data[index] as i32;
-                Some((value, value, value))
-            }
-            3 => {
-                if index + 2 >= self.data.len() {
-                    return None;
-                }
-                Some((
-                    self.data[index] as i32,
-                    self.data[index + 1] as i32,
-                    self.data[index + 2] as i32,
-                ))
-            }
-            _ => None,
+impl fmt::Display for FrameInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.pts {
+            Some(pts) => write!(f, "frame {} @ {}", self.frame_number, format_timestamp(pts)),
+            None => write!(f, "frame {}", self.frame_number),
         }
     }
+}
 
-    pub fn to_grayscale(&mut self) -> &mut Self {
-        // Early return if already grayscale
-        if self.channels == 1 {
-            return self;
-        }
-
-        let size = (self.width * self.height) as usize;
-        let mut gray_data = vec![0u8; size];
+// read the format field of the first caps structure
+fn caps_format(caps: &gst::CapsRef) -> Option<String> {
+    caps.structure(0)
+        .and_then(|s| s.get::<String>("format").ok())
+}
 
-        // Convert using direct indexing
-        for y in 0..self.height {
-            for x in 0..self.width {
-                if let Some((r, g, b)) = self.get_pixel(x, y) {
-                    let idx = (y * self.width + x) as usize;
-                    gray_data[idx] =
-                        ((0.299 * r as f32) + (0.587 * g as f32) + (0.114 * b as f32)) as u8;
-                }
-            }
-        }
+/// Failures of a `VideoPipeline`
+#[derive(Debug)]
+pub enum VideoError {
+    /// GStreamer itself could not be initialized
+    Init(String),
+    /// The input path or uri could not be opened
+    Input(String),
+    /// An element or plugin required to build or decode the stream is not installed
+    MissingElement(String),
+    /// Elements could not be added to the pipeline or linked together
+    LinkFailed(String),
+    /// The pipeline refused a state change without reporting a more specific error
+    StateChange(String),
+    /// The stream formats of two elements could not be agreed on
+    CapsNegotiation(String),
+    /// An element reported an error while reading or decoding the stream
+    Decode {
+        source: String,
+        message: String,
+        debug: Option<String>,
+    },
+    /// No frame arrived within the allowed time
+    Timeout(String),
+    /// A still image could not be found or decoded
+    Image(String),
+    /// A frame could not be encoded into the output video
+    Encode(String),
+    /// The end of the stream was reached
+    Eos,
+}
 
-        // Replace the existing data
-        self.data = gray_data;
-        self.channels = 1;
+impl VideoError {
+    // classify an error message posted on the pipeline bus
+    pub(crate) fn from_bus_error(err: &gst::message::Error) -> Self {
+        Self::classify(err.src(), err.error(), err.debug())
+    }
 
-        self
+    // classify a warning posted on the pipeline bus the same way as an error
+    fn from_bus_warning(warning: &gst::message::Warning) -> Self {
+        Self::classify(warning.src(), warning.error(), warning.debug())
     }
 
-    pub fn print_pixel(&self, x: i32, y: i32) {
-        match self.get_pixel(x, y) {
-            Some((r, g, b)) => {
-                if self.channels == 1 {
-                    println!(
-                        "Pixel at ({}, {}): Grayscale({}) - Hex: #{:02X}{:02X}{:02X}",
-                        x, y, r, r as u8, r as u8, r as u8
-                    );
-                } else {
-                    println!(
-                        "Pixel at ({}, {}): RGB({}, {}, {}) - Hex: #{:02X}{:02X}{:02X}",
-                        x, y, r, g, b, r as u8, g as u8, b as u8
-                    );
-                }
+    fn classify(
+        src: Option<&gst::Object>,
+        error: gst::glib::Error,
+        debug: Option<gst::glib::GString>,
+    ) -> Self {
+        let source = src
+            .map(|src| src.path_string().to_string())
+            .unwrap_or_else(|| "unknown".to_string());
+        let debug = debug.map(|debug| debug.to_string());
+
+        let not_negotiated = error.matches(gst::CoreError::Negotiation)
+            || error.matches(gst::StreamError::Format)
+            || debug
+                .as_deref()
+                .map_or(false, |debug| debug.contains("not-negotiated"));
+
+        if not_negotiated {
+            VideoError::CapsNegotiation(format!("{}: {}", source, error))
+        } else if error.matches(gst::CoreError::MissingPlugin)
+            || error.matches(gst::StreamError::CodecNotFound)
+        {
+            VideoError::MissingElement(format!("{} ({})", error, source))
+        } else {
+            VideoError::Decode {
+                source,
+                message: error.to_string(),
+                debug,
             }
-            None => println!("Pixel position ({}, {}) is out of bounds", x, y),
         }
     }
+}
 
-    pub fn save(&self, path: &PathBuf) -> io::Result<()> {
-        match self.channels {
-            1 => {
-                // Pre-allocate RGB buffer with exact size
-                let size = (self.width * self.height * 3) as usize;
-                let mut rgb_data = vec![0u8; size];
-
-                // Direct indexing instead of flat_map
-                for (i, &v) in self.data.iter().enumerate() {
-                    let rgb_idx = i * 3;
-                    rgb_data[rgb_idx] = v;
-                    rgb_data[rgb_idx + 1] = v;
-                    rgb_data[rgb_idx + 2] = v;
+impl fmt::Display for VideoError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            VideoError::Init(msg) => write!(f, "Failed to initialize GStreamer: {}", msg),
+            VideoError::Input(msg) => write!(f, "Invalid input: {}", msg),
+            VideoError::MissingElement(msg) => write!(f, "Missing GStreamer element: {}", msg),
+            VideoError::LinkFailed(msg) => write!(f, "Link failure: {}", msg),
+            VideoError::StateChange(msg) => write!(f, "State change failure: {}", msg),
+            VideoError::CapsNegotiation(msg) => write!(f, "Caps negotiation failure: {}", msg),
+            VideoError::Decode {
+                source,
+                message,
+                debug,
+            } => {
+                write!(f, "Decode error from {}: {}", source, message)?;
+                if let Some(debug) = debug {
+                    write!(f, " ({})", debug)?;
                 }
-
-                let img_buffer = ImageBuffer::<Rgb<u8>, _>::from_raw(
-                    self.width as u32,
-                    self.height as u32,
-                    rgb_data,
-                )
-                .ok_or_else(|| {
-                    io::Error::new(io::ErrorKind::InvalidData, "Failed to create image buffer")
-                })?;
-
-                img_buffer.save(path).unwrap();
-                Ok(())
-            }
-            3 => {
-                let img_buffer = ImageBuffer::<Rgb<u8>, _>::from_raw(
-                    self.width as u32,
-                    self.height as u32,
-                    self.data.to_vec(),
-                )
-                .ok_or_else(|| {
-                    io::Error::new(io::ErrorKind::InvalidData, "Failed to create image buffer")
-                })?;
-
-                img_buffer.save(path).unwrap();
                 Ok(())
             }
-            _ => Err(io::Error::new(
-                io::ErrorKind::InvalidInput,
-                format!("Unsupported number of channels: {}", self.channels),
-            )),
+            VideoError::Timeout(msg) => write!(f, "Timed out: {}", msg),
+            VideoError::Image(msg) => write!(f, "Image error: {}", msg),
+            VideoError::Encode(msg) => write!(f, "Encode error: {}", msg),
+            VideoError::Eos => write!(f, "End of stream"),
         }
     }
+}
+
+impl Error for VideoError {}
 
-    // Optional: Add a conversion back to RGB if needed
-    pub fn to_rgb(self) -> Frame {
-        if self.channels == 3 {
-            return self;
+impl From<VideoError> for io::Error {
+    fn from(error: VideoError) -> Self {
+        match error {
+            VideoError::Eos => io::Error::new(io::ErrorKind::UnexpectedEof, error.to_string()),
+            VideoError::Timeout(_) => io::Error::new(io::ErrorKind::TimedOut, error.to_string()),
+            _ => io::Error::new(io::ErrorKind::Other, error.to_string()),
         }
+    }
+}
 
-        let width = self.width;
-        let height = self.height;
+/// How decoded frames are handed to `next_frame`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DeliveryMode {
+    /// Every decoded frame is delivered, decoding waits while the consumer is busy
+    Lossless,
+    /// Only the latest frame is kept, frames are played in real time and older ones are dropped
+    Live,
+}
 
-        let size = (self.width * self.height * 3) as usize;
-        let mut rgb_data = vec![0u8; size];
+// sampling state shared with the pad probe in front of the colour conversion
+struct SamplingState {
+    // decides which decoded frames are kept
+    sampler: FrameSampler,
 
-        for (i, &value) in self.data.iter().enumerate() {
-            let rgb_idx = i * 3;
-            rgb_data[rgb_idx] = value;
-            rgb_data[rgb_idx + 1] = value;
-            rgb_data[rgb_idx + 2] = value;
-        }
+    // number of decoded frames seen since the pipeline started
+    next_index: u64,
 
-        // Drop original data
-        drop(self);
+    // sampling is bypassed while new probes the stream for its dimensions
+    enabled: bool,
 
-        Frame {
-            data: rgb_data,
-            width: width,
-            height: height,
-            channels: 3,
-        }
-    }
+    // set once the sampler is exhausted and eos has been sent
+    finished: bool,
 }
 
 // gstreamer pipeline to handle video processing frame by frame
@@ -188,92 +225,118 @@ pub struct VideoPipeline {
 
     // height of the video
     height: i32,
+
+    // frame rate of the video, 0/1 when variable or unknown
+    framerate: gst::Fraction,
+
+    // frame selection applied before colour conversion
+    sampling: Arc<Mutex<SamplingState>>,
+
+    // sink pad of the colour conversion, its caps carry the decoded pixel format
+    convert_sink: gst::Pad,
+
+    // how frames are handed from the appsink to next_frame
+    delivery: DeliveryMode,
+
+    // where frames are read from
+    source: VideoSource,
 }
 
 impl VideoPipeline {
     // create a new video pipeline
-    pub fn new(input: &str) -> Result<Self, gst::glib::Error> {
-        // init gstreamer if not already initialized
-        gst::init()?;
+    pub fn new(input: &str) -> Result<Self, VideoError> {
+        // init gstreamer if not already initialized, the only glib error reported as Init
+        gst::init().map_err(|e| VideoError::Init(e.to_string()))?;
+
+        // pick the source from the input scheme
+        let source = VideoSource::parse(input).map_err(VideoError::Input)?;
 
         // create pipleine
         let pipeline = gst::Pipeline::new();
 
         // create elements
-        let src = gst::ElementFactory::make_with_name("filesrc", None).map_err(|_e| {
-            gst::glib::Error::new(
-                gst::LibraryError::Failed,
-                "Failed to create filesrc element",
-            )
-        })?;
-        let demux = gst::ElementFactory::make_with_name("matroskademux", None).map_err(|_e| {
-            gst::glib::Error::new(
-                gst::LibraryError::Failed,
-                "Failed to create demuxer element",
-            )
-        })?;
-        let decode = gst::ElementFactory::make_with_name("decodebin", None).map_err(|_e| {
-            gst::glib::Error::new(
-                gst::LibraryError::Failed,
-                "Failed to create decodebin element",
-            )
-        })?;
-        let convert = gst::ElementFactory::make_with_name("videoconvert", None).map_err(|_e| {
-            gst::glib::Error::new(
-                gst::LibraryError::Failed,
-                "Failed to create convert element",
-            )
-        })?;
-
-        // build the caps for RGB only, no size specified
-        let caps = gst::Caps::builder("video/x-raw")
-            .field("format", &"RGB")
+        let convert = Self::make_element("videoconvert")?;
+
+        // accept every format a Frame can hold so decoded video is passed through
+        // unconverted, anything else is converted to RGB
+        let caps = gst_video::VideoCapsBuilder::new()
+            .format_list([
+                gst_video::VideoFormat::Rgb,
+                gst_video::VideoFormat::Bgr,
+                gst_video::VideoFormat::Rgba,
+                gst_video::VideoFormat::Gray8,
+                gst_video::VideoFormat::Gray16Le,
+                gst_video::VideoFormat::I420,
+                gst_video::VideoFormat::Nv12,
+            ])
             .build();
 
-        // create appsink
+        // create appsink, delivery defaults to lossless where a full appsink blocks decoding
         let sink: AppSink = gst_app::AppSink::builder()
             .name("appsink")
             .caps(&caps)
             .max_buffers(2)
-            .drop(true)
+            .drop(false)
             .build();
-
-        // set the video file path
-        src.set_property("location", input);
+        sink.set_property("sync", false);
 
         // add elements to pipeline
         pipeline
-            .add_many(&[&src, &demux, &decode, &convert, &sink.upcast_ref()])
+            .add_many(&[&convert, &sink.upcast_ref()])
             .map_err(|_e| {
-                gst::glib::Error::new(
-                    gst::LibraryError::Failed,
-                    "Failed to add elements to pipeline",
-                )
+                VideoError::LinkFailed("Failed to add elements to pipeline".to_string())
             })?;
 
         // link elements
-        src.link(&demux).map_err(|_e| {
-            gst::glib::Error::new(gst::LibraryError::Failed, "Failed to link src to demux")
-        })?;
-        convert.link(&sink).map_err(|_e| {
-            gst::glib::Error::new(gst::LibraryError::Failed, "Failed to link convert to sink")
-        })?;
-
-        // connect to demux pad-added signal
-        let decode_weak = decode.downgrade();
-        demux.connect_pad_added(move |_demux, pad| {
-            if let Some(decode) = decode_weak.upgrade() {
-                let sink_pad = decode.static_pad("sink").unwrap();
-                let _ = pad.link(&sink_pad);
+        convert
+            .link(&sink)
+            .map_err(|_e| VideoError::LinkFailed("Failed to link convert to sink".to_string()))?;
+
+        // build the source part in front of the converter
+        Self::add_source(&pipeline, &source, &convert)?;
+
+        // drop unselected frames before they are converted
+        let sampling = Arc::new(Mutex::new(SamplingState {
+            sampler: FrameSampler::new(FrameSampling::All),
+            next_index: 0,
+            enabled: false,
+            finished: false,
+        }));
+        let probe_sampling = sampling.clone();
+        let convert_sink = convert.static_pad("sink").unwrap();
+        convert_sink.add_probe(gst::PadProbeType::BUFFER, move |pad, info| {
+            let mut state = probe_sampling.lock().unwrap();
+            if !state.enabled {
+                return gst::PadProbeReturn::Ok;
             }
-        });
 
-        // Connect to decoder's pad-added signal
-        let convert_weak = convert.downgrade();
-        decode.connect_pad_added(move |_, src_pad| {
-            if let Some(convert) = convert_weak.upgrade() {
-                let sink_pad = convert.static_pad("sink").unwrap();
-                let _ = src_pad.link(&sink_pad);
+            let buffer = match info.data {
+                Some(gst::PadProbeData::Buffer(ref mut buffer)) => buffer,
+                _ => return gst::PadProbeReturn::Ok,
+            };
+
+            let index = state.next_index;
+            state.next_index += 1;
+
+            let pts = buffer.pts().map(|pts| Duration::from_nanos(pts.nseconds()));
+            let keyframe = !buffer.flags().contains(gst::BufferFlags::DELTA_UNIT);
+
+            match state.sampler.decide(index, pts, keyframe) {
+                SampleDecision::Keep => {
+                    // tag the buffer with its source frame number, conversion keeps the offset
+                    buffer.make_mut().set_offset(index);
+                    gst::PadProbeReturn::Ok
+                }
+                SampleDecision::Skip => gst::PadProbeReturn::Drop,
+                SampleDecision::Done => {
+                    // nothing else will be selected, end the stream instead of decoding the rest
+                    if !state.finished {
+                        state.finished = true;
+                        drop(state);
+                        let _ = pad.send_event(gst::event::Eos::new());
+                    }
+                    gst::PadProbeReturn::Drop
+                }
             }
         });
 
@@ -283,24 +346,51 @@ impl VideoPipeline {
             appsink: sink,
             width: 0,
             height: 0,
+            framerate: gst::Fraction::new(0, 1),
+            sampling,
+            convert_sink,
+            delivery: DeliveryMode::Lossless,
+            source,
         };
 
         // Start pipeline temporarily to get video info
-        pipeline
-            .pipeline
-            .set_state(gst::State::Playing)
-            .map_err(|_e| {
-                gst::glib::Error::new(
-                    gst::LibraryError::Failed,
-                    "Failed to set pipeline state to playing",
-                )
-            })?;
+        pipeline.set_state(gst::State::Playing)?;
 
         // Wait for up to 5 seconds for the first frame
         let start_time = std::time::Instant::now();
         let timeout = Duration::from_secs(5);
+        let bus = pipeline.pipeline.bus().unwrap();
+        let mut missing_plugins: Vec<String> = Vec::new();
+        let mut decode_error: Option<VideoError> = None;
 
         while start_time.elapsed() < timeout {
+            // collect decoder failures so we can report why no frame arrived
+            while let Some(msg) =
+                bus.pop_filtered(&[gst::MessageType::Error, gst::MessageType::Element])
+            {
+                match msg.view() {
+                    gst::MessageView::Element(element) => {
+                        if let Some(s) = element.structure() {
+                            if s.name().as_str() == "missing-plugin" {
+                                let description = s
+                                    .get::<String>("name")
+                                    .or_else(|_| s.get::<String>("detail"))
+                                    .unwrap_or_else(|_| "unknown plugin".to_string());
+                                missing_plugins.push(description);
+                            }
+                        }
+                    }
+                    gst::MessageView::Error(err) => {
+                        decode_error = Some(VideoError::from_bus_error(err));
+                    }
+                    _ => {}
+                }
+            }
+
+            if decode_error.is_some() {
+                break;
+            }
+
             match pipeline
                 .appsink
                 .try_pull_sample(gst::ClockTime::from_mseconds(100))
@@ -310,6 +400,9 @@ impl VideoPipeline {
                         let structure = caps.structure(0).unwrap();
                         pipeline.width = structure.get::<i32>("width").unwrap();
                         pipeline.height = structure.get::<i32>("height").unwrap();
+                        pipeline.framerate = structure
+                            .get::<gst::Fraction>("framerate")
+                            .unwrap_or(gst::Fraction::new(0, 1));
 
                         break;
                     }
@@ -321,65 +414,372 @@ impl VideoPipeline {
         }
 
         if pipeline.width == 0 || pipeline.height == 0 {
-            return Err(gst::glib::Error::new(
-                gst::LibraryError::Failed,
-                "Failed to get video info",
-            ));
+            let _ = pipeline.pipeline.set_state(gst::State::Null);
+
+            // a missing plugin explains the decode error that follows it, so report it first
+            return Err(if !missing_plugins.is_empty() {
+                VideoError::MissingElement(format!(
+                    "cannot decode {}, missing plugin(s): {}",
+                    input,
+                    missing_plugins.join(", ")
+                ))
+            } else if let Some(err) = decode_error {
+                err
+            } else {
+                VideoError::Timeout(format!(
+                    "no video frame decoded from {} within {} seconds",
+                    input,
+                    timeout.as_secs()
+                ))
+            });
         }
 
         // reset pipeline state
-        pipeline
-            .pipeline
-            .set_state(gst::State::Null)
-            .map_err(|_e| {
-                gst::glib::Error::new(
-                    gst::LibraryError::Failed,
-                    "Failed to set pipeline state to null",
-                )
-            })?;
+        pipeline.set_state(gst::State::Null)?;
+
+        // a live source cannot be paused by a slow consumer, keep the latest frame instead.
+        // this is the only place the delivery mode follows the source
+        if pipeline.source.is_live() {
+            pipeline.set_delivery_mode(DeliveryMode::Live);
+        }
 
         Ok(pipeline)
     }
 
-    pub fn start(&self) -> Result<(), gst::glib::Error> {
-        self.pipeline.set_state(gst::State::Playing).map_err(|_e| {
-            gst::glib::Error::new(gst::LibraryError::Failed, "Failed to start pipeline")
-        })?;
+    // create an element, reporting its factory name when the plugin is missing
+    fn make_element(factory: &str) -> Result<gst::Element, VideoError> {
+        gst::ElementFactory::make_with_name(factory, None)
+            .map_err(|_e| VideoError::MissingElement(factory.to_string()))
+    }
+
+    // convert a local path into a file:// uri
+    fn path_to_uri(path: &str) -> Result<String, VideoError> {
+        let absolute = std::fs::canonicalize(path)
+            .map_err(|e| VideoError::Input(format!("{}: {}", path, e)))?;
+
+        gst::glib::filename_to_uri(&absolute, None)
+            .map(|uri| uri.to_string())
+            .map_err(|e| VideoError::Input(format!("{}: {}", path, e)))
+    }
+
+    // add the elements producing raw video for a source and link them to the converter
+    fn add_source(
+        pipeline: &gst::Pipeline,
+        source: &VideoSource,
+        convert: &gst::Element,
+    ) -> Result<(), VideoError> {
+        let add = |elements: &[&gst::Element]| {
+            pipeline.add_many(elements).map_err(|_e| {
+                VideoError::LinkFailed("Failed to add source elements to pipeline".to_string())
+            })
+        };
+
+        match source {
+            VideoSource::File(path) => {
+                // the container is detected by typefinding
+                let decode = Self::make_element("uridecodebin")?;
+                decode.set_property("uri", Self::path_to_uri(path)?.as_str());
+                add(&[&decode])?;
+                Self::link_video_pads(&decode, convert);
+            }
+            VideoSource::Uri(uri) => {
+                // uridecodebin picks rtspsrc, souphttpsrc and hlsdemux as needed
+                let decode = Self::make_element("uridecodebin")?;
+                decode.set_property("uri", uri.as_str());
+                add(&[&decode])?;
+                Self::link_video_pads(&decode, convert);
+            }
+            VideoSource::V4l2 { device } => {
+                // cameras may deliver raw or compressed (e.g. MJPEG) frames
+                let src = Self::make_element("v4l2src")?;
+                let decode = Self::make_element("decodebin")?;
+                src.set_property("device", device.as_str());
+                add(&[&src, &decode])?;
+                src.link(&decode).map_err(|_e| {
+                    VideoError::LinkFailed(format!("Failed to link v4l2src for {}", device))
+                })?;
+                Self::link_video_pads(&decode, convert);
+            }
+            VideoSource::Test(test) => {
+                let (src, filter) = Self::make_test_source(test)?;
+                add(&[&src, &filter])?;
+                gst::Element::link_many(&[&src, &filter, convert]).map_err(|_e| {
+                    VideoError::CapsNegotiation(format!(
+                        "videotestsrc cannot produce {}x{} at {}/{} fps",
+                        test.width, test.height, test.fps.0, test.fps.1
+                    ))
+                })?;
+            }
+        }
 
         Ok(())
     }
 
-    pub fn stop(&self) -> Result<(), gst::glib::Error> {
-        self.pipeline.set_state(gst::State::Null).map_err(|_e| {
-            gst::glib::Error::new(gst::LibraryError::Failed, "Failed to stop pipeline")
+    // build videotestsrc and the caps filter fixing its size and rate
+    fn make_test_source(test: &TestPattern) -> Result<(gst::Element, gst::Element), VideoError> {
+        let src = Self::make_element("videotestsrc")?;
+        let filter = Self::make_element("capsfilter")?;
+
+        // validate the pattern nick before setting it, unknown values would abort
+        let pattern_known = src
+            .find_property("pattern")
+            .and_then(|pspec| gst::glib::EnumClass::with_type(pspec.value_type()))
+            .map_or(false, |class| class.value_by_nick(&test.pattern).is_some());
+        if !pattern_known {
+            return Err(VideoError::Input(format!(
+                "Unknown test pattern '{}'",
+                test.pattern
+            )));
+        }
+
+        src.set_property_from_str("pattern", &test.pattern);
+        src.set_property("is-live", test.live);
+        if let Some(frames) = test.frames {
+            src.set_property("num-buffers", frames as i32);
+        }
+
+        let caps = gst::Caps::builder("video/x-raw")
+            .field("width", test.width)
+            .field("height", test.height)
+            .field("framerate", gst::Fraction::new(test.fps.0, test.fps.1))
+            .build();
+        filter.set_property("caps", &caps);
+
+        Ok((src, filter))
+    }
+
+    // link the first video pad a decodebin exposes to the converter
+    fn link_video_pads(decode: &gst::Element, convert: &gst::Element) {
+        let convert_weak = convert.downgrade();
+        decode.connect_pad_added(move |_, src_pad| {
+            let is_video = src_pad
+                .current_caps()
+                .or_else(|| Some(src_pad.query_caps(None)))
+                .and_then(|caps| {
+                    caps.structure(0)
+                        .map(|s| s.name().as_str().starts_with("video/"))
+                })
+                .unwrap_or(false);
+
+            if !is_video {
+                return;
+            }
+
+            if let Some(convert) = convert_weak.upgrade() {
+                let sink_pad = convert.static_pad("sink").unwrap();
+                if !sink_pad.is_linked() {
+                    let _ = src_pad.link(&sink_pad);
+                }
+            }
+        });
+    }
+
+    // change the pipeline state, preferring the error an element posted over a generic failure
+    fn set_state(&self, state: gst::State) -> Result<(), VideoError> {
+        self.pipeline.set_state(state).map_err(|_e| {
+            self.pop_bus_error().unwrap_or_else(|| {
+                VideoError::StateChange(format!("Failed to set pipeline state to {:?}", state))
+            })
         })?;
 
         Ok(())
     }
 
+    // take the first pending error off the bus, if any
+    fn pop_bus_error(&self) -> Option<VideoError> {
+        let bus = self.pipeline.bus()?;
+        let msg = bus.pop_filtered(&[gst::MessageType::Error])?;
+
+        match msg.view() {
+            gst::MessageView::Error(err) => Some(VideoError::from_bus_error(err)),
+            _ => None,
+        }
+    }
+
+    /// Select which frames are delivered by `next_frame`, takes effect on the next `start`
+    pub fn set_sampling(&self, sampling: FrameSampling) {
+        self.sampling.lock().unwrap().sampler = FrameSampler::new(sampling);
+    }
+
+    /// Choose between lossless and live frame delivery, takes effect immediately
+    pub fn set_delivery_mode(&mut self, mode: DeliveryMode) {
+        match mode {
+            DeliveryMode::Lossless => {
+                // hold a couple of frames and block decoding when they are not consumed
+                self.appsink.set_max_buffers(2);
+                self.appsink.set_drop(false);
+                self.appsink.set_property("sync", false);
+            }
+            DeliveryMode::Live => {
+                // keep only the newest frame and pace decoding to the clock
+                self.appsink.set_max_buffers(1);
+                self.appsink.set_drop(true);
+                self.appsink.set_property("sync", true);
+            }
+        }
+
+        self.delivery = mode;
+    }
+
+    pub fn delivery_mode(&self) -> DeliveryMode {
+        self.delivery
+    }
+
+    pub fn start(&self) -> Result<(), VideoError> {
+        {
+            let mut state = self.sampling.lock().unwrap();
+            state.sampler.reset();
+            state.next_index = 0;
+            state.enabled = true;
+            state.finished = false;
+        }
+
+        self.set_state(gst::State::Playing)
+    }
+
+    pub fn stop(&self) -> Result<(), VideoError> {
+        self.set_state(gst::State::Null)
+    }
+
     pub fn get_dimensions(&self) -> (i32, i32) {
         (self.width, self.height)
     }
 
-    pub fn next_frame(&self) -> Option<Frame> {
-        self.appsink
-            .try_pull_sample(gst::ClockTime::from_seconds(5))
-            .map(|sample| {
-                let buffer = sample.buffer().unwrap();
-                let map = buffer.map_readable().unwrap();
-                let data = map.as_slice().to_vec();
-
-                // drop map and buffer
-                drop(map);
-                drop(sample);
-
-                Frame {
-                    data: data,
-                    width: self.width,
-                    height: self.height,
-                    channels: 3, // RGB format
+    pub fn get_framerate(&self) -> gst::Fraction {
+        self.framerate
+    }
+
+    pub fn source(&self) -> &VideoSource {
+        &self.source
+    }
+
+    /// Pull the next sampled frame along with its timing and source metadata.
+    /// Returns `VideoError::Eos` once the stream has ended, or the error an element reported.
+    /// Warnings posted while waiting are classified like errors and returned in the
+    /// `FrameInfo` of the frame.
+    pub fn next_frame(&self) -> Result<(FrameInfo, Frame), VideoError> {
+        let bus = self.pipeline.bus().unwrap();
+        let mut warnings = Vec::new();
+
+        loop {
+            if let Some(sample) = self
+                .appsink
+                .try_pull_sample(gst::ClockTime::from_mseconds(100))
+            {
+                let (mut info, frame) = self.sample_to_frame(sample)?;
+                info.warnings = warnings;
+                return Ok((info, frame));
+            }
+
+            // the appsink drained its queue after eos
+            if self.appsink.is_eos() {
+                return Err(VideoError::Eos);
+            }
+
+            // a stopped pipeline will never produce another frame
+            let (_, state, _) = self.pipeline.state(gst::ClockTime::ZERO);
+            if state == gst::State::Null {
+                return Err(VideoError::Eos);
+            }
+
+            // slow decoding is not the end of the stream, only eos or an error is
+            while let Some(msg) = bus.pop_filtered(&[
+                gst::MessageType::Eos,
+                gst::MessageType::Error,
+                gst::MessageType::Warning,
+            ]) {
+                match msg.view() {
+                    gst::MessageView::Error(err) => {
+                        return Err(VideoError::from_bus_error(err));
+                    }
+                    gst::MessageView::Warning(warning) => {
+                        warnings.push(VideoError::from_bus_warning(warning).to_string());
+                    }
+                    _ => {
+                        // frames still queued in the appsink are delivered before ending
+                        return match self.appsink.try_pull_sample(gst::ClockTime::ZERO) {
+                            Some(sample) => self.sample_to_frame(sample),
+                            None => Err(VideoError::Eos),
+                        };
+                    }
                 }
-            })
+            }
+        }
+    }
+
+    // copy a sample into a frame and collect its metadata
+    fn sample_to_frame(&self, sample: gst::Sample) -> Result<(FrameInfo, Frame), VideoError> {
+        let buffer = sample.buffer().unwrap();
+        let caps = sample
+            .caps()
+            .ok_or_else(|| VideoError::CapsNegotiation("sample without caps".to_string()))?;
+        let video_info = gst_video::VideoInfo::from_caps(caps).map_err(|e| {
+            VideoError::CapsNegotiation(format!("invalid video caps {}: {}", caps, e))
+        })?;
+        let format = PixelFormat::from_gst_name(video_info.format().to_str()).ok_or_else(|| {
+            VideoError::CapsNegotiation(format!(
+                "unsupported raw video format {}",
+                video_info.format().to_str()
+            ))
+        })?;
+
+        // report time relative to the stream rather than the running clock
+        let segment = sample
+            .segment()
+            .and_then(|segment| segment.downcast_ref::<gst::ClockTime>());
+        let pts = buffer.pts().map(|pts| {
+            segment
+                .and_then(|segment| segment.to_stream_time(pts))
+                .unwrap_or(pts)
+        });
+
+        let info = FrameInfo {
+            frame_number: buffer.offset(),
+            pts: pts.map(|pts| Duration::from_nanos(pts.nseconds())),
+            duration: buffer
+                .duration()
+                .map(|duration| Duration::from_nanos(duration.nseconds())),
+            stream_id: self
+                .appsink
+                .static_pad("sink")
+                .and_then(|pad| pad.stream_id())
+                .map(|id| id.to_string()),
+            source_format: self
+                .convert_sink
+                .current_caps()
+                .and_then(|caps| caps_format(&caps)),
+            pixel_format: format.to_string(),
+            warnings: Vec::new(),
+        };
+
+        // decoders may pad rows and planes, copy them into a tightly packed frame
+        let video_frame =
+            gst_video::VideoFrameRef::from_buffer_ref_readable(buffer, &video_info)
+                .map_err(|e| VideoError::CapsNegotiation(format!("failed to map frame: {}", e)))?;
+
+        let width = video_info.width() as usize;
+        let height = video_info.height() as usize;
+        let mut data = Vec::with_capacity(format.buffer_size(width, height));
+
+        for (plane, (row_bytes, rows)) in format.planes(width, height).into_iter().enumerate() {
+            let stride = video_frame.plane_stride()[plane] as usize;
+            let plane_data = video_frame.plane_data(plane as u32).map_err(|e| {
+                VideoError::CapsNegotiation(format!("failed to read plane {}: {}", plane, e))
+            })?;
+
+            for row in plane_data.chunks(stride).take(rows) {
+                data.extend_from_slice(&row[..row_bytes]);
+            }
+        }
+
+        // drop the mapping before the sample it borrows from
+        drop(video_frame);
+        drop(sample);
+
+        let frame = Frame::new(data, width as i32, height as i32, format)
+            .map_err(|e| VideoError::CapsNegotiation(format!("failed to read frame: {}", e)))?;
+
+        Ok((info, frame))
     }
 }
 
@@ -0,0 +1,239 @@
+use std::fmt;
+
+/// Where a `VideoPipeline` reads its frames from, selected by the `--input` uri scheme
+#[derive(Debug, Clone, PartialEq)]
+pub enum VideoSource {
+    /// A local video file in any container GStreamer can typefind
+    File(String),
+    /// A network or file uri handled by uridecodebin (`rtsp://`, `http(s)://`, HLS playlists, `file://`)
+    Uri(String),
+    /// A V4L2 capture device, `v4l2:///dev/video0`
+    V4l2 { device: String },
+    /// A synthetic `videotestsrc` pattern, `test://smpte?frames=300&width=640&height=480&fps=30`
+    Test(TestPattern),
+}
+
+/// Settings of the synthetic test source
+#[derive(Debug, Clone, PartialEq)]
+pub struct TestPattern {
+    /// videotestsrc pattern nick, e.g. smpte, ball, snow
+    pub pattern: String,
+    /// Number of frames before end of stream, unlimited when unset
+    pub frames: Option<u32>,
+    pub width: i32,
+    pub height: i32,
+    /// Frame rate as numerator and denominator
+    pub fps: (i32, i32),
+    /// Whether frames are produced in real time like a camera
+    pub live: bool,
+}
+
+impl Default for TestPattern {
+    fn default() -> Self {
+        Self {
+            pattern: "smpte".to_string(),
+            frames: None,
+            width: 640,
+            height: 480,
+            fps: (30, 1),
+            live: false,
+        }
+    }
+}
+
+impl VideoSource {
+    /// Parse an `--input` value, anything without a scheme is a local file path
+    pub fn parse(input: &str) -> Result<Self, String> {
+        let (scheme, rest) = match input.split_once("://") {
+            Some(parts) => parts,
+            None => return Ok(VideoSource::File(input.to_string())),
+        };
+
+        match scheme.to_ascii_lowercase().as_str() {
+            "v4l2" => {
+                if rest.is_empty() {
+                    return Err(format!(
+                        "Missing device in {}, expected v4l2:///dev/videoN",
+                        input
+                    ));
+                }
+
+                Ok(VideoSource::V4l2 {
+                    device: rest.to_string(),
+                })
+            }
+            "test" => Self::parse_test(input, rest).map(VideoSource::Test),
+            "rtsp" | "rtsps" | "http" | "https" | "file" => {
+                Ok(VideoSource::Uri(input.to_string()))
+            }
+            other => Err(format!(
+                "Unsupported input scheme '{}', expected a file path, v4l2://, rtsp://, http(s):// or test://",
+                other
+            )),
+        }
+    }
+
+    // parse `pattern?key=value&...` of a test:// uri
+    fn parse_test(input: &str, rest: &str) -> Result<TestPattern, String> {
+        let (pattern, query) = rest.split_once('?').unwrap_or((rest, ""));
+        let mut test = TestPattern::default();
+
+        if !pattern.is_empty() {
+            test.pattern = pattern.to_string();
+        }
+
+        for param in query.split('&').filter(|param| !param.is_empty()) {
+            let (key, value) = param.split_once('=').unwrap_or((param, ""));
+            let invalid = || format!("Invalid value '{}' for '{}' in {}", value, key, input);
+
+            match key {
+                "frames" => test.frames = Some(value.parse().map_err(|_| invalid())?),
+                "width" => test.width = value.parse().map_err(|_| invalid())?,
+                "height" => test.height = value.parse().map_err(|_| invalid())?,
+                "fps" => {
+                    let (numer, denom) = value.split_once('/').unwrap_or((value, "1"));
+                    test.fps = (
+                        numer.parse().map_err(|_| invalid())?,
+                        denom.parse().map_err(|_| invalid())?,
+                    );
+                }
+                "live" => test.live = value.is_empty() || value.parse().map_err(|_| invalid())?,
+                _ => return Err(format!("Unknown test source option '{}' in {}", key, input)),
+            }
+        }
+
+        if test.width <= 0 || test.height <= 0 || test.fps.0 <= 0 || test.fps.1 <= 0 {
+            return Err(format!(
+                "Test source size and fps must be positive in {}",
+                input
+            ));
+        }
+
+        Ok(test)
+    }
+
+    /// Whether the source produces frames in real time and cannot wait for the consumer
+    pub fn is_live(&self) -> bool {
+        match self {
+            VideoSource::File(_) => false,
+            VideoSource::Uri(uri) => {
+                let uri = uri.to_ascii_lowercase();
+                uri.starts_with("rtsp://") || uri.starts_with("rtsps://")
+            }
+            VideoSource::V4l2 { .. } => true,
+            VideoSource::Test(test) => test.live,
+        }
+    }
+}
+
+impl fmt::Display for VideoSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            VideoSource::File(path) => write!(f, "{}", path),
+            VideoSource::Uri(uri) => write!(f, "{}", uri),
+            VideoSource::V4l2 { device } => write!(f, "v4l2://{}", device),
+            VideoSource::Test(test) => write!(f, "test://{}", test.pattern),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn plain_paths_are_files() {
+        for path in [
+            "video.mp4",
+            "/data/clips/a b.mkv",
+            "C:\\videos\\clip.avi",
+            "",
+        ] {
+            assert_eq!(
+                VideoSource::parse(path),
+                Ok(VideoSource::File(path.to_string()))
+            );
+        }
+        assert!(!VideoSource::parse("video.mp4").unwrap().is_live());
+    }
+
+    #[test]
+    fn v4l2_devices() {
+        let source = VideoSource::parse("v4l2:///dev/video0").unwrap();
+        assert_eq!(
+            source,
+            VideoSource::V4l2 {
+                device: "/dev/video0".to_string()
+            }
+        );
+        assert!(source.is_live());
+        assert_eq!(source.to_string(), "v4l2:///dev/video0");
+
+        assert!(VideoSource::parse("v4l2://").is_err());
+    }
+
+    #[test]
+    fn network_uris() {
+        for (uri, live) in [
+            ("rtsp://camera.local:554/stream", true),
+            ("RTSPS://camera.local/stream", true),
+            ("http://example.com/video.mp4", false),
+            ("https://example.com/live/playlist.m3u8", false),
+            ("file:///tmp/video.mkv", false),
+        ] {
+            let source = VideoSource::parse(uri).unwrap();
+            assert_eq!(source, VideoSource::Uri(uri.to_string()));
+            assert_eq!(source.is_live(), live, "{}", uri);
+        }
+
+        let error = VideoSource::parse("ftp://example.com/video.mp4").unwrap_err();
+        assert!(error.contains("'ftp'"));
+    }
+
+    #[test]
+    fn test_patterns() {
+        assert_eq!(
+            VideoSource::parse("test://").unwrap(),
+            VideoSource::Test(TestPattern::default())
+        );
+
+        let source =
+            VideoSource::parse("test://ball?frames=300&width=320&height=240&fps=30000/1001&live")
+                .unwrap();
+        assert_eq!(
+            source,
+            VideoSource::Test(TestPattern {
+                pattern: "ball".to_string(),
+                frames: Some(300),
+                width: 320,
+                height: 240,
+                fps: (30000, 1001),
+                live: true,
+            })
+        );
+        assert!(source.is_live());
+
+        // pattern names are checked against videotestsrc when the pipeline is built
+        assert_eq!(
+            VideoSource::parse("test://no-such-pattern?live=false").unwrap(),
+            VideoSource::Test(TestPattern {
+                pattern: "no-such-pattern".to_string(),
+                ..TestPattern::default()
+            })
+        );
+    }
+
+    #[test]
+    fn invalid_test_options_are_rejected() {
+        for input in [
+            "test://smpte?frames=many",
+            "test://smpte?width=0",
+            "test://smpte?height=-5",
+            "test://smpte?fps=30/0",
+            "test://smpte?live=maybe",
+            "test://smpte?speed=2",
+        ] {
+            assert!(VideoSource::parse(input).is_err(), "{}", input);
+        }
+    }
+}
@@ -0,0 +1,361 @@
+use crate::frame::{Frame, PixelFormat};
+use crate::video_pipeline::{FrameInfo, VideoError};
+use gst::prelude::*;
+use gstreamer as gst;
+use gstreamer_app as gst_app;
+use std::path::Path;
+
+// how long `finish` waits for the muxer to write its trailer
+const FINISH_TIMEOUT_SECONDS: u64 = 30;
+
+/// What is encoded into each output video frame
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum VideoLayout {
+    /// Only the processed frame
+    Processed,
+    /// The original frame on the left and the processed frame on the right
+    SideBySide,
+}
+
+// encoder candidates in order of preference and the muxer for a container extension
+fn container_elements(extension: &str) -> Option<(&'static [&'static str], &'static str)> {
+    match extension {
+        "mkv" => Some((&["x264enc", "vp8enc", "avenc_mpeg4"], "matroskamux")),
+        "mp4" => Some((&["x264enc", "openh264enc", "avenc_mpeg4"], "mp4mux")),
+        "mov" => Some((&["x264enc", "openh264enc", "avenc_mpeg4"], "qtmux")),
+        "webm" => Some((&["vp8enc", "vp9enc"], "webmmux")),
+        "avi" => Some((&["avenc_mpeg4", "x264enc"], "avimux")),
+        _ => None,
+    }
+}
+
+// gstreamer pipeline that encodes processed frames back into a video file
+pub struct VideoWriter {
+    // output file path
+    path: String,
+
+    // frame rate of the source video
+    framerate: gst::Fraction,
+
+    // what each encoded frame contains
+    layout: VideoLayout,
+
+    // name of the encoder element picked for the container
+    encoder: &'static str,
+
+    // name of the muxer element for the container
+    muxer: &'static str,
+
+    // encoding pipeline, built on the first frame once the output size is known
+    pipeline: Option<gst::Pipeline>,
+
+    // appsrc feeding frames into the encoding pipeline
+    appsrc: Option<gst_app::AppSrc>,
+
+    // dimensions the encoding pipeline was negotiated with
+    width: i32,
+    height: i32,
+}
+
+impl VideoWriter {
+    /// Create a writer for the given output file
+    ///
+    /// # Arguments
+    /// * `path` - Output file, the container is picked from the extension (mkv, mp4, mov, webm, avi)
+    /// * `framerate` - Frame rate of the source video, used to timestamp the output
+    /// * `layout` - Whether to encode only the processed frame or both side by side
+    pub fn new(
+        path: &str,
+        framerate: gst::Fraction,
+        layout: VideoLayout,
+    ) -> Result<Self, VideoError> {
+        gst::init().map_err(|e| VideoError::Init(e.to_string()))?;
+
+        let extension = Path::new(path)
+            .extension()
+            .and_then(|ext| ext.to_str())
+            .unwrap_or("")
+            .to_ascii_lowercase();
+
+        let (encoders, muxer) = container_elements(&extension).ok_or_else(|| {
+            VideoError::Input(format!(
+                "Unsupported video output extension for {}, expected mkv, mp4, mov, webm or avi",
+                path
+            ))
+        })?;
+
+        // pick the first encoder that is actually installed
+        let encoder = encoders
+            .iter()
+            .copied()
+            .find(|name| gst::ElementFactory::find(name).is_some())
+            .ok_or_else(|| {
+                VideoError::MissingElement(format!(
+                    "no encoder for .{}, tried: {}",
+                    extension,
+                    encoders.join(", ")
+                ))
+            })?;
+
+        if gst::ElementFactory::find(muxer).is_none() {
+            return Err(VideoError::MissingElement(muxer.to_string()));
+        }
+
+        // a variable or unknown rate cannot be used to space frames, fall back to 30 fps
+        let framerate = if framerate.numer() > 0 && framerate.denom() > 0 {
+            framerate
+        } else {
+            gst::Fraction::new(30, 1)
+        };
+
+        Ok(Self {
+            path: path.to_string(),
+            framerate,
+            layout,
+            encoder,
+            muxer,
+            pipeline: None,
+            appsrc: None,
+            width: 0,
+            height: 0,
+        })
+    }
+
+    pub fn layout(&self) -> VideoLayout {
+        self.layout
+    }
+
+    // build appsrc -> videoconvert -> encoder -> muxer -> filesink for the given frame size
+    fn build_pipeline(&mut self, width: i32, height: i32) -> Result<(), VideoError> {
+        let pipeline = gst::Pipeline::new();
+
+        let make = |factory: &str| {
+            gst::ElementFactory::make_with_name(factory, None)
+                .map_err(|_e| VideoError::MissingElement(factory.to_string()))
+        };
+
+        let convert = make("videoconvert")?;
+        let encoder = make(self.encoder)?;
+        let muxer = make(self.muxer)?;
+        let sink = make("filesink")?;
+        sink.set_property("location", self.path.as_str());
+
+        let caps = gst::Caps::builder("video/x-raw")
+            .field("format", &"RGB")
+            .field("width", width)
+            .field("height", height)
+            .field("framerate", self.framerate)
+            .build();
+
+        let appsrc = gst_app::AppSrc::builder()
+            .caps(&caps)
+            .format(gst::Format::Time)
+            .block(true)
+            .build();
+
+        pipeline
+            .add_many(&[
+                appsrc.upcast_ref::<gst::Element>(),
+                &convert,
+                &encoder,
+                &muxer,
+                &sink,
+            ])
+            .map_err(|_e| {
+                VideoError::LinkFailed("Failed to add elements to writer pipeline".to_string())
+            })?;
+
+        gst::Element::link_many(&[
+            appsrc.upcast_ref::<gst::Element>(),
+            &convert,
+            &encoder,
+            &muxer,
+            &sink,
+        ])
+        .map_err(|_e| {
+            VideoError::LinkFailed(format!(
+                "Failed to link writer pipeline ({} -> {})",
+                self.encoder, self.muxer
+            ))
+        })?;
+
+        pipeline
+            .set_state(gst::State::Playing)
+            .map_err(|_e| VideoError::StateChange("Failed to start writer pipeline".to_string()))?;
+
+        self.pipeline = Some(pipeline);
+        self.appsrc = Some(appsrc);
+        self.width = width;
+        self.height = height;
+
+        Ok(())
+    }
+
+    /// Encode a processed frame
+    ///
+    /// # Arguments
+    /// * `original` - The frame before processing, required for the side by side layout
+    /// * `processed` - The frame after processing
+    /// * `info` - Timing of the frame in the source, used to place the frame in time
+    pub fn write_frame(
+        &mut self,
+        original: Option<&Frame>,
+        processed: &Frame,
+        info: &FrameInfo,
+    ) -> Result<(), VideoError> {
+        let frame = match (self.layout, original) {
+            (VideoLayout::SideBySide, Some(original)) => side_by_side(original, processed),
+            _ => processed.clone().to_rgb(),
+        };
+
+        if self.pipeline.is_none() {
+            self.build_pipeline(frame.width(), frame.height())?;
+        }
+
+        if frame.width() != self.width || frame.height() != self.height {
+            return Err(VideoError::Encode(format!(
+                "Frame size changed from {}x{} to {}x{} while writing {}",
+                self.width,
+                self.height,
+                frame.width(),
+                frame.height(),
+                self.path
+            )));
+        }
+
+        // gstreamer expects each RGB row padded to a multiple of 4 bytes
+        let row_bytes = (frame.width() * 3) as usize;
+        let stride = (row_bytes + 3) & !3;
+        let mut data = vec![0u8; stride * frame.height() as usize];
+        for (y, row) in frame.data().chunks_exact(row_bytes).enumerate() {
+            data[y * stride..y * stride + row_bytes].copy_from_slice(row);
+        }
+
+        // place the frame at its source time so sampled output keeps real timing,
+        // falling back to the nominal frame rate when the source has no timestamps
+        let numer = self.framerate.numer() as u128;
+        let denom = self.framerate.denom() as u128;
+        let pts = match info.pts {
+            Some(pts) => gst::ClockTime::from_nseconds(pts.as_nanos() as u64),
+            None => gst::ClockTime::from_nseconds(
+                (info.frame_number as u128 * 1_000_000_000 * denom / numer) as u64,
+            ),
+        };
+        let frame_duration = match info.duration {
+            Some(duration) => gst::ClockTime::from_nseconds(duration.as_nanos() as u64),
+            None => gst::ClockTime::from_nseconds((1_000_000_000 * denom / numer) as u64),
+        };
+
+        let mut buffer = gst::Buffer::from_mut_slice(data);
+        {
+            let buffer = buffer.get_mut().unwrap();
+            buffer.set_pts(pts);
+            buffer.set_duration(frame_duration);
+        }
+
+        self.appsrc
+            .as_ref()
+            .unwrap()
+            .push_buffer(buffer)
+            .map_err(|e| {
+                VideoError::Encode(format!("Failed to push frame to writer pipeline: {:?}", e))
+            })?;
+
+        Ok(())
+    }
+
+    /// Flush the encoder and finalize the container, must be called for the file to be playable.
+    /// Gives up with a timeout when the muxer does not finish within 30 seconds.
+    pub fn finish(&mut self) -> Result<(), VideoError> {
+        let (pipeline, appsrc) = match (self.pipeline.take(), self.appsrc.take()) {
+            (Some(pipeline), Some(appsrc)) => (pipeline, appsrc),
+            _ => return Ok(()),
+        };
+
+        let _ = appsrc.end_of_stream();
+
+        // wait for the muxer to write its trailer
+        let bus = pipeline.bus().unwrap();
+        let result = match bus.timed_pop_filtered(
+            gst::ClockTime::from_seconds(FINISH_TIMEOUT_SECONDS),
+            &[gst::MessageType::Eos, gst::MessageType::Error],
+        ) {
+            Some(msg) => match msg.view() {
+                gst::MessageView::Error(err) => Err(VideoError::from_bus_error(&err)),
+                _ => Ok(()),
+            },
+            None => Err(VideoError::Timeout(format!(
+                "{} was not finalized within {} seconds",
+                self.path, FINISH_TIMEOUT_SECONDS
+            ))),
+        };
+
+        pipeline
+            .set_state(gst::State::Null)
+            .map_err(|_e| VideoError::StateChange("Failed to stop writer pipeline".to_string()))?;
+
+        result
+    }
+}
+
+impl Drop for VideoWriter {
+    fn drop(&mut self) {
+        let _ = self.finish();
+    }
+}
+
+/// Place the original and processed frames next to each other in a single RGB frame
+pub fn side_by_side(original: &Frame, processed: &Frame) -> Frame {
+    let left = original.clone().to_rgb();
+    let right = processed.clone().to_rgb();
+
+    let width = left.width() + right.width();
+    let height = left.height().max(right.height());
+    let mut data = vec![0u8; (width * height * 3) as usize];
+
+    for (x_offset, part) in [(0, &left), (left.width(), &right)] {
+        let row_bytes = (part.width() * 3) as usize;
+        for y in 0..part.height() as usize {
+            let src = y * row_bytes;
+            let dst = (y * width as usize + x_offset as usize) * 3;
+            data[dst..dst + row_bytes].copy_from_slice(&part.data()[src..src + row_bytes]);
+        }
+    }
+
+    Frame::new(data, width, height, PixelFormat::Rgb)
+        .expect("the side by side buffer holds both frames")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn side_by_side_places_the_frames_next_to_each_other() {
+        // a 2x2 gray original and a taller 3x3 colour result
+        let original = Frame::new(vec![10, 20, 30, 40], 2, 2, PixelFormat::Gray8).unwrap();
+        let processed = Frame::new((0..27).collect(), 3, 3, PixelFormat::Rgb).unwrap();
+
+        let frame = side_by_side(&original, &processed);
+        assert_eq!(frame.format(), PixelFormat::Rgb);
+        assert_eq!((frame.width(), frame.height()), (5, 3));
+        assert_eq!(frame.data().len(), 5 * 3 * 3);
+
+        let pixel = |x: usize, y: usize| &frame.data()[(y * 5 + x) * 3..(y * 5 + x) * 3 + 3];
+        assert_eq!(pixel(0, 0), [10, 10, 10]);
+        assert_eq!(pixel(1, 1), [40, 40, 40]);
+        assert_eq!(pixel(2, 0), [0, 1, 2]);
+        assert_eq!(pixel(4, 2), [24, 25, 26]);
+
+        // below the shorter original the frame stays black
+        assert_eq!(pixel(0, 2), [0, 0, 0]);
+        assert_eq!(pixel(1, 2), [0, 0, 0]);
+    }
+
+    #[test]
+    fn containers_pick_their_muxer() {
+        assert_eq!(container_elements("mp4").unwrap().1, "mp4mux");
+        assert_eq!(container_elements("webm").unwrap().1, "webmmux");
+        assert!(container_elements("gif").is_none());
+    }
+}